@@ -26,6 +26,7 @@
 #include <unistd.h>
 #include <stdbool.h>
 #include <errno.h>
+#include <limits.h>
 
 #if PY_MINOR_VERSION < 9
     PyFrameObject *
@@ -144,21 +145,38 @@ static inline int should_track_memory() {
   return (likely(initialized) && atomic_load_explicit(&tracking_allocations, memory_order_acquire) && !am_i_reentrant());
 }
 
+// Means we don't know the current bytecode index.
+#define NO_BYTECODE_INDEX INT_MIN
+
 // Current thread's Python state; typically only set in C functions where GIL
 // might be released.
-static _Thread_local int current_line_number = -1;
+static _Thread_local int current_bytecode_index = NO_BYTECODE_INDEX;
+
+// The byte offset of the frame's current instruction. This is much cheaper to
+// get than the line number, which the Rust code only calculates from it when
+// writing out reports.
+static inline int get_bytecode_index(PyFrameObject *frame) {
+#if PY_MINOR_VERSION >= 11
+  return PyFrame_GetLasti(frame);
+#elif PY_MINOR_VERSION == 10
+  // f_lasti is in units of instructions:
+  if (frame->f_lasti < 0) {
+    return -1;
+  }
+  return frame->f_lasti * sizeof(_Py_CODEUNIT);
+#else
+  return frame->f_lasti;
+#endif
+}
 
-static inline int get_current_line_number() {
+static inline int get_current_bytecode_index() {
   if (PyGILState_Check()) {
     PyFrameObject *frame = PyEval_GetFrame();
     if (frame != NULL) {
-      return PyFrame_GetLineNumber(frame);
+      return get_bytecode_index(frame);
     }
   }
-  if (current_line_number != -1) {
-    return current_line_number;
-  }
-  return 0;
+  return current_bytecode_index;
 }
 
 // The file and function name responsible for an allocation.
@@ -171,21 +189,31 @@ struct FunctionLocation {
 
 // Implemented in the Rust library:
 extern uint64_t pymemprofile_add_function_location(const char* filename, size_t filename_length, const char* function_name,
-                                                   size_t function_length);
-extern void pymemprofile_start_call(uint16_t parent_line_number,
+                                                   size_t function_length, PyCodeObject *code);
+extern void pymemprofile_start_call(int parent_bytecode_index,
                                     uint64_t function_id,
-                                    uint16_t line_number);
+                                    int bytecode_index);
 extern void pymemprofile_finish_call();
 extern void pymemprofile_new_line_number(uint16_t line_number);
 extern void pymemprofile_reset(const char *path);
 extern void pymemprofile_start_tracking();
 extern void pymemprofile_stop_tracking();
 extern void pymemprofile_dump_peak_to_flamegraph(const char *path);
+extern void pymemprofile_take_snapshot(const char *name);
+extern void pymemprofile_set_notebook_cell(const char *filename,
+                                           unsigned int cell_number);
+extern void pymemprofile_leak_checkpoint_start();
+extern void pymemprofile_leak_checkpoint_end();
+extern void pymemprofile_prepare_fork();
+extern void pymemprofile_after_fork_in_parent();
+extern void pymemprofile_after_fork_in_child();
 extern void pymemprofile_add_allocation(size_t address, size_t length,
-                                        uint16_t line_number);
+                                        int bytecode_index,
+                                        size_t return_address);
 extern void pymemprofile_free_allocation(size_t address);
 extern void pymemprofile_add_anon_mmap(size_t address, size_t length,
-                                       uint16_t line_number);
+                                       int bytecode_index,
+                                       size_t return_address);
 extern void pymemprofile_free_anon_mmap(size_t address, size_t length);
 extern void *pymemprofile_get_current_callstack();
 extern void pymemprofile_set_current_callstack(void *callstack);
@@ -242,18 +270,18 @@ static void __attribute__((constructor)) constructor() {
   initialized = 1;
 }
 
-static void start_call(uint64_t function_id, uint16_t line_number, PyFrameObject* current_frame) {
+static void start_call(uint64_t function_id, int bytecode_index, PyFrameObject* current_frame) {
   if (should_track_memory()) {
     increment_reentrancy();
-    uint16_t parent_line_number = 0;
+    int parent_bytecode_index = NO_BYTECODE_INDEX;
     if (current_frame != NULL) {
       PyFrameObject *parent = PyFrame_GetBack(current_frame);
       if (parent != NULL ){
-        parent_line_number = PyFrame_GetLineNumber(parent);
+        parent_bytecode_index = get_bytecode_index(parent);
         Py_DECREF(parent);
       }
     }
-    pymemprofile_start_call(parent_line_number, function_id, line_number);
+    pymemprofile_start_call(parent_bytecode_index, function_id, bytecode_index);
     decrement_reentrancy();
   }
 }
@@ -271,7 +299,7 @@ __attribute__((visibility("hidden"))) int
 fil_tracer(PyObject *obj, PyFrameObject *frame, int what, PyObject *arg) {
   switch (what) {
   case PyTrace_LINE:
-    current_line_number = frame->f_lineno;
+    current_bytecode_index = get_bytecode_index(frame);
     break;
   case PyTrace_CALL:
     /*
@@ -280,7 +308,7 @@ fil_tracer(PyObject *obj, PyFrameObject *frame, int what, PyObject *arg) {
       then store the ID. Due to bad API design, value 0 indicates "no result",
       so we actually store the result + 1.
     */
-    current_line_number = frame->f_lineno;
+    current_bytecode_index = get_bytecode_index(frame);
     uint64_t function_id = 0;
     assert(extra_code_index != -1);
     PyCodeObject *code = PyFrame_GetCode(frame);
@@ -292,24 +320,26 @@ fil_tracer(PyObject *obj, PyFrameObject *frame, int what, PyObject *arg) {
       const char* function_name = PyUnicode_AsUTF8AndSize(code->co_name,
                                                           &function_length);
       increment_reentrancy();
-      function_id = pymemprofile_add_function_location(filename, (uint64_t)filename_length, function_name, (uint64_t)function_length);
+      // The Rust code reads the code object's line table, which it needs to
+      // turn bytecode indexes into line numbers; it doesn't keep a reference.
+      function_id = pymemprofile_add_function_location(filename, (uint64_t)filename_length, function_name, (uint64_t)function_length, code);
       decrement_reentrancy();
       _PyCode_SetExtra((PyObject *)code, extra_code_index,
                        (void *)function_id + 1);
-      Py_DECREF(code);
     } else {
       function_id -= 1;
     }
-    start_call(function_id, current_line_number, frame);
+    Py_DECREF(code);
+    start_call(function_id, current_bytecode_index, frame);
     break;
   case PyTrace_RETURN:
     finish_call();
     if (frame != NULL) {
       PyFrameObject* parent = PyFrame_GetBack(frame);
       if (parent == NULL) {
-        current_line_number = -1;
+        current_bytecode_index = NO_BYTECODE_INDEX;
       } else {
-        current_line_number = PyFrame_GetLineNumber(parent);
+        current_bytecode_index = get_bytecode_index(parent);
         Py_DECREF(parent);
       }
     }
@@ -317,10 +347,10 @@ fil_tracer(PyObject *obj, PyFrameObject *frame, int what, PyObject *arg) {
   case PyTrace_C_CALL:
     // C calls might release GIL, in which case they won't change the line
     // number, so record it.
-    current_line_number = PyFrame_GetLineNumber(frame);
+    current_bytecode_index = get_bytecode_index(frame);
     break;
   case PyTrace_C_RETURN:
-    current_line_number = -1;
+    current_bytecode_index = NO_BYTECODE_INDEX;
     break;
   default:
     break;
@@ -377,28 +407,66 @@ fil_dump_peak_to_flamegraph(const char *path) {
   decrement_reentrancy();
 }
 
+/// Snapshot current memory usage, diffing against the previous snapshot.
+__attribute__((visibility("default"))) void
+fil_take_snapshot(const char *name) {
+  increment_reentrancy();
+  pymemprofile_take_snapshot(name);
+  decrement_reentrancy();
+}
+
+/// The code with this filename is the given Jupyter notebook cell.
+__attribute__((visibility("default"))) void
+fil_set_notebook_cell(const char *filename, unsigned int cell_number) {
+  increment_reentrancy();
+  pymemprofile_set_notebook_cell(filename, cell_number);
+  decrement_reentrancy();
+}
+
+/// Allocations alive now are long-lived state, not leaks.
+__attribute__((visibility("default"))) void fil_leak_checkpoint_start() {
+  increment_reentrancy();
+  pymemprofile_leak_checkpoint_start();
+  decrement_reentrancy();
+}
+
+/// Allocations made from now on aren't leaks.
+__attribute__((visibility("default"))) void fil_leak_checkpoint_end() {
+  increment_reentrancy();
+  pymemprofile_leak_checkpoint_end();
+  decrement_reentrancy();
+}
+
 // *** End APIs called by Python ***
-static void add_allocation(size_t address, size_t size) {
-  uint16_t line_number = get_current_line_number();
-  pymemprofile_add_allocation(address, size, line_number);
+// The return address is that of the allocation function's caller, so we know
+// which shared library made the allocation.
+static void add_allocation(size_t address, size_t size,
+                           void *return_address) {
+  int bytecode_index = get_current_bytecode_index();
+  pymemprofile_add_allocation(address, size, bytecode_index,
+                              (size_t)return_address);
 }
 
-static void add_anon_mmap(size_t address, size_t size) {
-  uint16_t line_number = get_current_line_number();
-  pymemprofile_add_anon_mmap(address, size, line_number);
+static void add_anon_mmap(size_t address, size_t size, void *return_address) {
+  int bytecode_index = get_current_bytecode_index();
+  pymemprofile_add_anon_mmap(address, size, bytecode_index,
+                             (size_t)return_address);
 }
 
-// Disable memory tracking after fork() in the child.
+// Keep tracking after fork() in the child, as a separate process with its own
+// report.
 __attribute__((visibility("default"))) pid_t SYMBOL_PREFIX(fork)(void) {
   // Make sure subprocesses on macOS don't preload this:
   increment_reentrancy();
   unsetenv("DYLD_INSERT_LIBRARIES");
   decrement_reentrancy();
 
-  static int already_printed = 0;
-  if (atomic_load_explicit(&tracking_allocations, memory_order_acquire) && !already_printed) {
-    fprintf(stderr, "=fil-profile= WARNING: Fil does not (yet) support tracking memory in subprocesses.\n");
-    already_printed = 1;
+  int tracking = atomic_load_explicit(&tracking_allocations, memory_order_acquire);
+  if (tracking) {
+    // Take the Rust-side locks, so they're not held by some other thread
+    // at the moment of fork():
+    increment_reentrancy();
+    pymemprofile_prepare_fork();
   }
   pid_t result = underlying_real_fork();
   if (result == 0) {
@@ -407,8 +475,13 @@ __attribute__((visibility("default"))) pid_t SYMBOL_PREFIX(fork)(void) {
     // (filprofiler/__init__.py), so os.environ stays in sync. Doing it in only
     // C or only Python doesn't seem to work, need both for some reason.
     setenv("__FIL_STATUS", "subprocess", 1);
-    // Clear any memory if we're in icky fork()-without-exec() mode.
-    fil_stop_tracking();
+    if (tracking) {
+      pymemprofile_after_fork_in_child();
+      decrement_reentrancy();
+    }
+  } else if (tracking) {
+    pymemprofile_after_fork_in_parent();
+    decrement_reentrancy();
   }
   return result;
 }
@@ -421,7 +494,7 @@ SYMBOL_PREFIX(malloc)(size_t size) {
   decrement_reentrancy();
   if (should_track_memory()) {
     increment_reentrancy();
-    add_allocation((size_t)result, size);
+    add_allocation((size_t)result, size, __builtin_return_address(0));
     decrement_reentrancy();
   }
   return result;
@@ -435,7 +508,7 @@ SYMBOL_PREFIX(calloc)(size_t nmemb, size_t size) {
   size_t allocated = nmemb * size;
   if (should_track_memory()) {
     increment_reentrancy();
-    add_allocation((size_t)result, allocated);
+    add_allocation((size_t)result, allocated, __builtin_return_address(0));
     decrement_reentrancy();
   }
   return result;
@@ -463,7 +536,7 @@ SYMBOL_PREFIX(realloc)(void *addr, size_t size) {
   decrement_reentrancy();
   if (should_track_memory()) {
     increment_reentrancy();
-    add_allocation((size_t)result, size);
+    add_allocation((size_t)result, size, __builtin_return_address(0));
     decrement_reentrancy();
   }
   return result;
@@ -492,7 +565,7 @@ __attribute__((visibility("default"))) int SYMBOL_PREFIX(posix_memalign)(
   decrement_reentrancy();
   if (!result && should_track_memory()) {
     increment_reentrancy();
-    add_allocation((size_t)*memptr, size);
+    add_allocation((size_t)*memptr, size, __builtin_return_address(0));
     decrement_reentrancy();
   }
   return result;
@@ -512,11 +585,10 @@ __attribute__((visibility("default"))) void SYMBOL_PREFIX(free)(void *addr) {
   decrement_reentrancy();
 }
 
-// On Linux this is exposed via --wrap, to get both mmap() and mmap64() without
-// fighting the fact that glibc #defines mmap as mmap64 sometimes...
-__attribute__((visibility("default"))) void *
-fil_mmap_impl(void *addr, size_t length, int prot, int flags, int fd,
-                    off_t offset) {
+// The return address is passed in by the public entry points, so it's the
+// caller's rather than somewhere inside the profiler.
+static inline void *mmap_impl(void *addr, size_t length, int prot, int flags,
+                              int fd, off_t offset, void *return_address) {
   if (unlikely(!initialized)) {
 #ifdef __APPLE__
     return mmap(addr, length, prot, flags, fd, offset);
@@ -530,17 +602,27 @@ fil_mmap_impl(void *addr, size_t length, int prot, int flags, int fd,
   if (result != MAP_FAILED && (flags & MAP_ANONYMOUS) &&
       should_track_memory()) {
     increment_reentrancy();
-    add_anon_mmap((size_t)result, length);
+    add_anon_mmap((size_t)result, length, return_address);
     decrement_reentrancy();
   }
   return result;
 }
 
+// On Linux this is exposed via --wrap, to get both mmap() and mmap64() without
+// fighting the fact that glibc #defines mmap as mmap64 sometimes...
+__attribute__((visibility("default"))) void *
+fil_mmap_impl(void *addr, size_t length, int prot, int flags, int fd,
+                    off_t offset) {
+  return mmap_impl(addr, length, prot, flags, fd, offset,
+                   __builtin_return_address(0));
+}
+
 #ifdef __APPLE__
 __attribute__((visibility("default"))) void *
 SYMBOL_PREFIX(mmap)(void *addr, size_t length, int prot, int flags, int fd,
                     off_t offset) {
-  return fil_mmap_impl(addr, length, prot, flags, fd, offset);
+  return mmap_impl(addr, length, prot, flags, fd, offset,
+                   __builtin_return_address(0));
 }
 #endif
 
@@ -552,7 +634,7 @@ SYMBOL_PREFIX(aligned_alloc)(size_t alignment, size_t size) {
 
   if (should_track_memory()) {
     increment_reentrancy();
-    add_allocation((size_t)result, size);
+    add_allocation((size_t)result, size, __builtin_return_address(0));
     decrement_reentrancy();
   }
   return result;
@@ -1,15 +1,32 @@
 #![deny(unsafe_op_in_unsafe_fn)]
-use parking_lot::Mutex;
-use pymemprofile_api::memorytracking::LineNumberInfo::LineNumber;
+use parking_lot::{Mutex, MutexGuard};
+use pymemprofile_api::buffering::{
+    AllocationEvent, EventBuffers, ThreadEvents, MAX_BUFFERED_BYTES,
+};
+use pymemprofile_api::cleanup::CleanerChain;
+use pymemprofile_api::leaks::LeakTracker;
+use pymemprofile_api::memorytracking::LineNumberInfo::{self, BytecodeIndex, LineNumber};
 use pymemprofile_api::memorytracking::{
-    AllocationTracker, CallSiteId, Callstack, FunctionId, IdentityCleaner, VecFunctionLocations,
+    AllocationCounts, AllocationTracker, CallSiteId, Callstack, CallstackId, CallstackInterner,
+    FunctionId, LineTable, LocalCallstackInterner, ProcessUid, VecFunctionLocations,
     PARENT_PROCESS,
 };
+use pymemprofile_api::native::{
+    object_for_address, LocalSharedLibraries, NativeBacktraces, SharedLibraries,
+};
 use pymemprofile_api::oom::{InfiniteMemory, OutOfMemoryEstimator, RealMemoryInfo};
+use pymemprofile_api::overhead::AllocatorStats;
+use pymemprofile_api::peaks::{summary_json, TopPeaks};
+use pymemprofile_api::python::get_line_table;
+use pymemprofile_api::sampling::Sampler;
+use pymemprofile_api::timeline::TimelineRecorder;
+use pyo3::prelude::*;
 use std::cell::RefCell;
 use std::ffi::CStr;
 use std::os::raw::{c_char, c_int, c_void};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
 
 #[macro_use]
 extern crate lazy_static;
@@ -21,16 +38,85 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+/// Statistics for our own memory, which all comes from jemalloc.
+#[cfg(target_os = "linux")]
+fn jemalloc_stats() -> Option<AllocatorStats> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+    // Statistics are cached until the epoch is advanced:
+    epoch::advance().ok()?;
+    Some(AllocatorStats {
+        allocated: stats::allocated::read().ok()?,
+        resident: stats::resident::read().ok()?,
+    })
+}
+
 thread_local!(static THREAD_CALLSTACK: RefCell<Callstack> = RefCell::new(Callstack::new()));
 
+// Callstacks this thread has already interned can get their IDs without the
+// TRACKER_STATE lock:
+thread_local!(static THREAD_INTERNER: RefCell<LocalCallstackInterner> = RefCell::new(
+    LocalCallstackInterner::new()
+));
+
+// Allocation events are buffered per thread, rather than taking the
+// TRACKER_STATE lock on every malloc() and free(). Use lock_and_flush() to get
+// an up-to-date TrackerState.
+thread_local!(static THREAD_EVENTS: Arc<Mutex<ThreadEvents>> = EVENT_BUFFERS.new_thread_buffer());
+
+// Per-thread cache of shared library pseudo-frames, if FIL_NATIVE_LIBRARIES is
+// enabled:
+thread_local!(static THREAD_LIBRARIES: RefCell<LocalSharedLibraries> = RefCell::new(
+    LocalSharedLibraries::new()
+));
+
+// If sampling is enabled, each thread decides which of its allocations to
+// track:
+thread_local!(static THREAD_SAMPLER: RefCell<Option<Sampler>> = RefCell::new(
+    SAMPLING_MEAN_BYTES.map(Sampler::new)
+));
+
+// Set once we've detected out-of-memory, so we only handle it once:
+static OUT_OF_MEMORY: AtomicBool = AtomicBool::new(false);
+
+// The process we're tracking: PARENT_PROCESS, or in a fork()ed child, a
+// ProcessUid based on its pid.
+static CURRENT_PROCESS: AtomicU32 = AtomicU32::new(PARENT_PROCESS.0);
+
+fn current_process() -> ProcessUid {
+    ProcessUid(CURRENT_PROCESS.load(Ordering::Relaxed))
+}
+
 struct TrackerState {
     oom: OutOfMemoryEstimator,
     allocations: AllocationTracker<VecFunctionLocations>,
+    native: Option<NativeBacktraces>,
+    libraries: Option<SharedLibraries>,
 }
 
 lazy_static! {
     static ref TRACKER_STATE: Mutex<TrackerState> = Mutex::new(TrackerState {
-        allocations: AllocationTracker::new("/tmp".to_string(), VecFunctionLocations::new()),
+        allocations: {
+            let mut allocations =
+                AllocationTracker::new("/tmp".to_string(), VecFunctionLocations::new());
+            if let Some(timeline) = TimelineRecorder::from_env() {
+                allocations.enable_timeline(timeline);
+            }
+            if let Some(top_peaks) = TopPeaks::from_env() {
+                allocations.enable_top_peaks(top_peaks);
+            }
+            if let Some(leaks) = LeakTracker::from_env() {
+                allocations.enable_leaks(leaks);
+            }
+            if SAMPLING_MEAN_BYTES.is_some() {
+                allocations.enable_sampling();
+            }
+            if let Some(max_callstacks) = CallstackInterner::max_callstacks_from_env() {
+                allocations.set_max_callstacks(max_callstacks);
+            }
+            #[cfg(target_os = "linux")]
+            allocations.enable_allocator_stats(jemalloc_stats);
+            allocations
+        },
         oom: OutOfMemoryEstimator::new(
             if std::env::var("__FIL_DISABLE_OOM_DETECTION") == Ok("1".to_string()) {
                 Box::new(InfiniteMemory {})
@@ -38,17 +124,76 @@ lazy_static! {
                 Box::new(RealMemoryInfo::default())
             }
         ),
+        native: if *NATIVE_BACKTRACES {
+            Some(NativeBacktraces::new())
+        } else {
+            None
+        },
+        libraries: if *NATIVE_LIBRARIES {
+            Some(SharedLibraries::new())
+        } else {
+            None
+        },
     });
+    static ref EVENT_BUFFERS: EventBuffers = EventBuffers::new();
+    static ref SAMPLING_MEAN_BYTES: Option<usize> = Sampler::mean_bytes_from_env();
+    static ref NATIVE_BACKTRACES: bool = NativeBacktraces::enabled_in_env();
+    static ref NATIVE_LIBRARIES: bool = SharedLibraries::enabled_in_env();
+    static ref CALLSTACK_CLEANER: CleanerChain = CleanerChain::from_env();
+}
+
+/// Apply the events buffered by all threads to the tracker. Returns whether
+/// we're out of memory.
+fn flush_buffered_events(tracker_state: &mut TrackerState) -> bool {
+    let check_oom = !OUT_OF_MEMORY.load(Ordering::Relaxed);
+    let mut oom = false;
+    for event in EVENT_BUFFERS.drain() {
+        let size = event.allocated_bytes();
+        if check_oom && size > 0 {
+            let current_allocated_bytes = tracker_state.allocations.get_current_allocated_bytes();
+            oom |= tracker_state
+                .oom
+                .too_big_allocation(size, current_allocated_bytes);
+        }
+        event.apply(&mut tracker_state.allocations, current_process());
+    }
+    // Make sure we get to check again before it's due:
+    EVENT_BUFFERS.set_flush_threshold(tracker_state.oom.bytes_until_next_check());
+    oom
+}
+
+/// Lock the tracker state, and bring it up-to-date with all buffered events.
+fn lock_and_flush() -> MutexGuard<'static, TrackerState> {
+    let mut tracker_state = TRACKER_STATE.lock();
+    if flush_buffered_events(&mut tracker_state) {
+        out_of_memory(tracker_state);
+    }
+    tracker_state
+}
+
+/// Record an event in the current thread's buffer, flushing if it's full.
+/// Returns false if the buffer isn't available, which only happens during
+/// thread shutdown; the caller then needs to apply the event itself.
+fn record_event(event: AllocationEvent) -> bool {
+    match THREAD_EVENTS.try_with(|buffer| EVENT_BUFFERS.record(buffer, event)) {
+        Ok(needs_flush) => {
+            if needs_flush {
+                drop(lock_and_flush());
+            }
+            true
+        }
+        Err(_) => false,
+    }
 }
 
-/// Register a new function/filename location.
-fn add_function(filename: String, function_name: String) -> FunctionId {
+/// Register a new function/filename location, and its line table.
+fn add_function(filename: String, function_name: String, line_table: LineTable) -> FunctionId {
     let tracker_state = TRACKER_STATE.try_lock();
     if let Some(mut tracker_state) = tracker_state {
         tracker_state
             .allocations
             .functions
-            .add_function(filename, function_name)
+            .add_function_with_line_table(filename, function_name, line_table)
     } else {
         // This will help in SIGUSR2 handler: dumping calls into Python, we
         // can't really acquire lock since it's in the middle of dumping. So
@@ -57,12 +202,31 @@ fn add_function(filename: String, function_name: String) -> FunctionId {
     }
 }
 
+/// Record that a filename is a notebook cell's code.
+fn set_notebook_cell(filename: &str, cell_number: u32) {
+    let mut tracker_state = lock_and_flush();
+    tracker_state
+        .allocations
+        .functions
+        .set_notebook_cell(filename, cell_number);
+}
+
+/// Convert a bytecode index from the C code, which uses `i32::MIN` to mean
+/// it's unknown.
+fn to_line_number_info(index: i32) -> LineNumberInfo {
+    if index == i32::MIN {
+        LineNumber(0)
+    } else {
+        BytecodeIndex(index)
+    }
+}
+
 /// Add to per-thread function stack:
-fn start_call(call_site: FunctionId, parent_line_number: u16, line_number: u16) {
+fn start_call(call_site: FunctionId, parent_bytecode_index: i32, bytecode_index: i32) {
     THREAD_CALLSTACK.with(|cs| {
         cs.borrow_mut().start_call(
-            parent_line_number as u32,
-            CallSiteId::new(call_site, LineNumber(line_number as u32)),
+            to_line_number_info(parent_bytecode_index),
+            CallSiteId::new(call_site, to_line_number_info(bytecode_index)),
         );
     });
 }
@@ -89,10 +253,53 @@ fn set_current_callstack(callstack: &Callstack) {
 }
 
 extern "C" {
-    fn _exit(exit_code: std::os::raw::c_int);
+    fn _exit(exit_code: std::os::raw::c_int) -> !;
     fn free(address: *mut c_void);
 }
 
+/// The pseudo-call for the shared library containing a return address.
+/// Finding the library happens without holding the lock, see SharedLibraries.
+fn library_call(return_address: usize) -> Option<CallSiteId> {
+    let cached = TRACKER_STATE.lock().libraries.as_ref()?.get(return_address);
+    if let Some(call) = cached {
+        return call;
+    }
+    let object = object_for_address(return_address);
+    let mut tracker_state = TRACKER_STATE.lock();
+    let TrackerState {
+        allocations,
+        libraries,
+        ..
+    } = &mut *tracker_state;
+    libraries
+        .as_mut()?
+        .add(return_address, object, |filename, function_name| {
+            allocations.functions.add_function(filename, function_name)
+        })
+}
+
+/// Add a pseudo-frame for the shared library that made an allocation below
+/// the callstack. The lock is only needed the first time a thread sees a
+/// return address or callstack.
+fn add_library_frame(callstack_id: CallstackId, return_address: usize) -> CallstackId {
+    let intern = |callstack_id, call: Option<CallSiteId>| {
+        TRACKER_STATE
+            .lock()
+            .allocations
+            .intern_child_callstack(callstack_id, call.as_slice())
+    };
+    THREAD_LIBRARIES
+        .try_with(|libraries| {
+            libraries.borrow_mut().get_or_insert_id(
+                callstack_id,
+                return_address,
+                library_call,
+                intern,
+            )
+        })
+        .unwrap_or_else(|_| intern(callstack_id, library_call(return_address)))
+}
+
 /// Add a new allocation based off the current callstack.
 ///
 /// This can fail if the thread local with the Python stack is not available.
@@ -101,10 +308,90 @@ extern "C" {
 fn add_allocation(
     address: usize,
     size: usize,
-    line_number: u16,
+    bytecode_index: i32,
+    return_address: usize,
     is_mmap: bool,
 ) -> Result<(), std::thread::AccessError> {
-    let mut tracker_state = TRACKER_STATE.lock();
+    // When sampling, skip most small allocations, and scale up the size of
+    // those we do track. Anonymous mmap()s are rare and big, so they're
+    // always tracked.
+    let size = if !is_mmap && address != 0 && SAMPLING_MEAN_BYTES.is_some() {
+        match THREAD_SAMPLER.try_with(|sampler| sampler.borrow_mut().as_mut()?.sample(size))? {
+            Some(scaled_size) => scaled_size,
+            None => return Ok(()),
+        }
+    } else {
+        size
+    };
+
+    // Will fail during thread shutdown, but not much we can do at that point.
+    let callstack_id = THREAD_CALLSTACK.try_with(|tcs| {
+        let mut callstack = tcs.borrow_mut();
+        callstack.id_for_new_allocation(to_line_number_info(bytecode_index), |callstack| {
+            let intern = |callstack: &mut Callstack| {
+                TRACKER_STATE.lock().allocations.intern_callstack(callstack)
+            };
+            THREAD_INTERNER
+                .try_with(|interner| interner.borrow_mut().get_or_insert_id(callstack, intern))
+                .unwrap_or_else(|_| intern(callstack))
+        })
+    })?;
+
+    // Add the native frames below the Python callstack. Unwinding and
+    // symbolization happen without holding the lock, see NativeBacktraces.
+    let callstack_id = if *NATIVE_BACKTRACES {
+        let ips = NativeBacktraces::capture();
+        let unresolved = match TRACKER_STATE.lock().native.as_ref() {
+            Some(native) => native.unresolved(&ips),
+            None => vec![],
+        };
+        let resolved = NativeBacktraces::resolve(&unresolved);
+        let mut tracker_state = TRACKER_STATE.lock();
+        let TrackerState {
+            allocations,
+            native,
+            ..
+        } = &mut *tracker_state;
+        match native.as_mut() {
+            Some(native) => {
+                native.add_resolved(resolved, |filename, function_name| {
+                    allocations.functions.add_function(filename, function_name)
+                });
+                allocations.intern_child_callstack(callstack_id, &native.calls(&ips))
+            }
+            None => callstack_id,
+        }
+    } else {
+        callstack_id
+    };
+
+    let callstack_id = if *NATIVE_LIBRARIES {
+        add_library_frame(callstack_id, return_address)
+    } else {
+        callstack_id
+    };
+
+    let event = if is_mmap {
+        AllocationEvent::AnonMmap {
+            address,
+            size,
+            callstack_id,
+        }
+    } else {
+        AllocationEvent::Allocation {
+            address,
+            size,
+            callstack_id,
+        }
+    };
+
+    // Failed and big allocations aren't buffered, so we can check right away
+    // whether we're out of memory:
+    if address != 0 && size < MAX_BUFFERED_BYTES && record_event(event) {
+        return Ok(());
+    }
+
+    let mut tracker_state = lock_and_flush();
     let current_allocated_bytes = tracker_state.allocations.get_current_allocated_bytes();
 
     // Check if we're out of memory:
@@ -112,11 +399,11 @@ fn add_allocation(
         || tracker_state
             .oom
             .too_big_allocation(size, current_allocated_bytes);
+    EVENT_BUFFERS.set_flush_threshold(tracker_state.oom.bytes_until_next_check());
 
     // If we're out-of-memory, we're not going to exit this function or ever
-    // free() anything ever again, so we should clear some memory in order to
-    // reduce chances of running out as part of OOM reporting. We can also free
-    // the allocation that just happened, cause it's never going to be used.
+    // free() anything ever again. We can free the allocation that just
+    // happened, cause it's never going to be used.
     if oom {
         if address == 0 {
             eprintln!(
@@ -133,97 +420,172 @@ fn add_allocation(
                 }
             }
         }
-        tracker_state.allocations.oom_break_glass();
-        eprintln!("=fil-profile= WARNING: Detected out-of-memory condition, exiting soon.");
-        tracker_state.oom.print_info();
     }
 
-    let allocations = &mut tracker_state.allocations;
-    // Will fail during thread shutdown, but not much we can do at that point.
-    let callstack_id = THREAD_CALLSTACK.try_with(|tcs| {
-        let mut callstack = tcs.borrow_mut();
-        callstack.id_for_new_allocation(line_number as u32, |callstack| {
-            allocations.get_callstack_id(callstack)
-        })
-    })?;
-
-    if is_mmap {
-        allocations.add_anon_mmap(PARENT_PROCESS, address, size, callstack_id);
-    } else {
-        allocations.add_allocation(PARENT_PROCESS, address, size, callstack_id);
-    }
+    event.apply(&mut tracker_state.allocations, current_process());
 
     if oom {
-        // Uh-oh, we're out of memory.
-        eprintln!(
-            "=fil-profile= We'll try to dump out SVGs. Note that no HTML file will be written."
-        );
-        let default_path = allocations.default_path.clone();
-        // Release the lock, since dumping the flamegraph will reacquire it:
-        drop(tracker_state);
-
-        dump_to_flamegraph(
-            &default_path,
-            false,
-            "out-of-memory",
-            "Current allocations at out-of-memory time",
-            false,
-        );
-        unsafe {
-            _exit(53);
-        }
-    };
+        out_of_memory(tracker_state);
+    }
     Ok(())
 }
 
+/// We're out of memory: dump current allocations and exit.
+fn out_of_memory(mut tracker_state: MutexGuard<'static, TrackerState>) -> ! {
+    OUT_OF_MEMORY.store(true, Ordering::Relaxed);
+    // We're never going to free() anything ever again, so we should clear
+    // some memory in order to reduce chances of running out as part of OOM
+    // reporting.
+    tracker_state.allocations.oom_break_glass();
+    eprintln!("=fil-profile= WARNING: Detected out-of-memory condition, exiting soon.");
+    tracker_state.oom.print_info();
+    eprintln!("=fil-profile= We'll try to dump out SVGs. Note that no HTML file will be written.");
+    let default_path = tracker_state.allocations.default_path.clone();
+    // Release the lock, since dumping the flamegraph will reacquire it:
+    drop(tracker_state);
+
+    dump_to_flamegraph(
+        &default_path,
+        false,
+        "out-of-memory",
+        "Current allocations at out-of-memory time",
+        false,
+        true,
+    );
+    unsafe {
+        _exit(53);
+    }
+}
+
 /// Free an existing allocation.
 fn free_allocation(address: usize) {
-    let mut tracker_state = TRACKER_STATE.lock();
-
-    let allocations = &mut tracker_state.allocations;
-    allocations.free_allocation(PARENT_PROCESS, address);
+    let event = AllocationEvent::Free { address };
+    if !record_event(event) {
+        event.apply(&mut lock_and_flush().allocations, current_process());
+    }
 }
 
-/// Get the size of an allocation, or 0 if it's not tracked.
+/// Get the size of an allocation, or 0 if it's not tracked. Rather than
+/// flushing all the threads' buffers, we look for the latest event for the
+/// address in them; holding the lock means no flush can happen meanwhile.
 fn get_allocation_size(address: usize) -> usize {
     let tracker_state = TRACKER_STATE.lock();
-    let allocations = &tracker_state.allocations;
-    allocations.get_allocation_size(PARENT_PROCESS, address)
+    EVENT_BUFFERS
+        .buffered_allocation_size(address)
+        .unwrap_or_else(|| {
+            tracker_state
+                .allocations
+                .get_allocation_size(current_process(), address)
+        })
+}
+
+/// Called in the parent before fork(): take all the locks, so no other thread
+/// is holding them at the moment of the fork. Released by
+/// after_fork_in_parent() or after_fork_in_child().
+fn prepare_fork() {
+    let tracker_state = lock_and_flush();
+    EVENT_BUFFERS.before_fork();
+    std::mem::forget(tracker_state);
+}
+
+fn after_fork_in_parent() {
+    // SAFETY: prepare_fork() was called by this thread and left these locked.
+    unsafe {
+        EVENT_BUFFERS.after_fork();
+        TRACKER_STATE.force_unlock();
+    }
+}
+
+/// A fork()ed child gets its own ProcessUid, and starts tracking from scratch,
+/// with a report in a `fork-<pid>` subdirectory of the parent's report.
+/// Inherited allocations belong to the parent, and are forgotten; if the
+/// child frees them, it's like freeing memory allocated before tracking
+/// started.
+fn after_fork_in_child() {
+    // SAFETY: prepare_fork() was called by this thread and left these locked.
+    unsafe {
+        EVENT_BUFFERS.after_fork();
+        TRACKER_STATE.force_unlock();
+    }
+    let current_thread = THREAD_EVENTS.try_with(|buffer| buffer.clone()).ok();
+    EVENT_BUFFERS.clear_in_child(current_thread.as_ref());
+    let pid = std::process::id();
+    CURRENT_PROCESS.store(pid, Ordering::Relaxed);
+    let mut tracker_state = TRACKER_STATE.lock();
+    let default_path = Path::new(&tracker_state.allocations.default_path)
+        .join(format!("fork-{}", pid))
+        .to_string_lossy()
+        .into_owned();
+    tracker_state.allocations.reset(default_path);
 }
 
 /// Reset internal state.
 fn reset(default_path: String) {
     // Make sure we initialize this static, to prevent deadlocks:
     pymemprofile_api::ffi::initialize();
-    let mut tracker_state = TRACKER_STATE.lock();
+    lazy_static::initialize(&CALLSTACK_CLEANER);
+    let mut tracker_state = lock_and_flush();
     tracker_state.allocations.reset(default_path);
 }
 
+const SUBTITLE: &str = r#"Made with the Fil profiler. <a href="https://pythonspeed.com/fil/" style="text-decoration: underline;" target="_parent">Try it on your code!</a>"#;
+
+/// Write the flamegraphs and `.prof` files for peak or current memory usage.
+/// Unless `out_of_memory` is true, also write the pprof, speedscope and state
+/// files; building those takes a lot of memory, which we don't have when
+/// we've run out.
 fn dump_to_flamegraph(
     path: &str,
     peak: bool,
     base_filename: &str,
     title: &str,
     to_be_post_processed: bool,
+    out_of_memory: bool,
 ) {
     // In order to render the flamegraph, we want to load source code using
     // Python's linecache. That means calling into Python, which might release
     // the GIL, allowing another thread to run, and it will try to allocation
     // and hit the TRACKER_STATE mutex. And now we're deadlocked. So we make
     // sure flamegraph rendering does not require TRACKER_STATE to be locked.
-    let (allocated_bytes, flamegraph_callstacks_factory) = {
-        let mut tracker_state = TRACKER_STATE.lock();
+    let (
+        allocated_bytes,
+        flamegraph_callstacks_factory,
+        counts_factory,
+        timeline,
+        overhead,
+        state_file_factory,
+    ) = {
+        let mut tracker_state = lock_and_flush();
         let allocations = &mut tracker_state.allocations;
 
         // Print warning if we're missing allocations.
         allocations.warn_on_problems(peak);
+        let overhead = allocations.memory_overhead();
         let allocated_bytes = if peak {
             allocations.get_peak_allocated_bytes()
         } else {
             allocations.get_current_allocated_bytes()
         };
-        let flamegraph_callstacks_factory = allocations.combine_callstacks(peak, IdentityCleaner);
-        (allocated_bytes, flamegraph_callstacks_factory)
+        let flamegraph_callstacks_factory =
+            allocations.combine_callstacks(peak, CALLSTACK_CLEANER.clone());
+        let counts_factory = allocations.combine_allocation_counts(
+            if peak {
+                AllocationCounts::Peak
+            } else {
+                AllocationCounts::Current
+            },
+            CALLSTACK_CLEANER.clone(),
+        );
+        let timeline = allocations.get_timeline().cloned();
+        let state_file_factory = (!out_of_memory).then(|| allocations.state_file_factory());
+        (
+            allocated_bytes,
+            flamegraph_callstacks_factory,
+            counts_factory,
+            timeline,
+            overhead,
+            state_file_factory,
+        )
     };
 
     let flamegraph_callstacks = flamegraph_callstacks_factory();
@@ -231,30 +593,231 @@ fn dump_to_flamegraph(
     eprintln!("=fil-profile= Preparing to write to {}", path);
     let directory_path = Path::new(path);
 
+    counts_factory().write_flamegraphs(
+        directory_path,
+        &format!("{}-allocations", base_filename),
+        &format!("{}, Number of Live Allocations", title),
+        SUBTITLE,
+        "allocations",
+        to_be_post_processed,
+    );
+
     let title = format!(
         "{} ({:.1} MiB)",
         title,
         allocated_bytes as f64 / (1024.0 * 1024.0)
     );
-    let subtitle = r#"Made with the Fil profiler. <a href="https://pythonspeed.com/fil/" style="text-decoration: underline;" target="_parent">Try it on your code!</a>"#;
     flamegraph_callstacks.write_flamegraphs(
         directory_path,
         base_filename,
         &title,
-        subtitle,
+        SUBTITLE,
         "bytes",
         to_be_post_processed,
-    )
+    );
+
+    if let Some(timeline) = timeline {
+        timeline.write_files(directory_path, "Tracked Memory Usage Over Time");
+    }
+    overhead.write_json(directory_path);
+
+    let Some(state_file_factory) = state_file_factory else {
+        return;
+    };
+
+    let pprof_path = directory_path.join(format!("{}.pb.gz", base_filename));
+    if let Err(e) = flamegraph_callstacks.write_pprof(&pprof_path, base_filename, "bytes") {
+        eprintln!("=fil-profile= Error writing pprof profile: {}", e);
+    }
+
+    let speedscope_path = directory_path.join(format!("{}.speedscope.json", base_filename));
+    match flamegraph_callstacks.write_speedscope(&speedscope_path, &title, "bytes") {
+        Ok(_) => {
+            eprintln!(
+                "=fil-profile= Wrote speedscope profile to {:?}",
+                speedscope_path
+            );
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing speedscope profile: {}", e);
+        }
+    }
+
+    state_file_factory().write_to_directory(directory_path);
+}
+
+/// Dump the number of allocations ever made, including those since freed.
+fn dump_total_allocations_to_flamegraph(path: &str) {
+    // As in dump_to_flamegraph(), rendering must happen without the lock.
+    let factory = {
+        let mut tracker_state = lock_and_flush();
+        tracker_state
+            .allocations
+            .combine_allocation_counts(AllocationCounts::Total, CALLSTACK_CLEANER.clone())
+    };
+    factory().write_flamegraphs(
+        Path::new(path),
+        "total-allocations",
+        "Number of Allocations Made While Tracking",
+        SUBTITLE,
+        "allocations",
+        true,
+    );
+}
+
+/// Dump all bytes ever allocated, including those since freed.
+fn dump_churn_to_flamegraph(path: &str) {
+    // As in dump_to_flamegraph(), rendering must happen without the lock.
+    let (churn_bytes, factory) = {
+        let tracker_state = lock_and_flush();
+        let allocations = &tracker_state.allocations;
+        (
+            allocations.get_churn_allocated_bytes(),
+            allocations.combine_churn_callstacks(CALLSTACK_CLEANER.clone()),
+        )
+    };
+    factory().write_flamegraphs(
+        Path::new(path),
+        "allocation-churn",
+        &format!(
+            "Allocation Churn, All Bytes Allocated While Tracking ({:.1} MiB)",
+            churn_bytes as f64 / (1024.0 * 1024.0)
+        ),
+        SUBTITLE,
+        "bytes",
+        true,
+    );
+}
+
+/// Dump flamegraphs for each of the highest local peaks, if enabled.
+fn dump_local_peaks_to_flamegraphs(path: &str) {
+    // As in dump_to_flamegraph(), rendering must happen without the lock.
+    let (peaks, factories) = {
+        let mut tracker_state = lock_and_flush();
+        let allocations = &mut tracker_state.allocations;
+        let peaks = allocations.get_top_peaks();
+        let factories: Vec<_> = peaks
+            .iter()
+            .map(|peak| {
+                allocations.combine_callstacks_for_local_peak(peak, CALLSTACK_CLEANER.clone())
+            })
+            .collect();
+        (peaks, factories)
+    };
+    if peaks.is_empty() {
+        return;
+    }
+
+    let directory_path = Path::new(path);
+    let base_filename = |rank: usize| format!("local-peak-{}", rank);
+    for (i, (peak, factory)) in peaks.iter().zip(factories).enumerate() {
+        let title = format!(
+            "Local Peak #{} at {:.1}s ({:.1} MiB)",
+            i + 1,
+            peak.elapsed.as_secs_f64(),
+            peak.allocated_bytes as f64 / (1024.0 * 1024.0)
+        );
+        factory().write_flamegraphs(
+            directory_path,
+            &base_filename(i + 1),
+            &title,
+            SUBTITLE,
+            "bytes",
+            true,
+        );
+    }
+    let summary_path = directory_path.join("local-peaks.json");
+    if let Err(e) = std::fs::write(&summary_path, summary_json(&peaks, base_filename)) {
+        eprintln!("=fil-profile= Error writing {:?}: {}", summary_path, e);
+    }
+}
+
+/// Dump callstacks of leaked allocations, if leak tracking is enabled.
+fn dump_leaks_to_flamegraph(path: &str) {
+    // As in dump_to_flamegraph(), rendering must happen without the lock.
+    let leaks = {
+        let tracker_state = lock_and_flush();
+        tracker_state
+            .allocations
+            .combine_leaked_callstacks(CALLSTACK_CLEANER.clone())
+    };
+    if let Some((summary, factory)) = leaks {
+        const MIB: f64 = 1024.0 * 1024.0;
+        eprintln!(
+            "=fil-profile= Leaked: {:.1} MiB. Not counted as leaks: {:.1} MiB of long-lived allocations from before the start checkpoint, {:.1} MiB allocated after the end checkpoint.",
+            summary.leaked_bytes as f64 / MIB,
+            summary.long_lived_bytes as f64 / MIB,
+            summary.after_end_checkpoint_bytes as f64 / MIB,
+        );
+        factory().write_flamegraphs(
+            Path::new(path),
+            "leaks",
+            "Leaked Memory",
+            SUBTITLE,
+            "bytes",
+            true,
+        );
+    }
 }
 
 /// Dump all callstacks in peak memory usage to format used by flamegraph.
 fn dump_peak_to_flamegraph(path: &str) {
-    dump_to_flamegraph(path, true, "peak-memory", "Peak Tracked Memory Usage", true);
+    dump_to_flamegraph(
+        path,
+        true,
+        "peak-memory",
+        "Peak Tracked Memory Usage",
+        true,
+        false,
+    );
+    dump_total_allocations_to_flamegraph(path);
+    dump_churn_to_flamegraph(path);
+    dump_local_peaks_to_flamegraphs(path);
+    dump_leaks_to_flamegraph(path);
+}
+
+/// Store a snapshot of current memory usage under the given name. If there
+/// was a previous snapshot, write a differential flamegraph showing what
+/// changed between the two.
+fn take_snapshot(name: &str) {
+    // As in dump_to_flamegraph(), rendering must happen without the lock.
+    let (previous, factory, default_path) = {
+        let mut tracker_state = lock_and_flush();
+        let allocations = &mut tracker_state.allocations;
+        let previous = allocations.take_snapshot(name);
+        let factory = previous.as_ref().and_then(|previous| {
+            allocations.diff_snapshots(previous, name, CALLSTACK_CLEANER.clone())
+        });
+        (previous, factory, allocations.default_path.clone())
+    };
+    if let (Some(previous), Some(factory)) = (previous, factory) {
+        let (before, after) = factory();
+        let to_filename =
+            |name: &str| name.replace(|c: char| !(c.is_alphanumeric() || c == '-'), "_");
+        before.write_differential_flamegraphs(
+            &after,
+            Path::new(&default_path),
+            &format!(
+                "snapshot-diff-{}-to-{}",
+                to_filename(&previous),
+                to_filename(name)
+            ),
+            &format!("Memory Changes From Snapshot {:?} To {:?}", previous, name),
+            SUBTITLE,
+            "bytes",
+            true,
+        );
+    }
 }
 
 #[no_mangle]
-extern "C" fn pymemprofile_add_allocation(address: usize, size: usize, line_number: u16) {
-    add_allocation(address, size, line_number, false).unwrap_or(());
+extern "C" fn pymemprofile_add_allocation(
+    address: usize,
+    size: usize,
+    bytecode_index: i32,
+    return_address: usize,
+) {
+    add_allocation(address, size, bytecode_index, return_address, false).unwrap_or(());
 }
 
 #[no_mangle]
@@ -269,8 +832,13 @@ extern "C" fn pymemprofile_get_allocation_size(address: usize) -> usize {
 }
 
 #[no_mangle]
-extern "C" fn pymemprofile_add_anon_mmap(address: usize, size: usize, line_number: u16) {
-    add_allocation(address, size, line_number, true).unwrap_or(());
+extern "C" fn pymemprofile_add_anon_mmap(
+    address: usize,
+    size: usize,
+    bytecode_index: i32,
+    return_address: usize,
+) {
+    add_allocation(address, size, bytecode_index, return_address, true).unwrap_or(());
 }
 
 #[no_mangle]
@@ -279,6 +847,7 @@ unsafe extern "C" fn pymemprofile_add_function_location(
     filename_length: u64,
     function_name: *const c_char,
     function_length: u64,
+    code: *mut pyo3::ffi::PyCodeObject,
 ) -> u64 {
     let filename = unsafe {
         std::str::from_utf8_unchecked(std::slice::from_raw_parts(
@@ -293,7 +862,15 @@ unsafe extern "C" fn pymemprofile_add_function_location(
         ))
     };
 
-    let function_id = add_function(filename.to_string(), function_name.to_string());
+    // We're called by the Python tracer, so we have the GIL. The line table
+    // is read now, so we don't need the code object, or the GIL, later on.
+    let line_table = Python::with_gil(|py| {
+        let code = unsafe { Bound::from_borrowed_ptr(py, code as *mut pyo3::ffi::PyObject) };
+        get_line_table(&code)
+    })
+    .unwrap_or_default();
+
+    let function_id = add_function(filename.to_string(), function_name.to_string(), line_table);
     function_id.as_u64()
 }
 
@@ -301,12 +878,12 @@ unsafe extern "C" fn pymemprofile_add_function_location(
 /// Intended for use from C APIs, what can I say.
 #[no_mangle]
 unsafe extern "C" fn pymemprofile_start_call(
-    parent_line_number: u16,
+    parent_bytecode_index: i32,
     function_id: u64,
-    line_number: u16,
+    bytecode_index: i32,
 ) {
     let function_id = FunctionId::new(function_id);
-    start_call(function_id, parent_line_number, line_number);
+    start_call(function_id, parent_bytecode_index, bytecode_index);
 }
 
 #[no_mangle]
@@ -327,6 +904,21 @@ unsafe extern "C" fn pymemprofile_reset(default_path: *const c_char) {
 
 /// # Safety
 /// Intended for use from C.
+#[no_mangle]
+extern "C" fn pymemprofile_prepare_fork() {
+    prepare_fork();
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_after_fork_in_parent() {
+    after_fork_in_parent();
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_after_fork_in_child() {
+    after_fork_in_child();
+}
+
 #[no_mangle]
 unsafe extern "C" fn pymemprofile_dump_peak_to_flamegraph(path: *const c_char) {
     let path = unsafe { CStr::from_ptr(path) }
@@ -336,6 +928,37 @@ unsafe extern "C" fn pymemprofile_dump_peak_to_flamegraph(path: *const c_char) {
     dump_peak_to_flamegraph(&path);
 }
 
+#[no_mangle]
+extern "C" fn pymemprofile_leak_checkpoint_start() {
+    lock_and_flush().allocations.leak_checkpoint_start();
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_leak_checkpoint_end() {
+    lock_and_flush().allocations.leak_checkpoint_end();
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_take_snapshot(name: *const c_char) {
+    let name = unsafe { CStr::from_ptr(name) }
+        .to_str()
+        .expect("Snapshot name wasn't UTF-8")
+        .to_string();
+    take_snapshot(&name);
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_set_notebook_cell(filename: *const c_char, cell_number: u32) {
+    let filename = unsafe { CStr::from_ptr(filename) }
+        .to_str()
+        .expect("Filename wasn't UTF-8");
+    set_notebook_cell(filename, cell_number);
+}
+
 /// # Safety
 /// Intended for use from C.
 #[no_mangle]
@@ -362,7 +985,7 @@ unsafe extern "C" fn pymemprofile_clear_current_callstack() {
     set_current_callstack(&callstack);
 }
 
-/// # A start at implementing public API from Rust
+// # A start at implementing public API from Rust
 
 /// Convert pointer into Rust closure.
 extern "C" fn trampoline<F>(user_data: *mut c_void)
@@ -395,10 +1018,10 @@ impl pymemprofile_api::mmap::MmapAPI for FilMmapAPI {
     }
 
     fn remove_mmap(&self, address: usize, length: usize) {
-        let mut tracker_state = TRACKER_STATE.lock();
-
-        let allocations = &mut tracker_state.allocations;
-        allocations.free_anon_mmap(PARENT_PROCESS, address, length);
+        let event = AllocationEvent::FreeAnonMmap { address, length };
+        if !record_event(event) {
+            event.apply(&mut lock_and_flush().allocations, current_process());
+        }
     }
 
     fn is_initialized(&self) -> bool {
@@ -409,13 +1032,15 @@ impl pymemprofile_api::mmap::MmapAPI for FilMmapAPI {
 /// On macOS we're using reimplemented_* prefix.
 #[cfg(target_os = "macos")]
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn reimplemented_munmap(addr: *mut c_void, len: usize) -> c_int {
-    return unsafe { pymemprofile_api::mmap::munmap_wrapper(addr, len, &FilMmapAPI {}) };
+    unsafe { pymemprofile_api::mmap::munmap_wrapper(addr, len, &FilMmapAPI {}) }
 }
 
 /// On Linux we're using same name as the API we're replacing.
 #[cfg(target_os = "linux")]
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn munmap(addr: *mut c_void, len: usize) -> c_int {
-    return unsafe { pymemprofile_api::mmap::munmap_wrapper(addr, len, &FilMmapAPI {}) };
+    unsafe { pymemprofile_api::mmap::munmap_wrapper(addr, len, &FilMmapAPI {}) }
 }
@@ -2,9 +2,10 @@
 
 __all__ = ["__version__"]
 
-# If we're running with Fil preloaded, after forks make sure Fil is no longer
-# enabled, since we don't yet support child processes. This is also done in C
-# code; doing it only in Python or only C doesn't seem to work.
+# If we're running with Fil preloaded, after forks mark the process as a
+# subprocess, so the Fil APIs can't be used from it; tracking of fork()ed
+# children is handled by the C code and filprofiler._tracer. This is also done
+# in C code; doing it only in Python or only C doesn't seem to work.
 import sys
 import os
 
@@ -58,6 +58,7 @@ class FilMagics(Magics):
 
 def run_with_profile(code_to_profile):
     """Run some code under Fil, display result."""
+    _register_notebook_cell(code_to_profile.__code__.co_filename)
     topdir = Path("fil-result")
     if not topdir.exists():
         topdir.mkdir()
@@ -70,3 +71,17 @@ def run_with_profile(code_to_profile):
             display(IFrame(svg_path, width="100%", height="600"))
         else:
             display(HTML("No report generated, perhaps zero memory was allocated."))
+
+
+def _register_notebook_cell(filename):
+    """
+    Tell Fil which cell the code came from, so it can be shown as e.g.
+    "Cell [12]" rather than as a temporary filename.
+    """
+    from IPython import get_ipython
+    from ._tracer import set_notebook_cell
+
+    shell = get_ipython()
+    cell_number = getattr(shell.compile, "_filename_map", {}).get(filename)
+    if cell_number is not None:
+        set_notebook_cell(filename, cell_number)
@@ -94,6 +94,86 @@ PARSER.add_argument(
         "https://github.com/pythonspeed/filprofiler/issues/494"
     ),
 )
+PARSER.add_argument(
+    "--timeline",
+    action="store_true",
+    default=False,
+    help=(
+        "Record total memory usage over time, and write it out as a chart "
+        "(memory-timeline.svg) and as data (memory-timeline.json and .csv)."
+    ),
+)
+PARSER.add_argument(
+    "--top-peaks",
+    type=int,
+    default=0,
+    metavar="K",
+    help=(
+        "In addition to the global peak, write flamegraphs for the K highest "
+        "local peaks in memory usage."
+    ),
+)
+PARSER.add_argument(
+    "--peak-separation-mb",
+    type=int,
+    default=100,
+    metavar="MB",
+    help=(
+        "With --top-peaks, how many MiB memory usage needs to drop between two "
+        "local peaks for them to count as distinct peaks."
+    ),
+)
+PARSER.add_argument(
+    "--leaks",
+    action="store_true",
+    default=False,
+    help=(
+        "Write a report of allocations still alive at exit (leaks.svg), "
+        "optionally limited with filprofiler.api.leak_checkpoint_start() and "
+        "leak_checkpoint_end()."
+    ),
+)
+PARSER.add_argument(
+    "--sampling-bytes",
+    type=int,
+    default=0,
+    metavar="BYTES",
+    help=(
+        "Lower profiling overhead by only tracking a sample of allocations, on "
+        "average one every BYTES allocated bytes. Reported memory usage is "
+        "then an estimate."
+    ),
+)
+PARSER.add_argument(
+    "--max-callstacks",
+    type=int,
+    default=0,
+    metavar="N",
+    help=(
+        "Bound memory usage for long-running programs by tracking at most "
+        "roughly N distinct callstacks; any further callstacks are coalesced "
+        "and shown as [other]."
+    ),
+)
+PARSER.add_argument(
+    "--native-backtraces",
+    action="store_true",
+    default=False,
+    help=(
+        "Also record the native (C, C++, Cython, Rust) functions that did "
+        "each allocation, below the Python callstack. This is much slower."
+    ),
+)
+PARSER.add_argument(
+    "--native-libraries",
+    action="store_true",
+    default=False,
+    help=(
+        "Add the shared library that did each allocation, e.g. "
+        "libopenblas.so, below the Python callstack. Much cheaper than "
+        "--native-backtraces."
+    ),
+)
 PARSER.add_argument(
     "--no-browser",
     action="store_true",
@@ -171,6 +251,26 @@ def stage_1():
     if arguments.disable_oom_detection:
         # See filpreload/src/lib.rs:
         environ["__FIL_DISABLE_OOM_DETECTION"] = "1"
+    if arguments.timeline:
+        # See memapi/src/timeline.rs:
+        environ["FIL_TIMELINE"] = "1"
+    if arguments.top_peaks > 0:
+        # See memapi/src/peaks.rs:
+        environ["FIL_TOP_PEAKS"] = str(arguments.top_peaks)
+        environ["FIL_PEAK_SEPARATION_MB"] = str(arguments.peak_separation_mb)
+    if arguments.leaks:
+        # See memapi/src/leaks.rs:
+        environ["FIL_LEAKS"] = "1"
+    if arguments.sampling_bytes > 0:
+        # See memapi/src/sampling.rs:
+        environ["FIL_SAMPLING_BYTES"] = str(arguments.sampling_bytes)
+    if arguments.native_backtraces:
+        environ["FIL_NATIVE_BACKTRACES"] = "1"
+    if arguments.native_libraries:
+        environ["FIL_NATIVE_LIBRARIES"] = "1"
+    if arguments.max_callstacks > 0:
+        # See CallstackInterner in memapi/src/memorytracking.rs:
+        environ["FIL_MAX_CALLSTACKS"] = str(arguments.max_callstacks)
 
     # Initial status:
     environ["__FIL_STATUS"] = "launcher"
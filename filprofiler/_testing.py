@@ -12,6 +12,23 @@ def get_allocations(
         "peak-memory-reversed.svg",
         "index.html",
         "peak-memory.prof",
+        "peak-memory-source.prof",
+        "peak-memory.pb.gz",
+        "peak-memory.speedscope.json",
+        "peak-memory-allocations.svg",
+        "peak-memory-allocations-reversed.svg",
+        "peak-memory-allocations.prof",
+        "peak-memory-allocations-source.prof",
+        "total-allocations.svg",
+        "total-allocations-reversed.svg",
+        "total-allocations.prof",
+        "total-allocations-source.prof",
+        "allocation-churn.svg",
+        "allocation-churn-reversed.svg",
+        "allocation-churn.prof",
+        "allocation-churn-source.prof",
+        "fil-overhead.json",
+        "fil-state.bin",
     ],
     prof_file="peak-memory.prof",
     direct=False,
@@ -62,8 +62,15 @@ Full trackback:
     )
 
 
+# Where the report for the current tracing session will be written, if
+# tracing is on:
+_output_path = None
+
+
 def start_tracing(output_path: Union[str, Path]):
     """Start tracing allocations."""
+    global _output_path
+    _output_path = str(output_path)
     preload.fil_reset(str(output_path).encode("utf-8"))
     preload.fil_start_tracking()
     threading.settrace(_start_thread_trace)
@@ -88,6 +95,8 @@ def stop_tracing(output_path: str) -> str:
 
     Returns path to the index HTML page of the report.
     """
+    global _output_path
+    _output_path = None
     sys.settrace(None)
     threading.settrace(None)
     preload.fil_stop_tracking()
@@ -98,6 +107,72 @@ def stop_tracing(output_path: str) -> str:
     return result
 
 
+def _after_fork_in_child():
+    """
+    A fork()ed child keeps tracking on its own (see the fork() wrapper in
+    _filpreload.c), and gets its own report in a subdirectory of the parent's.
+    """
+    global _output_path
+    if _output_path is None:
+        return
+    output_path = _output_path = os.path.join(_output_path, f"fork-{os.getpid()}")
+    dumped = False
+
+    def dump():
+        nonlocal dumped
+        if dumped or _output_path != output_path:
+            return
+        dumped = True
+        if os.environ.get("FIL_NO_REPORT"):
+            return
+        index_path = stop_tracing(output_path)
+        print(
+            f"=fil-profile= Wrote HTML report for subprocess {os.getpid()} to "
+            + index_path,
+            file=sys.stderr,
+        )
+
+    atexit.register(dump)
+
+    # multiprocessing children exit via os._exit() once the Process's
+    # _bootstrap() is done, which skips atexit handlers. Only this child's
+    # copy of the class is changed:
+    mp_process = sys.modules.get("multiprocessing.process")
+    if mp_process is not None:
+        original_bootstrap = mp_process.BaseProcess._bootstrap
+
+        def _bootstrap(self, *args, **kwargs):
+            try:
+                return original_bootstrap(self, *args, **kwargs)
+            finally:
+                dump()
+
+        mp_process.BaseProcess._bootstrap = _bootstrap
+
+
+os.register_at_fork(after_in_child=_after_fork_in_child)
+
+
+def take_snapshot(name: str):
+    """Snapshot current memory usage, diffing it against the previous snapshot."""
+    preload.fil_take_snapshot(name.encode("utf-8"))
+
+
+def set_notebook_cell(filename: str, cell_number: int):
+    """The code with this filename is the given Jupyter notebook cell."""
+    preload.fil_set_notebook_cell(filename.encode("utf-8"), cell_number)
+
+
+def leak_checkpoint_start():
+    """Allocations alive now are long-lived state, not leaks."""
+    preload.fil_leak_checkpoint_start()
+
+
+def leak_checkpoint_end():
+    """Allocations made from now on aren't leaks."""
+    preload.fil_leak_checkpoint_end()
+
+
 def create_report(output_path: Union[str, Path]) -> str:
     preload.fil_dump_peak_to_flamegraph(str(output_path).encode("utf-8"))
     now = datetime.now()
@@ -109,7 +184,12 @@ def trace_until_exit(function, args, kwargs, output_path: str, open_browser: boo
     Given function, run it under the tracer until the program exits.
     """
 
+    parent_pid = os.getpid()
+
     def shutdown():
+        if os.getpid() != parent_pid:
+            # A fork()ed child, which writes its own report:
+            return
         if os.environ.get("FIL_NO_REPORT"):
             print(
                 "=fil-profile= FIL_NO_REPORT env variable is set, skipping report.",
@@ -45,4 +45,45 @@ def profile(code_to_profile: Callable[[], _T], path: Union[str, Path]) -> _T:
             stop_tracing(path)
 
 
-__all__ = ["profile"]
+def take_snapshot(name: str):
+    """
+    Store a snapshot of current memory usage under the given name.
+
+    Every snapshot after the first is compared to the one before it, and the
+    differences are written as a differential flamegraph to the output
+    directory, ``snapshot-diff-<previous>-to-<name>.svg``.
+    """
+    from ._tracer import take_snapshot, check_if_fil_preloaded
+
+    check_if_fil_preloaded()
+    take_snapshot(name)
+
+
+def leak_checkpoint_start():
+    """
+    When running with ``--leaks``, mark all currently alive allocations as
+    long-lived state, so they're not reported as leaks.
+    """
+    from ._tracer import leak_checkpoint_start, check_if_fil_preloaded
+
+    check_if_fil_preloaded()
+    leak_checkpoint_start()
+
+
+def leak_checkpoint_end():
+    """
+    When running with ``--leaks``, don't report allocations made from now on as
+    leaks.
+    """
+    from ._tracer import leak_checkpoint_end, check_if_fil_preloaded
+
+    check_if_fil_preloaded()
+    leak_checkpoint_end()
+
+
+__all__ = [
+    "profile",
+    "take_snapshot",
+    "leak_checkpoint_start",
+    "leak_checkpoint_end",
+]
@@ -0,0 +1,75 @@
+//! Merge `.prof` files from multiple processes or runs into one profile.
+//!
+//! Usage: fil-merge [--mode sum|max|per-source] [--title TITLE]
+//!                  [--count-name NAME] -o OUTPUT_DIR FILE.prof...
+
+use std::path::PathBuf;
+use std::process::exit;
+
+use pymemprofile_api::merge::{merge_prof_files, MergeMode};
+
+const USAGE: &str = "\
+Usage: fil-merge [--mode sum|max|per-source] [--title TITLE] [--count-name NAME] -o OUTPUT_DIR FILE.prof...
+
+Merges .prof files, e.g. peak-memory.prof from several worker processes, and
+writes merged.prof and merged.svg/merged-reversed.svg flamegraphs to OUTPUT_DIR.
+
+  --mode sum         Add up each callstack's size across files (default).
+  --mode max         Take each callstack's largest size across files.
+  --mode per-source  Keep files separate, under a root frame named after each.
+";
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("=fil-profile= {}\n\n{}", message, USAGE);
+    exit(2);
+}
+
+fn main() {
+    let mut mode = MergeMode::Sum;
+    let mut title = "Merged Profile".to_string();
+    let mut count_name = "bytes".to_string();
+    let mut output_dir = None;
+    let mut inputs: Vec<PathBuf> = vec![];
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .unwrap_or_else(|| usage_error(&format!("{} requires a value", name)))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                return;
+            }
+            "--mode" => {
+                mode = value("--mode")
+                    .parse()
+                    .unwrap_or_else(|e: String| usage_error(&e))
+            }
+            "--title" => title = value("--title"),
+            "--count-name" => count_name = value("--count-name"),
+            "-o" | "--output" => output_dir = Some(PathBuf::from(value("-o"))),
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+    let output_dir = output_dir.unwrap_or_else(|| usage_error("No output directory given"));
+    if inputs.is_empty() {
+        usage_error("No .prof files given");
+    }
+
+    // Rendering looks up Python source files, so we need an interpreter:
+    pyo3::prepare_freethreaded_python();
+    let merged = merge_prof_files(&inputs, mode).unwrap_or_else(|e| {
+        eprintln!("=fil-profile= Error merging profiles: {}", e);
+        exit(1);
+    });
+    merged.write_flamegraphs(
+        &output_dir,
+        "merged",
+        &title,
+        "Made with the Fil profiler.",
+        &count_name,
+        false,
+    );
+}
@@ -0,0 +1,131 @@
+//! Re-render a flamegraph SVG from a saved `.prof` or `-source.prof` file,
+//! with different presentation options.
+//!
+//! Usage: fil-render [OPTIONS] -o OUTPUT.svg INPUT.prof
+
+use std::path::PathBuf;
+use std::process::exit;
+
+use pymemprofile_api::flamegraph::{
+    flamegraph_options, get_flamegraph_with_options, has_source_code, parse_lines,
+};
+
+const USAGE: &str = "\
+Usage: fil-render [OPTIONS] -o OUTPUT.svg INPUT.prof
+
+Renders a flamegraph SVG from a .prof or -source.prof file saved by Fil.
+
+Options:
+  --title TITLE         Title of the flamegraph.
+  --subtitle SUBTITLE   Subtitle of the flamegraph.
+  --count-name NAME     What the sizes count (default: bytes).
+  --width PIXELS        Width of the image (default: fit to the browser).
+  --colors PALETTE      Frame colors, e.g. hot, mem, io, red, blue, python
+                        (default: hot).
+  --bgcolors COLOR      Background: yellow, blue, green, grey or #rrggbb.
+  --reversed            Put the allocating functions at the top.
+  --min-width PIXELS    Omit frames narrower than this (default: 0.2).
+  --min-percent PERCENT Omit callstacks using less than this percentage of
+                        the total (default: 0).
+";
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("=fil-profile= {}\n\n{}", message, USAGE);
+    exit(2);
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: String) -> T
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .unwrap_or_else(|e| usage_error(&format!("Bad value for {}: {}", name, e)))
+}
+
+fn main() {
+    let mut title = "Peak Tracked Memory Usage".to_string();
+    let mut subtitle = "Made with the Fil profiler.".to_string();
+    let mut count_name = "bytes".to_string();
+    let mut width: Option<usize> = None;
+    let mut colors = None;
+    let mut bgcolors = None;
+    let mut reversed = false;
+    let mut min_width: Option<f64> = None;
+    let mut min_percent = 0.0;
+    let mut output = None;
+    let mut input = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .unwrap_or_else(|| usage_error(&format!("{} requires a value", name)))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                return;
+            }
+            "--title" => title = value("--title"),
+            "--subtitle" => subtitle = value("--subtitle"),
+            "--count-name" => count_name = value("--count-name"),
+            "--width" => width = Some(parse_value("--width", value("--width"))),
+            "--colors" => colors = Some(parse_value("--colors", value("--colors"))),
+            "--bgcolors" => bgcolors = Some(parse_value("--bgcolors", value("--bgcolors"))),
+            "--reversed" => reversed = true,
+            "--min-width" => min_width = Some(parse_value("--min-width", value("--min-width"))),
+            "--min-percent" => min_percent = parse_value("--min-percent", value("--min-percent")),
+            "-o" | "--output" => output = Some(PathBuf::from(value("-o"))),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => usage_error("Only one input file can be given"),
+        }
+    }
+    let output = output.unwrap_or_else(|| usage_error("No output file given"));
+    let input = input.unwrap_or_else(|| usage_error("No input file given"));
+
+    let contents = std::fs::read_to_string(&input).unwrap_or_else(|e| {
+        eprintln!("=fil-profile= Couldn't read {}: {}", input.display(), e);
+        exit(1);
+    });
+    let lines = parse_lines(&contents).unwrap_or_else(|e| {
+        eprintln!("=fil-profile= Couldn't parse {}, {}", input.display(), e);
+        exit(1);
+    });
+    let total: usize = lines.iter().map(|(_, size)| size).sum();
+    let threshold = total as f64 * min_percent / 100.0;
+    let to_be_post_processed = has_source_code(&lines);
+
+    let mut options = flamegraph_options(reversed, &title, &count_name, to_be_post_processed);
+    options.image_width = width;
+    if let Some(colors) = colors {
+        options.colors = colors;
+    }
+    options.bgcolors = bgcolors;
+    if let Some(min_width) = min_width {
+        options.min_width = min_width;
+    }
+    if !to_be_post_processed {
+        options.subtitle = Some(subtitle.clone());
+    }
+
+    let result = get_flamegraph_with_options(
+        lines
+            .iter()
+            .filter(|(_, size)| *size as f64 >= threshold)
+            .map(|(callstack, size)| format!("{} {}", callstack, size)),
+        to_be_post_processed,
+        options,
+        Some(&subtitle),
+    )
+    .and_then(|svg| Ok(std::fs::write(&output, svg)?));
+    match result {
+        Ok(_) => {
+            eprintln!("=fil-profile= Wrote flamegraph to {:?}", output);
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing SVG: {}", e);
+            exit(1);
+        }
+    }
+}
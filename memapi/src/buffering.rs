@@ -0,0 +1,459 @@
+//! Per-thread buffering of allocation events.
+//!
+//! Taking a global lock on every malloc() and free() serializes multithreaded
+//! programs. Instead, each thread records events in its own buffer, and every
+//! so often all the buffers are flushed into the AllocationTracker.
+//!
+//! Events get a sequence number from a global counter when they're recorded,
+//! and flushing applies the events from all threads merged in sequence order.
+//! The tracker therefore sees exactly the same stream of events as it would
+//! with a global lock, so peak memory is still exact, and a free() of memory
+//! allocated by another thread is applied after the corresponding malloc().
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use itertools::Itertools;
+use parking_lot::Mutex;
+
+use crate::memorytracking::{AllocationTracker, CallstackId, ProcessUid, WriteFunctionLocations};
+use crate::overhead::PerThreadOverhead;
+
+/// Flush once a thread has buffered this many events...
+const MAX_BUFFERED_EVENTS: usize = 1024;
+
+/// ... or once all threads together have buffered this many newly allocated
+/// bytes, or fewer, see EventBuffers::set_flush_threshold(). Allocations at
+/// least this big shouldn't be buffered at all, so that out-of-memory
+/// detection can see them immediately.
+pub const MAX_BUFFERED_BYTES: usize = 16 * 1024 * 1024;
+
+/// Something that happened that the AllocationTracker needs to know about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocationEvent {
+    Allocation {
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    },
+    AnonMmap {
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    },
+    Free {
+        address: usize,
+    },
+    FreeAnonMmap {
+        address: usize,
+        length: usize,
+    },
+}
+
+impl AllocationEvent {
+    /// How many bytes this event allocates, if any.
+    pub fn allocated_bytes(&self) -> usize {
+        match self {
+            AllocationEvent::Allocation { size, .. } | AllocationEvent::AnonMmap { size, .. } => {
+                *size
+            }
+            AllocationEvent::Free { .. } | AllocationEvent::FreeAnonMmap { .. } => 0,
+        }
+    }
+
+    pub fn apply<FL: WriteFunctionLocations>(
+        self,
+        tracker: &mut AllocationTracker<FL>,
+        process: ProcessUid,
+    ) {
+        match self {
+            AllocationEvent::Allocation {
+                address,
+                size,
+                callstack_id,
+            } => tracker.add_allocation(process, address, size, callstack_id),
+            AllocationEvent::AnonMmap {
+                address,
+                size,
+                callstack_id,
+            } => tracker.add_anon_mmap(process, address, size, callstack_id),
+            AllocationEvent::Free { address } => {
+                tracker.free_allocation(process, address);
+            }
+            AllocationEvent::FreeAnonMmap { address, length } => {
+                tracker.free_anon_mmap(process, address, length)
+            }
+        }
+    }
+}
+
+/// A single thread's buffered events.
+#[derive(Default)]
+pub struct ThreadEvents {
+    // (sequence number, event), in increasing sequence order:
+    events: Vec<(u64, AllocationEvent)>,
+    overhead: PerThreadOverhead,
+}
+
+impl ThreadEvents {
+    fn update_overhead(&mut self) {
+        self.overhead
+            .update(self.events.capacity() * std::mem::size_of::<(u64, AllocationEvent)>());
+    }
+}
+
+/// All threads' event buffers.
+///
+/// Lock ordering: if you need the AllocationTracker's lock, take it before
+/// calling drain(), and never take it while recording an event.
+pub struct EventBuffers {
+    buffers: Mutex<Vec<Arc<Mutex<ThreadEvents>>>>,
+    next_sequence: AtomicU64,
+    // Bytes allocated by the buffered events of all threads:
+    buffered_bytes: AtomicUsize,
+    // Flush once buffered_bytes reaches this:
+    flush_threshold_bytes: AtomicUsize,
+}
+
+impl Default for EventBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBuffers {
+    pub fn new() -> Self {
+        Self {
+            buffers: Mutex::new(vec![]),
+            next_sequence: AtomicU64::new(0),
+            buffered_bytes: AtomicUsize::new(0),
+            flush_threshold_bytes: AtomicUsize::new(MAX_BUFFERED_BYTES),
+        }
+    }
+
+    /// Create and register a buffer for a new thread.
+    pub fn new_thread_buffer(&self) -> Arc<Mutex<ThreadEvents>> {
+        let buffer = Arc::new(Mutex::new(ThreadEvents::default()));
+        self.buffers.lock().push(buffer.clone());
+        buffer
+    }
+
+    /// Record an event in a thread's buffer. Returns whether it's time to
+    /// flush.
+    pub fn record(&self, buffer: &Mutex<ThreadEvents>, event: AllocationEvent) -> bool {
+        let mut buffer = buffer.lock();
+        // Getting the sequence number while holding the buffer's lock ensures
+        // drain() never sees a later event without also seeing the earlier
+        // ones. Relaxed is sufficient: if the malloc() happens-before a
+        // free() on another thread, the latter will get a higher number.
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        buffer.events.push((sequence, event));
+        buffer.update_overhead();
+        let allocated_bytes = event.allocated_bytes();
+        let needs_flush = allocated_bytes > 0 && {
+            let buffered_bytes = self
+                .buffered_bytes
+                .fetch_add(allocated_bytes, Ordering::Relaxed)
+                + allocated_bytes;
+            buffered_bytes >= self.flush_threshold_bytes.load(Ordering::Relaxed)
+        };
+        needs_flush || buffer.events.len() >= MAX_BUFFERED_EVENTS
+    }
+
+    /// Flush once all threads together have buffered this many newly
+    /// allocated bytes, e.g. so that out-of-memory detection gets to look at
+    /// them before it's due to check available memory again. It's capped at
+    /// MAX_BUFFERED_BYTES.
+    pub fn set_flush_threshold(&self, bytes: usize) {
+        self.flush_threshold_bytes
+            .store(bytes.min(MAX_BUFFERED_BYTES), Ordering::Relaxed);
+    }
+
+    /// Remove all buffered events from all threads, returning them in the
+    /// order they happened.
+    pub fn drain(&self) -> Vec<AllocationEvent> {
+        let mut buffers = self.buffers.lock();
+        let events = {
+            // Lock all the buffers at once, so we get all events up to some
+            // point in the sequence, with no gaps.
+            let mut locked: Vec<_> = buffers.iter().map(|buffer| buffer.lock()).collect();
+            // Events are only recorded while holding their buffer's lock, so
+            // this matches the events we're taking:
+            self.buffered_bytes.store(0, Ordering::Relaxed);
+            let events = locked
+                .iter_mut()
+                .map(|buffer| {
+                    let events = std::mem::take(&mut buffer.events);
+                    buffer.update_overhead();
+                    events
+                })
+                .kmerge_by(|(a, _), (b, _)| a < b)
+                .map(|(_, event)| event)
+                .collect();
+            events
+        };
+        // Buffers only we refer to belong to threads that have exited:
+        buffers.retain(|buffer| Arc::strong_count(buffer) > 1);
+        events
+    }
+
+    /// The size of the allocation at the given address according to the
+    /// buffered events, without flushing them: Some(0) if it was most
+    /// recently freed, or None if no buffered event mentions it, in which case
+    /// the AllocationTracker knows the answer. The caller should hold the
+    /// AllocationTracker's lock, so no flush can happen in the middle.
+    pub fn buffered_allocation_size(&self, address: usize) -> Option<usize> {
+        let buffers = self.buffers.lock();
+        buffers
+            .iter()
+            .filter_map(|buffer| {
+                buffer
+                    .lock()
+                    .events
+                    .iter()
+                    .rev()
+                    .find_map(|(sequence, event)| match *event {
+                        AllocationEvent::Allocation {
+                            address: a, size, ..
+                        } if a == address => Some((*sequence, size)),
+                        AllocationEvent::Free { address: a } if a == address => {
+                            Some((*sequence, 0))
+                        }
+                        _ => None,
+                    })
+            })
+            .max_by_key(|(sequence, _)| *sequence)
+            .map(|(_, size)| size)
+    }
+
+    /// Lock everything, so that no other thread is holding one of our locks
+    /// when fork() happens; in the child that lock would never be released.
+    /// Must be followed by after_fork() in both parent and child.
+    pub fn before_fork(&self) {
+        // Same lock order as drain():
+        let buffers = self.buffers.lock();
+        for buffer in buffers.iter() {
+            std::mem::forget(buffer.lock());
+        }
+        std::mem::forget(buffers);
+    }
+
+    /// Release the locks taken by before_fork().
+    ///
+    /// # Safety
+    ///
+    /// Must only be called once after each before_fork(), by the same thread.
+    pub unsafe fn after_fork(&self) {
+        // SAFETY: we hold the locks, per the caller's guarantees.
+        unsafe { self.buffers.force_unlock() };
+        for buffer in self.buffers.lock().iter() {
+            // SAFETY: we hold the locks, per the caller's guarantees.
+            unsafe { buffer.force_unlock() };
+        }
+    }
+
+    /// In a fork()ed child, only the thread that called fork() exists, and
+    /// all buffered events happened in the parent. So drop the other
+    /// threads' buffers, and discard all events.
+    pub fn clear_in_child(&self, current_thread: Option<&Arc<Mutex<ThreadEvents>>>) {
+        let mut buffers = self.buffers.lock();
+        buffers.retain(|buffer| current_thread.is_some_and(|current| Arc::ptr_eq(buffer, current)));
+        for buffer in buffers.iter() {
+            *buffer.lock() = ThreadEvents::default();
+        }
+        self.buffered_bytes.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllocationEvent, EventBuffers, MAX_BUFFERED_BYTES, MAX_BUFFERED_EVENTS};
+    use crate::memorytracking::{
+        AllocationTracker, CallSiteId, Callstack, FunctionId, LineNumberInfo::LineNumber,
+        VecFunctionLocations, PARENT_PROCESS,
+    };
+
+    fn alloc(address: usize, size: usize) -> AllocationEvent {
+        AllocationEvent::Allocation {
+            address,
+            size,
+            callstack_id: 0,
+        }
+    }
+
+    #[test]
+    fn drain_merges_threads_in_sequence_order() {
+        let buffers = EventBuffers::new();
+        let thread1 = buffers.new_thread_buffer();
+        let thread2 = buffers.new_thread_buffer();
+        buffers.record(&thread1, alloc(1, 100));
+        buffers.record(&thread2, alloc(2, 50));
+        // Freed by a different thread than the one that allocated it:
+        buffers.record(&thread2, AllocationEvent::Free { address: 1 });
+        buffers.record(&thread1, AllocationEvent::Free { address: 2 });
+        let events = buffers.drain();
+        assert_eq!(
+            events,
+            vec![
+                alloc(1, 100),
+                alloc(2, 50),
+                AllocationEvent::Free { address: 1 },
+                AllocationEvent::Free { address: 2 }
+            ]
+        );
+        assert_eq!(buffers.drain(), vec![]);
+
+        // Applying them gives the same peak as an unbuffered tracker would:
+        let mut tracker = AllocationTracker::new("/tmp".to_string(), VecFunctionLocations::new());
+        let mut callstack = Callstack::new();
+        callstack.start_call(
+            LineNumber(0),
+            CallSiteId::new(FunctionId::new(1), LineNumber(1)),
+        );
+        tracker.get_callstack_id(&callstack);
+        for event in events {
+            event.apply(&mut tracker, PARENT_PROCESS);
+        }
+        assert_eq!(tracker.get_peak_allocated_bytes(), 150);
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+    }
+
+    #[test]
+    fn flush_needed_when_buffer_is_full() {
+        let buffers = EventBuffers::new();
+        let thread = buffers.new_thread_buffer();
+        for i in 1..MAX_BUFFERED_EVENTS {
+            assert!(!buffers.record(&thread, AllocationEvent::Free { address: i }));
+        }
+        assert!(buffers.record(&thread, AllocationEvent::Free { address: 0 }));
+        buffers.drain();
+        assert!(!buffers.record(&thread, alloc(1, MAX_BUFFERED_BYTES - 1)));
+        assert!(buffers.record(&thread, alloc(2, 1)));
+    }
+
+    #[test]
+    fn flush_needed_when_all_threads_allocated_enough() {
+        let buffers = EventBuffers::new();
+        let thread1 = buffers.new_thread_buffer();
+        let thread2 = buffers.new_thread_buffer();
+        assert!(!buffers.record(&thread1, alloc(1, MAX_BUFFERED_BYTES / 2)));
+        assert!(buffers.record(&thread2, alloc(2, MAX_BUFFERED_BYTES / 2)));
+        buffers.drain();
+
+        // E.g. out-of-memory detection wants to check again soon:
+        buffers.set_flush_threshold(1000);
+        assert!(!buffers.record(&thread1, alloc(3, 600)));
+        assert!(!buffers.record(&thread1, AllocationEvent::Free { address: 3 }));
+        assert!(buffers.record(&thread2, alloc(4, 400)));
+        buffers.drain();
+        assert!(!buffers.record(&thread2, alloc(5, 999)));
+
+        // The threshold is capped:
+        buffers.set_flush_threshold(usize::MAX);
+        buffers.drain();
+        assert!(buffers.record(&thread1, alloc(6, MAX_BUFFERED_BYTES)));
+    }
+
+    #[test]
+    fn concurrent_cross_thread_frees_are_ordered() {
+        use std::sync::{mpsc, Arc, Mutex};
+
+        let buffers = Arc::new(EventBuffers::new());
+        let mut tracker = AllocationTracker::new("/tmp".to_string(), VecFunctionLocations::new());
+        tracker.get_callstack_id(&Callstack::new());
+        let tracker = Arc::new(Mutex::new(tracker));
+        let flush = |buffers: &EventBuffers, tracker: &Mutex<AllocationTracker<_>>| {
+            let mut tracker = tracker.lock().unwrap();
+            for event in buffers.drain() {
+                event.apply(&mut tracker, PARENT_PROCESS);
+            }
+        };
+
+        // Each allocating thread hands its allocations to a freeing thread:
+        let mut threads = vec![];
+        for i in 0..4 {
+            let (sender, receiver) = mpsc::channel::<usize>();
+            let (b, t) = (buffers.clone(), tracker.clone());
+            threads.push(std::thread::spawn(move || {
+                let buffer = b.new_thread_buffer();
+                for j in 0..10_000 {
+                    if b.record(&buffer, alloc(i * 1_000_000 + j, 10)) {
+                        flush(&b, &t);
+                    }
+                    sender.send(i * 1_000_000 + j).unwrap();
+                }
+            }));
+            let (b, t) = (buffers.clone(), tracker.clone());
+            threads.push(std::thread::spawn(move || {
+                let buffer = b.new_thread_buffer();
+                for address in receiver {
+                    if b.record(&buffer, AllocationEvent::Free { address }) {
+                        flush(&b, &t);
+                    }
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        flush(&buffers, &tracker);
+
+        // If any free() had been applied before its malloc(), the allocation
+        // would still be around:
+        let tracker = tracker.lock().unwrap();
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+        assert!(tracker.get_peak_allocated_bytes() >= 10);
+    }
+
+    #[test]
+    fn buffered_allocation_size_uses_latest_event() {
+        let buffers = EventBuffers::new();
+        let thread1 = buffers.new_thread_buffer();
+        let thread2 = buffers.new_thread_buffer();
+        assert_eq!(buffers.buffered_allocation_size(1), None);
+        buffers.record(&thread1, alloc(1, 100));
+        buffers.record(&thread1, alloc(2, 50));
+        assert_eq!(buffers.buffered_allocation_size(1), Some(100));
+        // Freed and reallocated by another thread:
+        buffers.record(&thread2, AllocationEvent::Free { address: 1 });
+        assert_eq!(buffers.buffered_allocation_size(1), Some(0));
+        buffers.record(&thread1, alloc(1, 30));
+        assert_eq!(buffers.buffered_allocation_size(1), Some(30));
+        assert_eq!(buffers.buffered_allocation_size(2), Some(50));
+        // Nothing is flushed:
+        assert_eq!(buffers.drain().len(), 4);
+        assert_eq!(buffers.buffered_allocation_size(1), None);
+    }
+
+    #[test]
+    fn fork_locks_are_released() {
+        let buffers = EventBuffers::new();
+        let thread1 = buffers.new_thread_buffer();
+        let thread2 = buffers.new_thread_buffer();
+        buffers.record(&thread1, alloc(1, 100));
+        buffers.before_fork();
+        assert!(thread1.try_lock().is_none());
+        assert!(buffers.buffers.try_lock().is_none());
+        unsafe { buffers.after_fork() };
+        buffers.record(&thread2, alloc(2, 100));
+
+        // In the child, only the current thread's buffer is left, empty:
+        buffers.before_fork();
+        unsafe { buffers.after_fork() };
+        buffers.clear_in_child(Some(&thread2));
+        assert_eq!(buffers.buffers.lock().len(), 1);
+        assert_eq!(buffers.drain(), vec![]);
+        buffers.record(&thread2, alloc(3, 100));
+        assert_eq!(buffers.drain(), vec![alloc(3, 100)]);
+    }
+
+    #[test]
+    fn exited_threads_buffers_are_dropped() {
+        let buffers = EventBuffers::new();
+        let thread = buffers.new_thread_buffer();
+        buffers.record(&thread, alloc(1, 100));
+        drop(thread);
+        assert_eq!(buffers.drain(), vec![alloc(1, 100)]);
+        assert_eq!(buffers.buffers.lock().len(), 0);
+    }
+}
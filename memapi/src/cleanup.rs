@@ -0,0 +1,805 @@
+//! CallstackCleaners that hide frames that aren't interesting, so the
+//! flamegraphs are easier to read.
+//!
+//! They can be combined with a CleanerChain, which can also be configured
+//! from the environment:
+//!
+//! * `FIL_HIDE_FRAMES` is a `;`-separated list of frames to hide, each one of
+//!   `file:GLOB`, `function:GLOB`, `file-regex:REGEX` or
+//!   `function-regex:REGEX`.
+//! * `FIL_DROP_IMPORTLIB_FRAMES=1` hides importlib's bootstrap frames.
+//! * `FIL_COLLAPSE_LIBRARY_FRAMES=1` collapses runs of standard library and
+//!   installed package frames into a single `[library]` frame.
+//! * `FIL_FOLD_RECURSION=1` folds recursive calls into a `(recursive)`
+//!   frame.
+//!
+//! The runpy frames Fil's own startup adds to the start of every callstack
+//! are always removed, see StripRunpyPrefix.
+
+use std::{borrow::Cow, sync::Arc};
+
+use regex::Regex;
+
+use crate::{
+    flamegraph::CallstackCleaner,
+    memorytracking::ReadFunctionLocations,
+    memorytracking::{CallSiteId, Callstack, FunctionId, LineNumberInfo},
+    python::{get_runpy_path, get_stdlib_path},
+};
+
+/// Whether the filename is that of the stdlib's runpy module.
+fn is_runpy(filename: &str) -> bool {
+    // On Python 3.11 it uses <frozen runpy> for some reason.
+    filename == get_runpy_path() || filename == "<frozen runpy>"
+}
+
+/// Keep only the calls for which `keep(filename, function_name)` is true.
+fn retain_calls<'a>(
+    callstack: &'a Callstack,
+    functions: &dyn ReadFunctionLocations,
+    keep: impl Fn(&str, &str) -> bool,
+) -> Cow<'a, Callstack> {
+    let is_kept = |call: &CallSiteId| {
+        let (function, filename, _) =
+            functions.get_function_and_filename_and_display_filename(call.function);
+        keep(filename, function)
+    };
+    if callstack.calls().iter().all(is_kept) {
+        return Cow::Borrowed(callstack);
+    }
+    Cow::Owned(Callstack::from_vec(
+        callstack.calls().iter().copied().filter(is_kept).collect(),
+    ))
+}
+
+/// Convert a glob, with the same syntax as Python's `fnmatch` module, into an
+/// equivalent regex: `*` matches anything (including `/`), `?` matches a
+/// single character, and `[...]`/`[!...]` match a set of characters.
+fn glob_to_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut pattern = String::from("^");
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            '[' => {
+                // Like fnmatch, a "]" straight after the "[" or "[!" is part
+                // of the set, and an unclosed "[" is just a "[".
+                let mut start = i + 1;
+                let negated = chars.get(start) == Some(&'!');
+                if negated {
+                    start += 1;
+                }
+                let mut end = start;
+                if chars.get(end) == Some(&']') {
+                    end += 1;
+                }
+                while end < chars.len() && chars[end] != ']' {
+                    end += 1;
+                }
+                if end == chars.len() {
+                    pattern.push_str(r"\[");
+                } else {
+                    pattern.push('[');
+                    if negated {
+                        pattern.push('^');
+                    }
+                    for c in &chars[start..end] {
+                        if matches!(c, '\\' | '[' | ']' | '^' | '&' | '~') {
+                            pattern.push('\\');
+                        }
+                        pattern.push(*c);
+                    }
+                    pattern.push(']');
+                    i = end;
+                }
+            }
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    pattern.push('$');
+    Regex::new(&pattern)
+}
+
+/// Which frames to hide, see HideFrames.
+#[derive(Clone, Debug)]
+pub enum FrameMatcher {
+    /// Frames whose full filename matches.
+    Filename(Regex),
+    /// Frames whose function name matches.
+    Function(Regex),
+}
+
+impl FrameMatcher {
+    /// Match filenames with a glob, e.g. `*/site-packages/pandas/*`.
+    pub fn filename_glob(glob: &str) -> Result<Self, regex::Error> {
+        Ok(Self::Filename(glob_to_regex(glob)?))
+    }
+
+    /// Match function names with a glob, e.g. `_*`.
+    pub fn function_glob(glob: &str) -> Result<Self, regex::Error> {
+        Ok(Self::Function(glob_to_regex(glob)?))
+    }
+
+    /// Match filenames with a regex; it can match anywhere in the filename.
+    pub fn filename_regex(regex: &str) -> Result<Self, regex::Error> {
+        Ok(Self::Filename(Regex::new(regex)?))
+    }
+
+    /// Match function names with a regex; it can match anywhere in the name.
+    pub fn function_regex(regex: &str) -> Result<Self, regex::Error> {
+        Ok(Self::Function(Regex::new(regex)?))
+    }
+
+    /// Parse the `kind:pattern` format used by `FIL_HIDE_FRAMES`.
+    pub fn parse(rule: &str) -> Result<Self, String> {
+        let (kind, pattern) = rule
+            .split_once(':')
+            .ok_or_else(|| format!("expected KIND:PATTERN, got {:?}", rule))?;
+        match kind.trim() {
+            "file" => Self::filename_glob(pattern),
+            "function" => Self::function_glob(pattern),
+            "file-regex" => Self::filename_regex(pattern),
+            "function-regex" => Self::function_regex(pattern),
+            kind => return Err(format!("unknown kind of frame pattern {:?}", kind)),
+        }
+        .map_err(|e| e.to_string())
+    }
+
+    fn matches(&self, filename: &str, function: &str) -> bool {
+        match self {
+            Self::Filename(regex) => regex.is_match(filename),
+            Self::Function(regex) => regex.is_match(function),
+        }
+    }
+}
+
+/// Hide frames that match any of the given FrameMatchers.
+#[derive(Clone, Debug)]
+pub struct HideFrames {
+    matchers: Vec<FrameMatcher>,
+}
+
+impl HideFrames {
+    pub fn new(matchers: Vec<FrameMatcher>) -> Self {
+        Self { matchers }
+    }
+}
+
+impl CallstackCleaner for HideFrames {
+    fn cleanup<'a>(
+        &self,
+        callstack: &'a Callstack,
+        functions: &dyn ReadFunctionLocations,
+    ) -> Cow<'a, Callstack> {
+        retain_calls(callstack, functions, |filename, function| {
+            !self
+                .matchers
+                .iter()
+                .any(|matcher| matcher.matches(filename, function))
+        })
+    }
+
+    fn merges_callstacks(&self) -> bool {
+        true
+    }
+}
+
+/// Remove the runpy frames at the start of callstacks, which are there due to
+/// implementation details of how Fil runs the program. Callstacks that are
+/// nothing but runpy frames are left alone.
+#[derive(Clone, Copy, Debug)]
+pub struct StripRunpyPrefix;
+
+impl CallstackCleaner for StripRunpyPrefix {
+    fn cleanup<'a>(
+        &self,
+        callstack: &'a Callstack,
+        functions: &dyn ReadFunctionLocations,
+    ) -> Cow<'a, Callstack> {
+        let calls = callstack.calls();
+        let prefix_length = calls
+            .iter()
+            .take_while(|call| {
+                let (_, filename, _) =
+                    functions.get_function_and_filename_and_display_filename(call.function);
+                is_runpy(filename)
+            })
+            .count();
+        if prefix_length == 0 || prefix_length == calls.len() {
+            Cow::Borrowed(callstack)
+        } else {
+            Cow::Owned(Callstack::from_vec(calls[prefix_length..].to_vec()))
+        }
+    }
+
+    fn merges_callstacks(&self) -> bool {
+        true
+    }
+}
+
+/// Hide the frames of importlib's bootstrap code, which show up whenever a
+/// module is imported.
+#[derive(Clone, Copy, Debug)]
+pub struct DropImportlibFrames;
+
+impl DropImportlibFrames {
+    fn is_importlib_bootstrap(filename: &str) -> bool {
+        // E.g. "<frozen importlib._bootstrap_external>":
+        filename.starts_with("<frozen importlib._bootstrap")
+            || filename.ends_with("/importlib/_bootstrap.py")
+            || filename.ends_with("/importlib/_bootstrap_external.py")
+    }
+}
+
+impl CallstackCleaner for DropImportlibFrames {
+    fn cleanup<'a>(
+        &self,
+        callstack: &'a Callstack,
+        functions: &dyn ReadFunctionLocations,
+    ) -> Cow<'a, Callstack> {
+        retain_calls(callstack, functions, |filename, _| {
+            !Self::is_importlib_bootstrap(filename)
+        })
+    }
+
+    fn merges_callstacks(&self) -> bool {
+        true
+    }
+}
+
+/// Collapse each run of consecutive frames from the standard library or
+/// from installed packages into a single `[library]` frame.
+///
+/// The runpy frames at the start of callstacks are left alone, since
+/// StripRunpyPrefix removes them.
+#[derive(Clone, Debug, Default)]
+pub struct CollapseLibraryFrames {
+    stdlib_directory: Option<String>,
+}
+
+impl CollapseLibraryFrames {
+    /// The standard library is found using the running Python interpreter.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use the given standard library directory, rather than asking Python.
+    pub fn with_stdlib_directory(directory: &str) -> Self {
+        Self {
+            stdlib_directory: Some(directory.trim_end_matches('/').to_string()),
+        }
+    }
+
+    fn is_library(&self, filename: &str) -> bool {
+        if filename.contains("/site-packages/") || filename.contains("/dist-packages/") {
+            return true;
+        }
+        if filename.starts_with("<frozen ") {
+            return filename != "<frozen runpy>";
+        }
+        let stdlib_directory = match self.stdlib_directory {
+            Some(ref directory) => directory.as_str(),
+            None => get_stdlib_path(),
+        };
+        // runpy lives in the standard library too:
+        filename
+            .strip_prefix(stdlib_directory)
+            .map(|rest| rest.starts_with('/') && rest != "/runpy.py")
+            .unwrap_or(false)
+    }
+}
+
+impl CallstackCleaner for CollapseLibraryFrames {
+    fn cleanup<'a>(
+        &self,
+        callstack: &'a Callstack,
+        functions: &dyn ReadFunctionLocations,
+    ) -> Cow<'a, Callstack> {
+        let library_call = CallSiteId::new(FunctionId::LIBRARY, LineNumberInfo::LineNumber(0));
+        let mut calls: Vec<CallSiteId> = Vec::with_capacity(callstack.calls().len());
+        let mut changed = false;
+        for call in callstack.calls() {
+            let (_, filename, _) =
+                functions.get_function_and_filename_and_display_filename(call.function);
+            if !self.is_library(filename) {
+                calls.push(*call);
+                continue;
+            }
+            changed = true;
+            if calls.last() != Some(&library_call) {
+                calls.push(library_call);
+            }
+        }
+        if changed {
+            Cow::Owned(Callstack::from_vec(calls))
+        } else {
+            Cow::Borrowed(callstack)
+        }
+    }
+
+    fn merges_callstacks(&self) -> bool {
+        true
+    }
+}
+
+/// Fold recursion, direct (`f` calling `f`) or mutual (`f` calling `g`
+/// calling `f`), so that callstacks that only differ in how deep the recursion
+/// went end up the same, or at least a lot more similar.
+///
+/// When a cycle of calls repeats three or more times, the first and last
+/// iterations are kept, and the iterations in between are replaced by a
+/// single `(recursive)` frame. It doesn't say how many iterations were
+/// hidden, since then callstacks of different depths wouldn't be the same.
+/// Calls are compared by function, ignoring line numbers, so e.g. the left
+/// and right branches of a tree walker count as the same call.
+#[derive(Clone, Copy, Debug)]
+pub struct FoldRecursion {
+    max_cycle_length: usize,
+}
+
+impl Default for FoldRecursion {
+    fn default() -> Self {
+        Self {
+            max_cycle_length: 8,
+        }
+    }
+}
+
+impl FoldRecursion {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only look for cycles of up to this many calls.
+    pub fn with_max_cycle_length(max_cycle_length: usize) -> Self {
+        Self {
+            max_cycle_length: max_cycle_length.max(1),
+        }
+    }
+
+    /// Find the shortest cycle starting at `start` that repeats at least
+    /// three times, returning its length and how many times it repeats.
+    fn find_cycle(&self, calls: &[CallSiteId], start: usize) -> Option<(usize, usize)> {
+        let same_functions = |a: &[CallSiteId], b: &[CallSiteId]| {
+            a.iter().zip(b).all(|(a, b)| a.function == b.function)
+        };
+        let remaining = calls.len() - start;
+        (1..=self.max_cycle_length.min(remaining / 3)).find_map(|length| {
+            let cycle = &calls[start..start + length];
+            let repetitions = 1 + calls[start + length..]
+                .chunks_exact(length)
+                .take_while(|chunk| same_functions(chunk, cycle))
+                .count();
+            (repetitions >= 3).then_some((length, repetitions))
+        })
+    }
+}
+
+impl CallstackCleaner for FoldRecursion {
+    fn cleanup<'a>(
+        &self,
+        callstack: &'a Callstack,
+        _functions: &dyn ReadFunctionLocations,
+    ) -> Cow<'a, Callstack> {
+        let calls = callstack.calls();
+        let mut folded: Option<Vec<CallSiteId>> = None;
+        let mut i = 0;
+        while i < calls.len() {
+            match self.find_cycle(calls, i) {
+                Some((length, repetitions)) => {
+                    let folded = folded.get_or_insert_with(|| calls[..i].to_vec());
+                    let last = i + (repetitions - 1) * length;
+                    folded.extend_from_slice(&calls[i..i + length]);
+                    folded.push(CallSiteId::new(
+                        FunctionId::RECURSIVE,
+                        LineNumberInfo::LineNumber(0),
+                    ));
+                    folded.extend_from_slice(&calls[last..last + length]);
+                    i = last + length;
+                }
+                None => {
+                    if let Some(ref mut folded) = folded {
+                        folded.push(calls[i]);
+                    }
+                    i += 1;
+                }
+            }
+        }
+        match folded {
+            Some(folded) => Cow::Owned(Callstack::from_vec(folded)),
+            None => Cow::Borrowed(callstack),
+        }
+    }
+
+    fn merges_callstacks(&self) -> bool {
+        true
+    }
+}
+
+/// Apply several CallstackCleaners, in order.
+#[derive(Clone, Default)]
+pub struct CleanerChain {
+    cleaners: Vec<Arc<dyn CallstackCleaner + Send + Sync>>,
+}
+
+impl CleanerChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add another cleaner, run after the existing ones.
+    pub fn then<CC: CallstackCleaner + Send + Sync + 'static>(mut self, cleaner: CC) -> Self {
+        self.cleaners.push(Arc::new(cleaner));
+        self
+    }
+
+    /// Configure cleaners using the `FIL_HIDE_FRAMES`,
+    /// `FIL_DROP_IMPORTLIB_FRAMES`, `FIL_COLLAPSE_LIBRARY_FRAMES` and
+    /// `FIL_FOLD_RECURSION` environment variables. With none of them set,
+    /// only the runpy prefix is removed.
+    pub fn from_env() -> Self {
+        Self::from_settings(
+            std::env::var("FIL_HIDE_FRAMES").ok().as_deref(),
+            std::env::var("FIL_DROP_IMPORTLIB_FRAMES").as_deref() == Ok("1"),
+            std::env::var("FIL_COLLAPSE_LIBRARY_FRAMES").as_deref() == Ok("1"),
+            std::env::var("FIL_FOLD_RECURSION").as_deref() == Ok("1"),
+        )
+    }
+
+    fn from_settings(
+        hide_frames: Option<&str>,
+        drop_importlib: bool,
+        collapse: bool,
+        fold_recursion: bool,
+    ) -> Self {
+        let mut chain = Self::new();
+        // Fil4prod doesn't use runpy to start the program:
+        if !cfg!(feature = "fil4prod") {
+            chain = chain.then(StripRunpyPrefix);
+        }
+        // Importlib frames are dropped first, so that they don't interrupt a
+        // run of library frames.
+        if drop_importlib {
+            chain = chain.then(DropImportlibFrames);
+        }
+        let matchers: Vec<FrameMatcher> = hide_frames
+            .unwrap_or("")
+            .split(';')
+            .filter(|rule| !rule.trim().is_empty())
+            .filter_map(|rule| match FrameMatcher::parse(rule) {
+                Ok(matcher) => Some(matcher),
+                Err(e) => {
+                    eprintln!("=fil-profile= Ignoring bad FIL_HIDE_FRAMES entry: {}", e);
+                    None
+                }
+            })
+            .collect();
+        if !matchers.is_empty() {
+            chain = chain.then(HideFrames::new(matchers));
+        }
+        if collapse {
+            chain = chain.then(CollapseLibraryFrames::new());
+        }
+        // Recursion is folded last, since e.g. a visitor calling itself via
+        // library code only looks recursive once the library frames are
+        // collapsed.
+        if fold_recursion {
+            chain = chain.then(FoldRecursion::new());
+        }
+        chain
+    }
+}
+
+impl CallstackCleaner for CleanerChain {
+    fn cleanup<'a>(
+        &self,
+        callstack: &'a Callstack,
+        functions: &dyn ReadFunctionLocations,
+    ) -> Cow<'a, Callstack> {
+        let mut result = Cow::Borrowed(callstack);
+        for cleaner in &self.cleaners {
+            result = match result {
+                Cow::Borrowed(callstack) => cleaner.cleanup(callstack, functions),
+                Cow::Owned(callstack) => {
+                    Cow::Owned(cleaner.cleanup(&callstack, functions).into_owned())
+                }
+            };
+        }
+        result
+    }
+
+    fn merges_callstacks(&self) -> bool {
+        self.cleaners
+            .iter()
+            .any(|cleaner| cleaner.merges_callstacks())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        glob_to_regex, CleanerChain, CollapseLibraryFrames, DropImportlibFrames, FoldRecursion,
+        FrameMatcher, HideFrames, StripRunpyPrefix,
+    };
+    use crate::flamegraph::{CallstackCleaner, FlamegraphCallstacks};
+    use crate::memorytracking::{
+        CallSiteId, Callstack, FunctionId, LineNumberInfo::LineNumber, VecFunctionLocations,
+    };
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+
+    /// Create a callstack with a call for each (filename, function) pair,
+    /// returning it along with the function locations.
+    fn callstack(calls: &[(&str, &str)]) -> (Callstack, VecFunctionLocations) {
+        let mut functions = VecFunctionLocations::new();
+        let calls = calls
+            .iter()
+            .enumerate()
+            .map(|(i, (filename, function))| {
+                let id = functions.add_function(filename.to_string(), function.to_string());
+                CallSiteId::new(id, LineNumber(i as u32 + 1))
+            })
+            .collect();
+        (Callstack::from_vec(calls), functions)
+    }
+
+    fn names(callstack: &Callstack, functions: &VecFunctionLocations) -> Vec<String> {
+        use crate::memorytracking::ReadFunctionLocations;
+        callstack
+            .calls()
+            .iter()
+            .map(|call| {
+                functions
+                    .get_function_and_filename_and_display_filename(call.function)
+                    .0
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn globs() {
+        let regex = glob_to_regex("*/site-packages/pa?das/*.py").unwrap();
+        assert!(regex.is_match("/venv/site-packages/pandas/core/frame.py"));
+        assert!(!regex.is_match("/venv/site-packages/pandas/core/frame.pyc"));
+        let regex = glob_to_regex("[!_]*").unwrap();
+        assert!(regex.is_match("public"));
+        assert!(!regex.is_match("_private"));
+        assert!(glob_to_regex("f[ab]").unwrap().is_match("fa"));
+        assert!(glob_to_regex("a.b").unwrap().is_match("a.b"));
+        assert!(!glob_to_regex("a.b").unwrap().is_match("axb"));
+        assert!(glob_to_regex("[oops").unwrap().is_match("[oops"));
+        assert!(glob_to_regex("[]]").unwrap().is_match("]"));
+    }
+
+    #[test]
+    fn hide_frames_by_filename_or_function() {
+        let (callstack, functions) = callstack(&[
+            ("/app/main.py", "main"),
+            ("/venv/site-packages/click/core.py", "invoke"),
+            ("/app/lib.py", "_helper"),
+            ("/app/lib.py", "work"),
+        ]);
+        let cleaner = HideFrames::new(vec![
+            FrameMatcher::parse("file:*/click/*").unwrap(),
+            FrameMatcher::parse("function-regex:^_").unwrap(),
+        ]);
+        let cleaned = cleaner.cleanup(&callstack, &functions);
+        assert_eq!(names(&cleaned, &functions), vec!["main", "work"]);
+
+        // Nothing to hide means no copy:
+        let cleaner = HideFrames::new(vec![FrameMatcher::parse("function:nope").unwrap()]);
+        assert!(matches!(
+            cleaner.cleanup(&callstack, &functions),
+            Cow::Borrowed(_)
+        ));
+
+        assert!(FrameMatcher::parse("nocolon").is_err());
+        assert!(FrameMatcher::parse("line:1").is_err());
+        assert!(FrameMatcher::parse("file-regex:(").is_err());
+    }
+
+    #[test]
+    fn strip_runpy_prefix() {
+        pyo3::prepare_freethreaded_python();
+        let (with_prefix, functions) = callstack(&[
+            ("<frozen runpy>", "_run_module_as_main"),
+            ("<frozen runpy>", "_run_code"),
+            ("/app/main.py", "<module>"),
+            ("<frozen runpy>", "run_path"),
+        ]);
+        let cleaned = StripRunpyPrefix.cleanup(&with_prefix, &functions);
+        assert_eq!(names(&cleaned, &functions), vec!["<module>", "run_path"]);
+
+        // Nothing else to show, so keep the runpy frames:
+        let (only_runpy, functions) = callstack(&[("<frozen runpy>", "_run_code")]);
+        assert!(matches!(
+            StripRunpyPrefix.cleanup(&only_runpy, &functions),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn drop_importlib_frames() {
+        let (callstack, functions) = callstack(&[
+            ("/app/main.py", "<module>"),
+            ("<frozen importlib._bootstrap>", "_find_and_load"),
+            ("<frozen importlib._bootstrap_external>", "exec_module"),
+            ("/app/module.py", "<module>"),
+        ]);
+        let cleaned = DropImportlibFrames.cleanup(&callstack, &functions);
+        assert_eq!(names(&cleaned, &functions), vec!["<module>", "<module>"]);
+    }
+
+    #[test]
+    fn collapse_library_frames() {
+        let (callstack, functions) = callstack(&[
+            ("/usr/lib/python3.11/runpy.py", "_run_module_as_main"),
+            ("<frozen runpy>", "_run_code"),
+            ("/app/main.py", "main"),
+            ("/usr/lib/python3.11/json/__init__.py", "loads"),
+            ("/usr/lib/python3.11/json/decoder.py", "decode"),
+            ("/venv/lib/python3.11/site-packages/pkg/hooks.py", "hook"),
+            ("/app/callbacks.py", "callback"),
+            ("/usr/lib/python3.11/copy.py", "deepcopy"),
+        ]);
+        let cleaner = CollapseLibraryFrames::with_stdlib_directory("/usr/lib/python3.11/");
+        let cleaned = cleaner.cleanup(&callstack, &functions);
+        assert_eq!(
+            names(&cleaned, &functions),
+            vec![
+                "_run_module_as_main",
+                "_run_code",
+                "main",
+                "[library]",
+                "callback",
+                "[library]"
+            ]
+        );
+        assert_eq!(cleaned.calls()[3].function, FunctionId::LIBRARY);
+    }
+
+    #[test]
+    fn identical_cleaned_callstacks_are_merged() {
+        let (callstack, functions) = callstack(&[
+            ("/app/a.py", "main"),
+            ("/app/b.py", "_one"),
+            ("/app/c.py", "_two"),
+        ]);
+        let mut calls = callstack.to_vec();
+        let two = calls.pop().unwrap();
+        calls.pop();
+        calls.push(two);
+        let data: HashMap<Callstack, usize> =
+            HashMap::from([(callstack, 10), (Callstack::from_vec(calls), 5)]);
+        let cleaner = HideFrames::new(vec![FrameMatcher::parse("function:_*").unwrap()]);
+        let lines: Vec<String> = FlamegraphCallstacks::new(data, functions, cleaner)
+            .to_lines(false)
+            .collect();
+        assert_eq!(lines, vec!["/app/a.py:1 (main) 15"]);
+    }
+
+    #[test]
+    fn fold_direct_and_mutual_recursion() {
+        let functions = {
+            let mut functions = VecFunctionLocations::new();
+            for name in ["main", "walk", "even", "odd", "leaf"] {
+                functions.add_function("/app/r.py".to_string(), name.to_string());
+            }
+            functions
+        };
+        let call =
+            |function: u64, line: u32| CallSiteId::new(FunctionId::new(function), LineNumber(line));
+        let to_string = |callstack: &Callstack| {
+            callstack.as_string(
+                false,
+                false,
+                &functions,
+                ";",
+                &mut crate::linecache::LineCacher::default(),
+            )
+        };
+
+        let fold = |calls: Vec<CallSiteId>| {
+            to_string(&FoldRecursion::new().cleanup(&Callstack::from_vec(calls), &functions))
+        };
+
+        // Direct recursion, with calls from different lines:
+        let mut calls = vec![call(0, 1)];
+        calls.extend([5, 6, 5, 6, 5].map(|line| call(1, line)));
+        calls.push(call(1, 9));
+        calls.push(call(4, 2));
+        assert_eq!(
+            fold(calls),
+            "/app/r.py:1 (main);/app/r.py:5 (walk);(recursive);/app/r.py:9 (walk);/app/r.py:2 (leaf)"
+        );
+
+        // Mutual recursion:
+        let mut calls = vec![call(0, 1)];
+        for _ in 0..4 {
+            calls.extend([call(2, 3), call(3, 4)]);
+        }
+        assert_eq!(
+            fold(calls),
+            "/app/r.py:1 (main);/app/r.py:3 (even);/app/r.py:4 (odd);(recursive);/app/r.py:3 (even);/app/r.py:4 (odd)"
+        );
+
+        // Callstacks of different depths become the same:
+        let depth = |n: usize| {
+            let mut calls = vec![call(0, 1)];
+            calls.extend(std::iter::repeat_n(call(1, 5), n));
+            Callstack::from_vec(calls)
+        };
+        let folded = FoldRecursion::new()
+            .cleanup(&depth(5), &functions)
+            .into_owned();
+        assert_eq!(
+            to_string(&folded),
+            "/app/r.py:1 (main);/app/r.py:5 (walk);(recursive);/app/r.py:5 (walk)"
+        );
+        for n in [3, 4, 50] {
+            assert_eq!(
+                FoldRecursion::new()
+                    .cleanup(&depth(n), &functions)
+                    .into_owned(),
+                folded
+            );
+        }
+
+        // Recursing only twice isn't worth folding:
+        let twice = Callstack::from_vec(vec![call(0, 1), call(1, 5), call(1, 5), call(4, 2)]);
+        assert!(matches!(
+            FoldRecursion::new().cleanup(&twice, &functions),
+            Cow::Borrowed(_)
+        ));
+
+        // Cycles longer than the limit aren't folded:
+        let mut calls = vec![];
+        for _ in 0..3 {
+            calls.extend([call(2, 3), call(3, 4)]);
+        }
+        let calls = Callstack::from_vec(calls);
+        assert!(matches!(
+            FoldRecursion::with_max_cycle_length(1).cleanup(&calls, &functions),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn chain_from_settings() {
+        pyo3::prepare_freethreaded_python();
+        let (with_runpy, with_runpy_functions) =
+            callstack(&[("<frozen runpy>", "_run_code"), ("/app/main.py", "main")]);
+        let (callstack, functions) = callstack(&[
+            ("/app/main.py", "main"),
+            ("<frozen importlib._bootstrap>", "_find_and_load"),
+            ("/app/module.py", "<module>"),
+            ("/app/module.py", "_setup"),
+        ]);
+        // Nothing configured, only the runpy prefix is removed:
+        let chain = CleanerChain::from_settings(None, false, false, false);
+        assert!(matches!(
+            chain.cleanup(&callstack, &functions),
+            Cow::Borrowed(_)
+        ));
+        let cleaned = chain.cleanup(&with_runpy, &with_runpy_functions);
+        assert_eq!(names(&cleaned, &with_runpy_functions), vec!["main"]);
+        assert!(!CleanerChain::new().merges_callstacks());
+        // Anything that changes callstacks can make them the same:
+        assert!(
+            CleanerChain::from_settings(Some("function:_*"), false, false, false)
+                .merges_callstacks()
+        );
+        assert!(CleanerChain::from_settings(None, true, false, false).merges_callstacks());
+        assert!(CleanerChain::from_settings(None, false, true, false).merges_callstacks());
+        assert!(CleanerChain::from_settings(None, false, false, true).merges_callstacks());
+
+        // Bad entries are skipped:
+        let chain = CleanerChain::from_settings(Some("function:_*; oops ;"), true, false, false);
+        let cleaned = chain.cleanup(&callstack, &functions);
+        assert_eq!(names(&cleaned, &functions), vec!["main", "<module>"]);
+    }
+}
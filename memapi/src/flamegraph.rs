@@ -1,11 +1,13 @@
-use std::{borrow::Cow, fs, io::Write, path::Path};
+use std::{borrow::Cow, collections::HashMap, fs, io::Write, path::Path};
 
-use inferno::flamegraph;
+use inferno::{differential, flamegraph};
 use itertools::Itertools;
 
 use crate::{
     linecache::LineCacher,
     memorytracking::{Callstack, ReadFunctionLocations},
+    pprof::{self, ProfileBuilder},
+    speedscope::{self, FileBuilder},
 };
 
 /// Filter down to top 99% of samples.
@@ -64,9 +66,51 @@ pub fn write_lines<I: IntoIterator<Item = String>>(lines: I, path: &Path) -> std
     Ok(())
 }
 
-/// A strategy for cleaning up callstacks before rendering them to text.
+/// Parse lines in the format written by FlamegraphCallstacks::to_lines(),
+/// e.g. a saved `.prof` or `-source.prof` file, back into (callstack, size)
+/// pairs.
+pub fn parse_lines(contents: &str) -> Result<Vec<(&str, usize)>, String> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let (callstack, size) = line
+                .rsplit_once(' ')
+                .ok_or_else(|| format!("line {}: missing size", i + 1))?;
+            let size = size
+                .parse()
+                .map_err(|e| format!("line {}: bad size {:?}: {}", i + 1, size, e))?;
+            Ok((callstack, size))
+        })
+        .collect()
+}
+
+/// Whether parsed lines include source code, i.e. came from a `-source.prof`
+/// file, in which case they need post-processing when rendered.
+pub fn has_source_code(lines: &[(&str, usize)]) -> bool {
+    lines
+        .iter()
+        .any(|(callstack, _)| callstack.contains('\u{2800}'))
+}
+
+/// A strategy for cleaning up callstacks before rendering them to text. See
+/// the cleanup module for implementations that hide frames.
 pub trait CallstackCleaner {
-    fn cleanup<'a>(&self, callstack: &'a Callstack) -> Cow<'a, Callstack>;
+    fn cleanup<'a>(
+        &self,
+        callstack: &'a Callstack,
+        functions: &dyn ReadFunctionLocations,
+    ) -> Cow<'a, Callstack>;
+
+    /// Whether cleaning up can turn many callstacks into the same one, which
+    /// is the case for any cleaner that hides, collapses or folds frames. If
+    /// so, callstacks are cleaned up and merged before the biggest ones are
+    /// picked for rendering, rather than only afterwards, so outputs like
+    /// pprof profiles don't end up with duplicate callstacks.
+    fn merges_callstacks(&self) -> bool {
+        false
+    }
 }
 
 /// The data needed to create a flamegraph.
@@ -93,25 +137,55 @@ where
     }
 
     /// Create iterator over the line-based string format parsed by the inferno
-    /// crate.
+    /// crate, with full filenames.
     pub fn to_lines(
         &'a self,
         to_be_post_processed: bool,
     ) -> impl ExactSizeIterator<Item = String> + 'a {
-        let by_call = (&self.data).into_iter();
+        self.lines(to_be_post_processed, false)
+    }
+
+    /// Like to_lines(), but with the shorter display filenames, for
+    /// rendering.
+    pub fn to_display_lines(
+        &'a self,
+        to_be_post_processed: bool,
+    ) -> impl ExactSizeIterator<Item = String> + 'a {
+        self.lines(to_be_post_processed, true)
+    }
+
+    fn lines(
+        &'a self,
+        to_be_post_processed: bool,
+        display_filenames: bool,
+    ) -> impl ExactSizeIterator<Item = String> + 'a {
         let mut linecache = LineCacher::default();
-        by_call.map(move |(callstack, size)| {
-            format!(
-                "{} {}",
-                self.callstack_cleaner.cleanup(callstack).as_string(
+        // Cleaning up callstacks can make different callstacks render the
+        // same, in which case their sizes are added up:
+        let mut indexes: HashMap<String, usize> = HashMap::new();
+        let mut lines: Vec<(String, usize)> = Vec::new();
+        for (callstack, size) in &self.data {
+            let callstack = self
+                .callstack_cleaner
+                .cleanup(callstack, &self.functions)
+                .as_string(
                     to_be_post_processed,
+                    display_filenames,
                     &self.functions,
                     ";",
                     &mut linecache,
-                ),
-                size,
-            )
-        })
+                );
+            match indexes.get(&callstack) {
+                Some(index) => lines[*index].1 += size,
+                None => {
+                    indexes.insert(callstack.clone(), lines.len());
+                    lines.push((callstack, *size));
+                }
+            }
+        }
+        lines
+            .into_iter()
+            .map(|(callstack, size)| format!("{} {}", callstack, size))
     }
 
     /// Low-level interface for writing flamegraphs with post-processing:
@@ -122,8 +196,40 @@ where
         // special cased because it needs to be psot-processed:
         subtitle: Option<&str>,
     ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let lines = self.to_lines(to_be_post_processed);
-        get_flamegraph_with_options(lines, to_be_post_processed, options, subtitle)
+        let lines = self.to_display_lines(to_be_post_processed);
+        let svg = get_flamegraph_with_options(lines, to_be_post_processed, options, subtitle)?;
+        Ok(add_full_filenames_to_tooltips(svg, &self.full_filenames()))
+    }
+
+    /// The display filenames used by the callstacks, mapped to their full
+    /// filenames. Display filenames that are the same as the full filename,
+    /// or that are used for more than one file, are omitted.
+    fn full_filenames(&'a self) -> HashMap<String, String> {
+        let mut full_filenames: HashMap<&str, Option<&str>> = HashMap::new();
+        for (callstack, _) in &self.data {
+            for call in callstack.to_vec() {
+                let (_, filename, display_filename) = self
+                    .functions
+                    .get_function_and_filename_and_display_filename(call.function);
+                if filename == display_filename {
+                    continue;
+                }
+                full_filenames
+                    .entry(display_filename)
+                    .and_modify(|existing| {
+                        if *existing != Some(filename) {
+                            *existing = None;
+                        }
+                    })
+                    .or_insert(Some(filename));
+            }
+        }
+        full_filenames
+            .into_iter()
+            .filter_map(|(display_filename, filename)| {
+                Some((display_filename.to_string(), filename?.to_string()))
+            })
+            .collect()
     }
 
     /// Write a flamegraph SVG to disk, given lines in summarized format.
@@ -135,14 +241,15 @@ where
         count_name: &str,
         to_be_post_processed: bool,
     ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        get_flamegraph(
-            self.to_lines(to_be_post_processed),
+        let svg = get_flamegraph(
+            self.to_display_lines(to_be_post_processed),
             reversed,
             title,
             subtitle,
             count_name,
             to_be_post_processed,
-        )
+        )?;
+        Ok(add_full_filenames_to_tooltips(svg, &self.full_filenames()))
     }
 
     /// Write a flamegraph SVG to disk.
@@ -162,6 +269,54 @@ where
         Ok(())
     }
 
+    /// Write a gzipped pprof profile.proto file, for use with `go tool pprof`
+    /// and other pprof-compatible viewers.
+    pub fn write_pprof(
+        &'a self,
+        path: &Path,
+        sample_type: &str,
+        unit: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = ProfileBuilder::new(sample_type, unit);
+        for (callstack, size) in &self.data {
+            let callstack = self.callstack_cleaner.cleanup(callstack, &self.functions);
+            let calls = callstack.resolved_calls(&self.functions);
+            builder.add_sample(
+                calls.iter().map(|(id, (function, filename, _))| {
+                    (*function, *filename, id.line_number.get_line_number())
+                }),
+                *size as i64,
+            );
+        }
+        pprof::write_gzipped(&builder.build(), path)?;
+        Ok(())
+    }
+
+    /// Write a speedscope JSON file, for its left-heavy and sandwich views.
+    pub fn write_speedscope(
+        &'a self,
+        path: &Path,
+        name: &str,
+        unit: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = FileBuilder::new(name, unit);
+        for (callstack, size) in &self.data {
+            let callstack = self.callstack_cleaner.cleanup(callstack, &self.functions);
+            let calls = callstack.resolved_calls(&self.functions);
+            builder.add_sample(
+                calls.iter().map(|(id, (function, _, display_filename))| {
+                    (
+                        *function,
+                        *display_filename,
+                        id.line_number.get_line_number(),
+                    )
+                }),
+                *size as u64,
+            );
+        }
+        speedscope::write_json(&builder.build(), path)
+    }
+
     /// Write .prof, -source.prof, .svg and -reversed.svg files for given lines.
     pub fn write_flamegraphs(
         &'a self,
@@ -172,12 +327,7 @@ where
         count_name: &str,
         to_be_post_processed: bool,
     ) {
-        if !directory_path.exists() {
-            fs::create_dir_all(directory_path)
-                .expect("=fil-profile= Couldn't create the output directory.");
-        } else if !directory_path.is_dir() {
-            panic!("=fil-profile= Output path must be a directory.");
-        }
+        create_output_directory(directory_path);
 
         let raw_path_without_source_code = directory_path.join(format!("{}.prof", base_filename));
 
@@ -191,8 +341,9 @@ where
             return;
         }
 
-        // Optionally write version with source code for SVGs, if we're using
-        // source code.
+        // Optionally write version with source code, if we're using source
+        // code; the SVGs are rendered from it, and it's kept so `fil-render`
+        // can re-render them later.
         if to_be_post_processed {
             if let Err(e) = write_lines(self.to_lines(true), &raw_path_with_source_code) {
                 eprintln!("=fil-profile= Error writing raw profiling data: {}", e);
@@ -232,10 +383,94 @@ where
                 eprintln!("=fil-profile= Error writing SVG: {}", e);
             }
         }
-        if to_be_post_processed {
-            // Don't need this file, and it'll be quite big, so delete it.
-            let _ = std::fs::remove_file(raw_path_with_source_code);
+    }
+
+    /// Lines in inferno's differential format, "stack before after", with
+    /// `self` as the before and `after` as the after.
+    fn to_differential_lines(
+        &'a self,
+        after: &'a Self,
+        to_be_post_processed: bool,
+        display_filenames: bool,
+    ) -> std::io::Result<Vec<String>> {
+        let before_lines = self
+            .lines(to_be_post_processed, display_filenames)
+            .join("\n");
+        let after_lines = after
+            .lines(to_be_post_processed, display_filenames)
+            .join("\n");
+        let mut output = vec![];
+        differential::from_readers(
+            differential::Options::default(),
+            before_lines.as_bytes(),
+            after_lines.as_bytes(),
+            &mut output,
+        )?;
+        Ok(String::from_utf8_lossy(&output)
+            .lines()
+            .map(|line| line.to_string())
+            .collect())
+    }
+
+    /// Write .prof and .svg files for a differential flamegraph comparing
+    /// `self` (before) to `after`. Frame widths are the after values; red
+    /// frames grew and blue frames shrank.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_differential_flamegraphs(
+        &'a self,
+        after: &'a Self,
+        directory_path: &Path,
+        base_filename: &str,
+        title: &str,
+        subtitle: &str,
+        count_name: &str,
+        to_be_post_processed: bool,
+    ) {
+        create_output_directory(directory_path);
+
+        let raw_path = directory_path.join(format!("{}.prof", base_filename));
+        if let Err(e) = self
+            .to_differential_lines(after, false, false)
+            .and_then(|lines| write_lines(lines, &raw_path))
+        {
+            eprintln!("=fil-profile= Error writing raw profiling data: {}", e);
+            return;
         }
+
+        let svg_path = directory_path.join(format!("{}.svg", base_filename));
+        let mut full_filenames = self.full_filenames();
+        full_filenames.extend(after.full_filenames());
+        let result = self
+            .to_differential_lines(after, to_be_post_processed, true)
+            .map_err(|e| e.into())
+            .and_then(|lines| {
+                get_flamegraph_with_options(
+                    lines,
+                    to_be_post_processed,
+                    flamegraph_options(false, title, count_name, to_be_post_processed),
+                    Some(subtitle),
+                )
+            })
+            .map(|svg| add_full_filenames_to_tooltips(svg, &full_filenames))
+            .and_then(|svg| Ok(fs::write(&svg_path, svg)?));
+        match result {
+            Ok(_) => {
+                eprintln!("=fil-profile= Wrote flamegraph to {:?}", svg_path);
+            }
+            Err(e) => {
+                eprintln!("=fil-profile= Error writing SVG: {}", e);
+            }
+        }
+    }
+}
+
+/// Make sure the output directory exists.
+fn create_output_directory(directory_path: &Path) {
+    if !directory_path.exists() {
+        fs::create_dir_all(directory_path)
+            .expect("=fil-profile= Couldn't create the output directory.");
+    } else if !directory_path.is_dir() {
+        panic!("=fil-profile= Output path must be a directory.");
     }
 }
 
@@ -273,6 +508,51 @@ pub fn get_flamegraph_with_options<I: IntoIterator<Item = String>>(
     }
 }
 
+/// Escape text the way it appears in the SVG.
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+/// Flamegraphs are rendered with short display filenames, but the tooltips
+/// should show the full filename, given a mapping from one to the other.
+fn add_full_filenames_to_tooltips(
+    svg: Vec<u8>,
+    full_filenames: &HashMap<String, String>,
+) -> Vec<u8> {
+    if full_filenames.is_empty() {
+        return svg;
+    }
+    let svg = match String::from_utf8(svg) {
+        Ok(svg) => svg,
+        Err(e) => return e.into_bytes(),
+    };
+    let full_filenames: HashMap<String, String> = full_filenames
+        .iter()
+        .map(|(display_filename, filename)| (xml_escape(display_filename), xml_escape(filename)))
+        .collect();
+    let mut result = String::with_capacity(svg.len());
+    let mut rest = svg.as_str();
+    // Tooltips look like "<title>filename:line (function) (size)</title>":
+    while let Some(index) = rest.find("<title>") {
+        let (before, title) = rest.split_at(index + "<title>".len());
+        result.push_str(before);
+        rest = title;
+        if let Some((display_filename, after)) = title.split_once(':') {
+            if let Some(filename) = full_filenames.get(display_filename) {
+                result.push_str(filename);
+                result.push(':');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result.into_bytes()
+}
+
 /// Write a flamegraph SVG to disk, given lines in summarized format.
 pub fn get_flamegraph<I: IntoIterator<Item = String>>(
     lines: I,
@@ -282,6 +562,17 @@ pub fn get_flamegraph<I: IntoIterator<Item = String>>(
     count_name: &str,
     to_be_post_processed: bool,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let options = flamegraph_options(reversed, title, count_name, to_be_post_processed);
+    get_flamegraph_with_options(lines, to_be_post_processed, options, Some(subtitle))
+}
+
+/// The flamegraph options we use for all our SVGs.
+pub fn flamegraph_options<'o>(
+    reversed: bool,
+    title: &str,
+    count_name: &str,
+    to_be_post_processed: bool,
+) -> flamegraph::Options<'o> {
     let title = format!("{}{}", title, if reversed { ", Reversed" } else { "" },);
     let mut options = flamegraph::Options::default();
     options.title = title;
@@ -300,16 +591,81 @@ pub fn get_flamegraph<I: IntoIterator<Item = String>>(
         // Can't put structured text into subtitle, so have to do a hack.
         options.subtitle = Some("__FIL-SUBTITLE-HERE__".to_string());
     }
-    get_flamegraph_with_options(lines, to_be_post_processed, options, Some(subtitle))
+    options
 }
 
 #[cfg(test)]
 mod tests {
-    use super::filter_to_useful_callstacks;
+    use super::{
+        add_full_filenames_to_tooltips, filter_to_useful_callstacks, flamegraph_options,
+        get_flamegraph_with_options, has_source_code, parse_lines,
+    };
     use im::HashMap;
     use itertools::Itertools;
     use proptest::prelude::*;
 
+    #[test]
+    fn parse_saved_lines() {
+        let prof = "a.py:1 (<module>);b.py:2 (f) 100\n\n[No Python stack] 7\n";
+        let lines = parse_lines(prof).unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                ("a.py:1 (<module>);b.py:2 (f)", 100),
+                ("[No Python stack]", 7)
+            ]
+        );
+        assert!(!has_source_code(&lines));
+        assert!(parse_lines("a.py:1 (f) 1\nnosize").is_err());
+        assert!(parse_lines("a.py:1 (f) lots").is_err());
+    }
+
+    #[test]
+    fn rerender_saved_source_lines() {
+        let source_prof =
+            "a.py:1 (<module>);\u{2800}x\u{12e4}=\u{12e4}f()\u{ff1b};a.py:5 (f);\u{2800}return\u{12e4}1 100";
+        let lines = parse_lines(source_prof).unwrap();
+        assert!(has_source_code(&lines));
+        let svg = get_flamegraph_with_options(
+            lines
+                .iter()
+                .map(|(callstack, size)| format!("{} {}", callstack, size)),
+            true,
+            flamegraph_options(false, "Re-rendered", "bytes", true),
+            Some("the subtitle"),
+        )
+        .unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.contains("Re-rendered"));
+        assert!(svg.contains("the subtitle"));
+        assert!(svg.contains("x\u{a0}=\u{a0}f();"));
+        assert!(!svg.contains('\u{2800}'));
+    }
+
+    #[test]
+    fn tooltips_get_full_filenames() {
+        let svg = get_flamegraph_with_options(
+            ["a.py:1 (<module>);numpy/core.py:5 (f);b&c.py:2 (g) 100".to_string()],
+            false,
+            flamegraph_options(false, "Title", "bytes", false),
+            None,
+        )
+        .unwrap();
+        let full_filenames = std::collections::HashMap::from([
+            (
+                "numpy/core.py".to_string(),
+                "/venv/site-packages/numpy/core.py".to_string(),
+            ),
+            ("b&c.py".to_string(), "/home/<me>/b&c.py".to_string()),
+        ]);
+        let svg = String::from_utf8(add_full_filenames_to_tooltips(svg, &full_filenames)).unwrap();
+        assert!(svg.contains("<title>/venv/site-packages/numpy/core.py:5 (f)"));
+        assert!(svg.contains("<title>/home/&lt;me&gt;/b&amp;c.py:2 (g)"));
+        assert!(svg.contains("<title>a.py:1 (&lt;module&gt;)"));
+        // The frame labels keep the shorter names:
+        assert!(svg.contains(">numpy/core.py:5 (f)</text>"));
+    }
+
     proptest! {
         #[test]
         fn filtering_of_callstacks(
@@ -320,7 +676,7 @@ mod tests {
             let total_size_99 = (99 * total_size) / 100;
             let callstacks = allocated_sizes.iter().enumerate();
             let filtered : HashMap<usize,usize>  = filter_to_useful_callstacks(callstacks, total_size).collect();
-            let filtered_size :usize = filtered.values().into_iter().sum();
+            let filtered_size :usize = filtered.values().sum();
             if filtered_size >= total_size_99  {
                 if filtered.len() > 100 {
                     // Removing any item should take us to or below 99%
@@ -0,0 +1,261 @@
+//! Leak detection: which allocations are still alive at exit?
+//!
+//! Not everything alive at exit is a leak. Imports, caches and other
+//! long-lived interpreter state get allocated early on and are never freed.
+//! So the user can set a start checkpoint, e.g. after warm-up; anything alive
+//! at that point is long-lived state, not a leak. They can also set an end
+//! checkpoint, after which new allocations are ignored, e.g. so that the work
+//! done during shutdown doesn't show up as leaked.
+//!
+//! Anonymous mmap()s can't be tracked by address the same way, since
+//! munmap() can unmap part of one, so each one instead records which
+//! checkpoints came before it was made.
+
+use std::collections::{BTreeMap, HashSet};
+
+use ahash::RandomState as ARandomState;
+
+use crate::memorytracking::{CallstackId, ProcessUid};
+use crate::overhead::hashmap_overhead;
+use crate::rangemap::RangeMap;
+
+/// Live malloc() allocations, identified by process and address.
+type Addresses = HashSet<(ProcessUid, usize), ARandomState>;
+
+#[derive(Default)]
+pub struct LeakTracker {
+    // Allocations that were alive at the start checkpoint:
+    long_lived: Addresses,
+    // Once the end checkpoint is reached, the allocations made between the
+    // two checkpoints that were still alive at the end checkpoint; only these
+    // can be leaks.
+    candidates: Option<Addresses>,
+    // Incremented at every checkpoint:
+    generation: u64,
+    // The generations that started at the start and end checkpoints, if set:
+    start_generation: Option<u64>,
+    end_generation: Option<u64>,
+    // Live anonymous mmap()s, with their callstack and the generation they
+    // were made in:
+    anon_mmaps: BTreeMap<ProcessUid, RangeMap<(CallstackId, u64)>>,
+}
+
+impl LeakTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create if `FIL_LEAKS=1` is set, otherwise None.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("FIL_LEAKS").as_deref() == Ok("1") {
+            Some(Self::new())
+        } else {
+            None
+        }
+    }
+
+    /// Estimated bytes of memory used.
+    pub fn memory_overhead(&self) -> usize {
+        let capacity = self.long_lived.capacity()
+            + self
+                .candidates
+                .as_ref()
+                .map_or(0, |candidates| candidates.capacity());
+        hashmap_overhead::<(ProcessUid, usize), ()>(capacity)
+            + self
+                .anon_mmaps
+                .values()
+                .map(|ranges| ranges.memory_overhead())
+                .sum::<usize>()
+    }
+
+    /// Forget all checkpoints, and all anonymous mmap()s.
+    pub fn reset(&mut self) {
+        self.long_lived.clear();
+        self.candidates = None;
+        self.start_generation = None;
+        self.end_generation = None;
+        self.anon_mmaps.clear();
+    }
+
+    /// Everything currently alive is long-lived state, and everything
+    /// allocated from now on may be a leak.
+    pub fn start_checkpoint(&mut self, live: impl Iterator<Item = (ProcessUid, usize)>) {
+        self.long_lived = live.collect();
+        self.candidates = None;
+        self.generation += 1;
+        self.start_generation = Some(self.generation);
+        self.end_generation = None;
+    }
+
+    /// Allocations made from now on aren't leaks.
+    pub fn end_checkpoint(&mut self, live: impl Iterator<Item = (ProcessUid, usize)>) {
+        let long_lived = &self.long_lived;
+        self.candidates = Some(live.filter(|key| !long_lived.contains(key)).collect());
+        self.generation += 1;
+        self.end_generation = Some(self.generation);
+    }
+
+    /// An allocation was freed, so its address may get reused.
+    pub fn freed(&mut self, process: ProcessUid, address: usize) {
+        let key = (process, address);
+        self.long_lived.remove(&key);
+        if let Some(candidates) = self.candidates.as_mut() {
+            candidates.remove(&key);
+        }
+    }
+
+    /// An anonymous mmap() was made.
+    pub fn add_anon_mmap(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    ) {
+        self.anon_mmaps.entry(process).or_default().add(
+            address,
+            size,
+            (callstack_id, self.generation),
+        );
+    }
+
+    /// (Part of) an anonymous mmap() was unmapped.
+    pub fn free_anon_mmap(&mut self, process: ProcessUid, address: usize, size: usize) {
+        if let Some(ranges) = self.anon_mmaps.get_mut(&process) {
+            ranges.remove(address, size);
+        }
+    }
+
+    /// The process died, so its anonymous mmap()s are gone.
+    pub fn drop_process(&mut self, process: ProcessUid) {
+        self.anon_mmaps.remove(&process);
+    }
+
+    /// The anonymous mmap()s that are still alive, as (size, callstack ID,
+    /// how it's reported).
+    pub fn classify_anon_mmaps(&self) -> impl Iterator<Item = (usize, CallstackId, LeakKind)> + '_ {
+        self.anon_mmaps.values().flat_map(move |ranges| {
+            ranges
+                .iter()
+                .map(move |(size, (callstack_id, generation))| {
+                    (size, *callstack_id, self.classify_generation(*generation))
+                })
+        })
+    }
+
+    fn classify_generation(&self, generation: u64) -> LeakKind {
+        if self
+            .start_generation
+            .is_some_and(|start| generation < start)
+        {
+            LeakKind::LongLived
+        } else if self.end_generation.is_some_and(|end| generation >= end) {
+            LeakKind::AfterEndCheckpoint
+        } else {
+            LeakKind::Leaked
+        }
+    }
+
+    /// Classify an allocation that is still alive.
+    pub fn classify(&self, process: ProcessUid, address: usize) -> LeakKind {
+        let key = (process, address);
+        if self.long_lived.contains(&key) {
+            LeakKind::LongLived
+        } else if let Some(candidates) = &self.candidates {
+            if candidates.contains(&key) {
+                LeakKind::Leaked
+            } else {
+                LeakKind::AfterEndCheckpoint
+            }
+        } else {
+            LeakKind::Leaked
+        }
+    }
+}
+
+/// How a still-alive allocation is reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeakKind {
+    /// Allocated between the checkpoints, and never freed.
+    Leaked,
+    /// Already alive at the start checkpoint.
+    LongLived,
+    /// Allocated after the end checkpoint.
+    AfterEndCheckpoint,
+}
+
+/// Totals for the different kinds of still-alive allocations.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LeakSummary {
+    pub leaked_bytes: usize,
+    pub long_lived_bytes: usize,
+    pub after_end_checkpoint_bytes: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LeakKind, LeakTracker};
+    use crate::memorytracking::PARENT_PROCESS;
+
+    #[test]
+    fn allocations_classified_by_checkpoints() {
+        let p = PARENT_PROCESS;
+        let mut leaks = LeakTracker::new();
+        // No checkpoints, everything is a leak:
+        assert_eq!(leaks.classify(p, 1), LeakKind::Leaked);
+
+        leaks.start_checkpoint([(p, 1), (p, 2)].into_iter());
+        leaks.end_checkpoint([(p, 1), (p, 2), (p, 3), (p, 4)].into_iter());
+        assert_eq!(leaks.classify(p, 1), LeakKind::LongLived);
+        assert_eq!(leaks.classify(p, 3), LeakKind::Leaked);
+        assert_eq!(leaks.classify(p, 5), LeakKind::AfterEndCheckpoint);
+
+        // Freed addresses get reused by new allocations:
+        leaks.freed(p, 1);
+        leaks.freed(p, 3);
+        assert_eq!(leaks.classify(p, 1), LeakKind::AfterEndCheckpoint);
+        assert_eq!(leaks.classify(p, 3), LeakKind::AfterEndCheckpoint);
+        assert_eq!(leaks.classify(p, 4), LeakKind::Leaked);
+
+        leaks.reset();
+        assert_eq!(leaks.classify(p, 2), LeakKind::Leaked);
+    }
+
+    #[test]
+    fn anon_mmaps_classified_by_checkpoints() {
+        let p = PARENT_PROCESS;
+        let mut leaks = LeakTracker::new();
+        let classified = |leaks: &LeakTracker| {
+            let mut classified: Vec<_> = leaks.classify_anon_mmaps().collect();
+            classified.sort_by_key(|(_, callstack_id, _)| *callstack_id);
+            classified
+        };
+        // No checkpoints, everything is a leak:
+        leaks.add_anon_mmap(p, 0, 100, 1);
+        assert_eq!(classified(&leaks), vec![(100, 1, LeakKind::Leaked)]);
+
+        leaks.start_checkpoint(std::iter::empty());
+        leaks.add_anon_mmap(p, 1000, 200, 2);
+        leaks.end_checkpoint(std::iter::empty());
+        leaks.add_anon_mmap(p, 2000, 300, 3);
+        // Unmapping the start of an mmap doesn't change how the rest of it
+        // is reported:
+        leaks.free_anon_mmap(p, 0, 50);
+        leaks.free_anon_mmap(p, 1000, 50);
+        assert_eq!(
+            classified(&leaks),
+            vec![
+                (50, 1, LeakKind::LongLived),
+                (150, 2, LeakKind::Leaked),
+                (300, 3, LeakKind::AfterEndCheckpoint)
+            ]
+        );
+
+        leaks.drop_process(p);
+        assert_eq!(classified(&leaks), vec![]);
+        leaks.add_anon_mmap(p, 0, 100, 1);
+        leaks.reset();
+        assert_eq!(classified(&leaks), vec![]);
+    }
+}
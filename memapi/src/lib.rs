@@ -1,12 +1,24 @@
 #![deny(unsafe_op_in_unsafe_fn)]
+pub mod buffering;
+pub mod cleanup;
 pub mod ffi;
 pub mod flamegraph;
+pub mod leaks;
 pub mod linecache;
 pub mod memorytracking;
+pub mod merge;
 pub mod mmap;
+pub mod native;
 pub mod oom;
+pub mod overhead;
+pub mod peaks;
+pub mod pprof;
 pub mod python;
 mod rangemap;
+pub mod sampling;
+pub mod speedscope;
+pub mod statefile;
+pub mod timeline;
 pub mod util;
 
 #[macro_use]
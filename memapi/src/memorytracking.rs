@@ -1,18 +1,26 @@
 use crate::flamegraph::filter_to_useful_callstacks;
 use crate::flamegraph::CallstackCleaner;
 use crate::flamegraph::FlamegraphCallstacks;
+use crate::leaks::{LeakKind, LeakSummary, LeakTracker};
 use crate::linecache::LineCacher;
-use crate::python::get_runpy_path;
+use crate::overhead::{
+    hashmap_overhead, per_thread_overhead, AllocatorStatsFn, MemoryOverhead, PerThreadOverhead,
+};
+use crate::peaks::{LocalPeak, TopPeaks};
+use crate::statefile::{self, CallstackNodeEntry, FunctionEntry, RunMetadata, StateFile};
+use crate::timeline::TimelineRecorder;
 
 use super::rangemap::RangeMap;
 use super::util::new_hashmap;
 use ahash::RandomState as ARandomState;
+use im::HashMap as ImHashMap;
 use im::Vector as ImVector;
 use itertools::Itertools;
 use serde::Deserialize;
 use serde::Serialize;
 use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 
 extern "C" {
@@ -24,6 +32,15 @@ pub struct FunctionId(u64);
 
 impl FunctionId {
     pub const UNKNOWN: Self = Self(u64::MAX);
+    /// Stands in for the calls of callstacks that were coalesced once the
+    /// maximum number of callstacks was reached.
+    pub const OTHER: Self = Self(u64::MAX - 1);
+    /// Stands in for a run of standard library or third-party library calls
+    /// that were collapsed into one, see cleanup::CollapseLibraryFrames.
+    pub const LIBRARY: Self = Self(u64::MAX - 2);
+    /// Stands in for repeated iterations of a recursive cycle of calls, see
+    /// cleanup::FoldRecursion.
+    pub const RECURSIVE: Self = Self(u64::MAX - 3);
 
     pub fn new(id: u64) -> Self {
         FunctionId(id)
@@ -34,11 +51,104 @@ impl FunctionId {
     }
 }
 
+lazy_static! {
+    // Used to shorten display filenames. Figured out once, so that if the
+    // program changes directory filenames don't change.
+    static ref WORKING_DIRECTORY: Option<String> = std::env::current_dir()
+        .ok()
+        .and_then(|path| path.to_str().map(|path| path.to_string()));
+}
+
+/// A shorter version of a filename to show in flamegraphs: files installed in
+/// `site-packages` are shown as e.g. `package/module.py`, and files inside the
+/// working directory are shown relative to it. IPython cells, which have
+/// filenames like `<ipython-input-12-0123456789ab>`, are shown as `Cell [12]`.
+/// Other filenames are kept as is.
+pub fn display_filename(filename: &str, working_directory: Option<&str>) -> String {
+    if let Some(cell_number) = filename
+        .strip_prefix("<ipython-input-")
+        .and_then(|rest| rest.split_once('-'))
+        .and_then(|(cell_number, _)| cell_number.parse::<u32>().ok())
+    {
+        return notebook_cell_display_filename(cell_number);
+    }
+    for packages_directory in ["/site-packages/", "/dist-packages/"] {
+        if let Some(index) = filename.rfind(packages_directory) {
+            return filename[index + packages_directory.len()..].to_string();
+        }
+    }
+    if let Some(working_directory) = working_directory {
+        let working_directory = working_directory.trim_end_matches('/');
+        if !working_directory.is_empty() {
+            if let Some(relative) = filename
+                .strip_prefix(working_directory)
+                .and_then(|rest| rest.strip_prefix('/'))
+            {
+                return relative.to_string();
+            }
+        }
+    }
+    filename.to_string()
+}
+
+fn notebook_cell_display_filename(cell_number: u32) -> String {
+    format!("Cell [{}]", cell_number)
+}
+
+/// Maps a Python function's bytecode indexes to line numbers, read from its
+/// code object when the function is first seen, so no Python APIs are needed
+/// later on.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LineTable {
+    /// The line the function starts on, used for instructions that don't have
+    /// a line of their own, e.g. those creating cells for closures.
+    first_line: u32,
+    /// (first bytecode index, line number), sorted by bytecode index, with
+    /// one entry per run of instructions on the same line.
+    starts: Vec<(i32, u32)>,
+}
+
+impl LineTable {
+    /// Create from the function's first line and (start, end, line) ranges of
+    /// bytecode indexes, as returned by Python's `code.co_lines()`. A line of
+    /// `None` means the instructions have no line number.
+    pub fn new(first_line: u32, ranges: impl IntoIterator<Item = (i32, i32, Option<u32>)>) -> Self {
+        let mut starts: Vec<(i32, u32)> = vec![];
+        for (start, _end, line) in ranges {
+            let line = line.filter(|line| *line > 0).unwrap_or(first_line);
+            if starts.last().map(|(_, last_line)| *last_line) != Some(line) {
+                starts.push((start, line));
+            }
+        }
+        Self { first_line, starts }
+    }
+
+    /// The line number for a bytecode index.
+    pub fn line_number(&self, bytecode_index: i32) -> u32 {
+        match self
+            .starts
+            .partition_point(|(start, _)| *start <= bytecode_index)
+        {
+            0 => self.first_line,
+            i => self.starts[i - 1].1,
+        }
+    }
+
+    /// Estimated bytes of memory used.
+    fn memory_overhead(&self) -> usize {
+        self.starts.capacity() * std::mem::size_of::<(i32, u32)>()
+    }
+}
+
 /// A function location in the Python source code, e.g. "example() in foo.py".
 #[derive(Clone)]
 struct FunctionLocation {
     filename: String,
     function_name: String,
+    display_filename: String,
+    /// Used to turn bytecode indexes into line numbers; empty for functions
+    /// that aren't Python code.
+    line_table: LineTable,
 }
 
 /// Basic usage: first clone, once any locks are released, convert to
@@ -54,6 +164,12 @@ pub trait WriteFunctionLocations {
 
     /// Convert to ReadFunctionLocations.
     fn to_reader(self) -> Self::Reader;
+
+    /// Estimated bytes of memory used.
+    fn memory_overhead(&self) -> usize;
+
+    /// The same call, with a bytecode index turned into a line number.
+    fn resolve_line_number(&self, call: CallSiteId) -> CallSiteId;
 }
 
 pub trait ReadFunctionLocations {
@@ -64,6 +180,15 @@ pub trait ReadFunctionLocations {
 #[derive(Clone)]
 pub struct VecFunctionLocations {
     functions: ImVector<FunctionLocation>,
+    // Filenames of notebook cells whose names don't include the cell number,
+    // e.g. ipykernel's temporary files, mapped to the cell number.
+    notebook_cells: ImHashMap<String, u32>,
+}
+
+impl Default for VecFunctionLocations {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VecFunctionLocations {
@@ -71,19 +196,50 @@ impl VecFunctionLocations {
     pub fn new() -> Self {
         Self {
             functions: ImVector::new(),
+            notebook_cells: ImHashMap::new(),
         }
     }
 
     /// Register a function, get back its id.
     pub fn add_function(&mut self, filename: String, function_name: String) -> FunctionId {
+        self.add_function_with_line_table(filename, function_name, LineTable::default())
+    }
+
+    /// Register a Python function along with the line table used to resolve
+    /// its bytecode indexes into line numbers.
+    pub fn add_function_with_line_table(
+        &mut self,
+        filename: String,
+        function_name: String,
+        line_table: LineTable,
+    ) -> FunctionId {
+        let display_filename = match self.notebook_cells.get(&filename) {
+            Some(cell_number) => notebook_cell_display_filename(*cell_number),
+            None => display_filename(&filename, WORKING_DIRECTORY.as_deref()),
+        };
         self.functions.push_back(FunctionLocation {
+            display_filename,
             filename,
             function_name,
+            line_table,
         });
         // If we ever have 2 ** 32 or more functions in our program, this will
         // break. Seems unlikely, even with long running workers.
         FunctionId((self.functions.len() - 1) as u64)
     }
+
+    /// Record that the given filename is the code of a notebook cell, so it
+    /// is displayed as e.g. `Cell [12]`. The same code can be re-run in a
+    /// later cell, so functions we already know about are updated too.
+    pub fn set_notebook_cell(&mut self, filename: &str, cell_number: u32) {
+        self.notebook_cells
+            .insert(filename.to_string(), cell_number);
+        for location in self.functions.iter_mut() {
+            if location.filename == filename {
+                location.display_filename = notebook_cell_display_filename(cell_number);
+            }
+        }
+    }
 }
 
 impl ReadFunctionLocations for VecFunctionLocations {
@@ -92,12 +248,20 @@ impl ReadFunctionLocations for VecFunctionLocations {
         if id == FunctionId::UNKNOWN {
             return ("UNKNOWN", "UNKNOWN", "UNKNOWN DUE TO BUG");
         }
+        if id == FunctionId::OTHER {
+            return ("[other]", "[other]", "[other]");
+        }
+        if id == FunctionId::LIBRARY {
+            return ("[library]", "[library]", "[library]");
+        }
+        if id == FunctionId::RECURSIVE {
+            return ("(recursive)", "(recursive)", "(recursive)");
+        }
         let location = &self.functions[id.0 as usize];
         (
             &location.function_name,
             &location.filename,
-            // TODO on Jupyter you might want to make display filename different...
-            &location.filename,
+            &location.display_filename,
         )
     }
 }
@@ -108,15 +272,55 @@ impl WriteFunctionLocations for VecFunctionLocations {
     fn cheap_clone(&self) -> Self {
         Self {
             functions: self.functions.clone(),
+            notebook_cells: self.notebook_cells.clone(),
         }
     }
 
     fn to_reader(self) -> Self::Reader {
         self
     }
+
+    fn memory_overhead(&self) -> usize {
+        self.functions.len() * std::mem::size_of::<FunctionLocation>()
+            + self
+                .functions
+                .iter()
+                .map(|location| {
+                    location.filename.capacity()
+                        + location.function_name.capacity()
+                        + location.display_filename.capacity()
+                        + location.line_table.memory_overhead()
+                })
+                .sum::<usize>()
+    }
+
+    fn resolve_line_number(&self, call: CallSiteId) -> CallSiteId {
+        let LineNumberInfo::BytecodeIndex(bytecode_index) = call.line_number else {
+            return call;
+        };
+        let line_number = match self.functions.get(call.function.0 as usize) {
+            Some(location) => location.line_table.line_number(bytecode_index),
+            None => 0,
+        };
+        CallSiteId::new(call.function, LineNumberInfo::LineNumber(line_number))
+    }
 }
 
 /// Either the line number, or the bytecode index needed to get it.
+///
+/// Getting a bytecode index is cheaper than getting a line number, so that's
+/// what gets recorded for Python code; it's only turned into a line number,
+/// with WriteFunctionLocations::resolve_line_number(), when the callstack is
+/// interned. So interned callstacks, and therefore reports, only have line
+/// numbers.
+///
+/// Resolving when interning rather than when reporting means callstacks that
+/// differ only in bytecode index, but not in line, share an ID, so they're
+/// merged before FIL_MAX_CALLSTACKS gets a chance to coalesce them, and the
+/// per-callstack statistics don't grow with the number of bytecode indexes.
+/// The cost is a binary search of the function's line table for each call
+/// the shared CallstackInterner hasn't seen before, and the
+/// LocalCallstackInterner missing once for every new bytecode index.
 #[derive(Copy, Clone, Serialize, Deserialize, Hash, Eq, PartialEq, Debug)]
 pub enum LineNumberInfo {
     LineNumber(u32),
@@ -124,12 +328,12 @@ pub enum LineNumberInfo {
 }
 
 impl LineNumberInfo {
-    fn get_line_number(&self) -> u32 {
-        if let LineNumberInfo::LineNumber(lineno) = self {
-            *lineno
-        } else {
-            debug_assert!(false, "This should never happen.");
-            0
+    /// The line number, or 0 for a bytecode index that was never resolved,
+    /// e.g. in a state file from an older version.
+    pub fn get_line_number(&self) -> u32 {
+        match self {
+            LineNumberInfo::LineNumber(line_number) => *line_number,
+            LineNumberInfo::BytecodeIndex(_) => 0,
         }
     }
 }
@@ -157,22 +361,41 @@ impl CallSiteId {
 #[derivative(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Callstack {
     calls: Vec<CallSiteId>,
+    // Interned IDs of the prefixes of calls: node_ids[i] is the ID of
+    // calls[..i]. Only the first few may be known, since new calls are
+    // interned lazily, and changing a call invalidates the IDs of all
+    // prefixes that include it.
+    #[derivative(Hash = "ignore", PartialEq = "ignore")]
+    #[serde(skip)]
+    node_ids: Vec<CallstackId>,
+    // If calls[node_ids.len() - 1] couldn't be interned because the interner
+    // was full, the ID it was coalesced into. This stays valid until that
+    // call or one of its parents changes.
     #[derivative(Hash = "ignore", PartialEq = "ignore")]
-    cached_callstack_id: Option<(u32, CallstackId)>, // first bit is line number
+    #[serde(skip)]
+    coalesced_id: Option<CallstackId>,
+}
+
+impl Default for Callstack {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Callstack {
     pub fn new() -> Callstack {
         Callstack {
             calls: Vec::new(),
-            cached_callstack_id: None,
+            node_ids: Vec::new(),
+            coalesced_id: None,
         }
     }
 
     pub fn from_vec(vec: Vec<CallSiteId>) -> Self {
         Self {
             calls: vec,
-            cached_callstack_id: None,
+            node_ids: Vec::new(),
+            coalesced_id: None,
         }
     }
 
@@ -180,49 +403,86 @@ impl Callstack {
         self.calls.clone()
     }
 
-    pub fn start_call(&mut self, parent_line_number: u32, callsite_id: CallSiteId) {
-        if parent_line_number != 0 {
-            if let Some(call) = self.calls.last_mut() {
-                call.line_number = LineNumberInfo::LineNumber(parent_line_number);
+    /// The calls, root first.
+    pub fn calls(&self) -> &[CallSiteId] {
+        &self.calls
+    }
+
+    /// Change the line number of the last call, if there is one. A line
+    /// number of 0 means there's no new information, so it's ignored.
+    fn set_last_line_number(&mut self, line_number: LineNumberInfo) {
+        if line_number == LineNumberInfo::LineNumber(0) {
+            return;
+        }
+        let depth = self.calls.len();
+        if let Some(call) = self.calls.last_mut() {
+            if call.line_number != line_number {
+                call.line_number = line_number;
+                if depth <= self.node_ids.len() {
+                    self.coalesced_id = None;
+                }
+                self.node_ids.truncate(depth);
             }
         }
+    }
+
+    pub fn start_call(&mut self, parent_line_number: LineNumberInfo, callsite_id: CallSiteId) {
+        self.set_last_line_number(parent_line_number);
         self.calls.push(callsite_id);
-        self.cached_callstack_id = None;
     }
 
     pub fn finish_call(&mut self) {
         self.calls.pop();
-        self.cached_callstack_id = None;
+        if self.calls.len() < self.node_ids.len() {
+            self.coalesced_id = None;
+        }
+        self.node_ids.truncate(self.calls.len() + 1);
     }
 
-    pub fn id_for_new_allocation<F>(&mut self, line_number: u32, get_callstack_id: F) -> CallstackId
+    pub fn id_for_new_allocation<F>(
+        &mut self,
+        line_number: LineNumberInfo,
+        intern: F,
+    ) -> CallstackId
     where
-        F: FnOnce(&Callstack) -> CallstackId,
+        F: FnOnce(&mut Callstack) -> CallstackId,
     {
-        // If same line number as last callstack, and we have cached callstack
-        // ID, reuse it:
-        if let Some((previous_line_number, callstack_id)) = self.cached_callstack_id {
-            if line_number == previous_line_number {
-                return callstack_id;
-            }
-        }
-
         // Set the new line number:
-        if line_number != 0 {
-            if let Some(call) = self.calls.last_mut() {
-                call.line_number = LineNumberInfo::LineNumber(line_number);
-            }
+        self.set_last_line_number(line_number);
+
+        // If nothing changed since the last time we were interned, reuse the
+        // ID:
+        if self.node_ids.len() == self.calls.len() + 1 {
+            return self.node_ids[self.calls.len()];
         }
+        if let Some(coalesced_id) = self.coalesced_id {
+            return coalesced_id;
+        }
+        intern(self)
+    }
 
-        // Calculate callstack ID, cache it, and then return it;
-        let callstack_id = get_callstack_id(self);
-        self.cached_callstack_id = Some((line_number, callstack_id));
-        callstack_id
+    /// Look up the function and filenames for each call, root first.
+    pub fn resolved_calls<'a, FL: ReadFunctionLocations>(
+        &self,
+        functions: &'a FL,
+    ) -> Vec<(CallSiteId, (&'a str, &'a str, &'a str))> {
+        self.calls
+            .iter()
+            .map(|id| {
+                (
+                    *id,
+                    functions.get_function_and_filename_and_display_filename(id.function),
+                )
+            })
+            .collect()
     }
 
+    /// Convert to text, with the shorter display filenames if
+    /// `display_filenames` is true and full filenames otherwise.
     pub fn as_string<FL: ReadFunctionLocations>(
         &self,
         to_be_post_processed: bool,
+        display_filenames: bool,
         functions: &FL,
         separator: &'static str,
         linecache: &mut LineCacher,
@@ -230,28 +490,24 @@ impl Callstack {
         if self.calls.is_empty() {
             return "[No Python stack]".to_string();
         }
-        let calls: Vec<(CallSiteId, (&str, &str, &str))> = self
-            .calls
-            .iter()
-            .map(|id| {
-                (
-                    *id,
-                    functions.get_function_and_filename_and_display_filename(id.function),
-                )
-            })
-            .collect();
-        let skip_prefix = if cfg!(feature = "fil4prod") {
-            0
-        } else {
-            // Due to implementation details we have some runpy() frames at the
-            // start; remove them.
-            runpy_prefix_length(calls.iter())
-        };
-        calls
+        self.resolved_calls(functions)
             .into_iter()
-            .skip(skip_prefix)
             .map(|(id, (function, filename, display_filename))| {
-                if to_be_post_processed {
+                let shown_filename = if display_filenames {
+                    display_filename
+                } else {
+                    filename
+                };
+                if id.function == FunctionId::OTHER
+                    || id.function == FunctionId::LIBRARY
+                    || id.function == FunctionId::RECURSIVE
+                {
+                    function.to_string()
+                } else if filename.is_empty() {
+                    // A synthetic frame, e.g. the per-source root frames
+                    // added when merging profiles:
+                    function.to_string()
+                } else if to_be_post_processed {
                     // Get Python code.
                     let code = linecache
                         .get_source_line(filename, id.line_number.get_line_number() as usize);
@@ -278,16 +534,16 @@ impl Callstack {
                     // and that whitespace doesn't get trimmed from start;
                     // we'll get rid of this in post-processing.
                     format!(
-                        "{display_filename}:{line} ({function});\u{2800}{code}",
-                        display_filename = display_filename,
+                        "{filename}:{line} ({function});\u{2800}{code}",
+                        filename = shown_filename,
                         line = id.line_number.get_line_number(),
                         function = function,
                         code = &code.trim_end(),
                     )
                 } else {
                     format!(
-                        "{display_filename}:{line} ({function})",
-                        display_filename = display_filename,
+                        "{filename}:{line} ({function})",
+                        filename = shown_filename,
                         line = id.line_number.get_line_number(),
                         function = function,
                     )
@@ -297,61 +553,327 @@ impl Callstack {
     }
 }
 
-fn runpy_prefix_length(calls: std::slice::Iter<(CallSiteId, (&str, &str, &str))>) -> usize {
-    let mut length = 0;
-    let runpy_path = get_runpy_path();
-    for (_, (_, filename, _)) in calls {
-        // On Python 3.11 it uses <frozen runpy> for some reason.
-        if *filename == runpy_path || *filename == "<frozen runpy>" {
-            length += 1;
-        } else {
-            return length;
-        }
-    }
-    0
-}
-
 pub type CallstackId = u32;
 
-/// Maps Functions to integer identifiers used in CallStacks.
+/// A node in the callstack trie: its parent's callstack, plus one more call.
+#[derive(Clone, Copy)]
+struct CallstackNode {
+    parent: CallstackId,
+    call: CallSiteId,
+}
+
+/// Maps Callstacks to integer identifiers.
+///
+/// Callstacks are stored as a trie, so every prefix of an interned callstack
+/// is also interned, and shares its storage with all the other callstacks it
+/// is a prefix of. The empty callstack is the root.
+///
+/// Optionally the number of callstacks can be capped, to bound memory usage.
+/// Once the cap is reached, new callstacks are coalesced: everything past
+/// their deepest interned prefix is replaced with a single `[other]` call.
 pub struct CallstackInterner {
-    max_id: CallstackId,
-    callstack_to_id: HashMap<Callstack, u32, ARandomState>,
+    // Map CallstackId -> node, or None for the root:
+    nodes: ImVector<Option<CallstackNode>>,
+    node_to_id: HashMap<(CallstackId, CallSiteId), CallstackId, ARandomState>,
+    max_callstacks: Option<usize>,
+}
+
+impl Default for CallstackInterner {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+/// The call that replaces the rest of a coalesced callstack.
+const OTHER_CALL: CallSiteId = CallSiteId {
+    function: FunctionId::OTHER,
+    line_number: LineNumberInfo::LineNumber(0),
+};
+
 impl CallstackInterner {
     pub fn new() -> Self {
         CallstackInterner {
-            max_id: 0,
-            callstack_to_id: new_hashmap(),
+            nodes: ImVector::new(),
+            node_to_id: new_hashmap(),
+            max_callstacks: None,
         }
     }
 
-    /// Add a (possibly) new Function, returning its ID.
-    pub fn get_or_insert_id<F: FnOnce()>(
+    /// The maximum number of callstacks from `FIL_MAX_CALLSTACKS`, or None if
+    /// unlimited.
+    pub fn max_callstacks_from_env() -> Option<usize> {
+        std::env::var("FIL_MAX_CALLSTACKS")
+            .ok()?
+            .parse()
+            .ok()
+            .filter(|max_callstacks| *max_callstacks > 0)
+    }
+
+    /// Coalesce new callstacks once this many have been interned. Each
+    /// interned callstack can get an additional `[other]` child, so the total
+    /// number of IDs is at most twice this.
+    pub fn set_max_callstacks(&mut self, max_callstacks: usize) {
+        self.max_callstacks = Some(max_callstacks);
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_callstacks
+            .is_some_and(|max_callstacks| self.nodes.len() >= max_callstacks)
+    }
+
+    /// Whether the given ID is for a coalesced callstack.
+    pub fn is_coalesced(&self, callstack_id: CallstackId) -> bool {
+        matches!(self.nodes[callstack_id as usize], Some(node) if node.call == OTHER_CALL)
+    }
+
+    fn get_or_insert_node<F: FnMut()>(
         &mut self,
-        callstack: Cow<Callstack>,
-        call_on_new: F,
+        parent: CallstackId,
+        call: CallSiteId,
+        call_on_new: &mut F,
     ) -> CallstackId {
-        let max_id = &mut self.max_id;
-        if let Some(result) = self.callstack_to_id.get(&*callstack) {
-            *result
-        } else {
-            let new_id = *max_id;
-            *max_id += 1;
-            self.callstack_to_id.insert(callstack.into_owned(), new_id);
+        let nodes = &mut self.nodes;
+        *self.node_to_id.entry((parent, call)).or_insert_with(|| {
+            nodes.push_back(Some(CallstackNode { parent, call }));
             call_on_new();
-            new_id
+            (nodes.len() - 1) as CallstackId
+        })
+    }
+
+    /// Add a (possibly) new Callstack, returning its ID. The callstack's
+    /// cached IDs for its prefixes are used as a starting point, and then
+    /// updated. `call_on_new` is called for every newly added ID.
+    pub fn get_or_insert_id<F: FnMut()>(
+        &mut self,
+        callstack: &mut Callstack,
+        call_on_new: F,
+    ) -> CallstackId {
+        self.get_or_insert_resolved_id(callstack, |call| call, call_on_new)
+    }
+
+    /// Like get_or_insert_id(), but calls are first passed through `resolve`,
+    /// e.g. to turn bytecode indexes into line numbers. Calls that resolve to
+    /// the same thing get the same ID.
+    pub fn get_or_insert_resolved_id<R, F>(
+        &mut self,
+        callstack: &mut Callstack,
+        resolve: R,
+        mut call_on_new: F,
+    ) -> CallstackId
+    where
+        R: Fn(CallSiteId) -> CallSiteId,
+        F: FnMut(),
+    {
+        callstack.coalesced_id = None;
+        if callstack.node_ids.is_empty() {
+            if self.nodes.is_empty() {
+                self.nodes.push_back(None);
+                call_on_new();
+            }
+            callstack.node_ids.push(0);
+        }
+        for call in &callstack.calls[callstack.node_ids.len() - 1..] {
+            let parent = callstack.node_ids[callstack.node_ids.len() - 1];
+            let call = resolve(*call);
+            let id = match self.node_to_id.get(&(parent, call)) {
+                Some(id) => *id,
+                None if self.is_full() => {
+                    let id = self.get_or_insert_node(parent, OTHER_CALL, &mut call_on_new);
+                    callstack.coalesced_id = Some(id);
+                    return id;
+                }
+                None => self.get_or_insert_node(parent, call, &mut call_on_new),
+            };
+            callstack.node_ids.push(id);
+        }
+        callstack.node_ids[callstack.node_ids.len() - 1]
+    }
+
+    /// Add (possibly) new calls below an already interned callstack,
+    /// returning the ID of the result. Coalescing works the same as in
+    /// get_or_insert_id(), and coalesced callstacks don't get any more calls.
+    pub fn get_or_insert_child_id<F: FnMut()>(
+        &mut self,
+        mut parent: CallstackId,
+        calls: &[CallSiteId],
+        mut call_on_new: F,
+    ) -> CallstackId {
+        if self.is_coalesced(parent) {
+            return parent;
+        }
+        for call in calls {
+            parent = match self.node_to_id.get(&(parent, *call)) {
+                Some(id) => *id,
+                None if self.is_full() => {
+                    return self.get_or_insert_node(parent, OTHER_CALL, &mut call_on_new);
+                }
+                None => self.get_or_insert_node(parent, *call, &mut call_on_new),
+            };
+        }
+        parent
+    }
+
+    /// Estimated bytes of memory used.
+    fn memory_overhead(&self) -> usize {
+        self.nodes.len() * std::mem::size_of::<Option<CallstackNode>>()
+            + hashmap_overhead::<(CallstackId, CallSiteId), CallstackId>(self.node_to_id.capacity())
+    }
+
+    /// A cheap copy of the interned callstacks, so they can be reconstructed
+    /// without holding any locks.
+    pub fn cheap_clone(&self) -> InternedCallstacks {
+        InternedCallstacks {
+            nodes: self.nodes.clone(),
+        }
+    }
+
+    /// Reconstruct the Callstack with the given ID, by walking up the trie.
+    pub fn get_callstack(&self, callstack_id: CallstackId) -> Callstack {
+        get_callstack(&self.nodes, callstack_id)
+    }
+}
+
+fn get_callstack(
+    nodes: &ImVector<Option<CallstackNode>>,
+    mut callstack_id: CallstackId,
+) -> Callstack {
+    let mut calls = vec![];
+    while let Some(node) = nodes[callstack_id as usize] {
+        calls.push(node.call);
+        callstack_id = node.parent;
+    }
+    calls.reverse();
+    Callstack::from_vec(calls)
+}
+
+/// The callstacks interned by a CallstackInterner at some point in time, see
+/// CallstackInterner::cheap_clone().
+#[derive(Clone)]
+pub struct InternedCallstacks {
+    nodes: ImVector<Option<CallstackNode>>,
+}
+
+impl InternedCallstacks {
+    /// Reconstruct the Callstack with the given ID, by walking up the trie.
+    pub fn get_callstack(&self, callstack_id: CallstackId) -> Callstack {
+        get_callstack(&self.nodes, callstack_id)
+    }
+
+    /// The interned callstacks, indexed by CallstackId, for a state file.
+    fn to_state_file_entries(&self) -> Vec<CallstackNodeEntry> {
+        self.nodes
+            .iter()
+            .map(|node| match node {
+                None => CallstackNodeEntry::root(),
+                Some(node) => CallstackNodeEntry::new(node.parent, node.call),
+            })
+            .collect()
+    }
+}
+
+/// How many calls a LocalCallstackInterner remembers, by default.
+const MAX_LOCAL_CALLS: usize = 16 * 1024;
+
+/// A copy of the parts of a CallstackInterner that one thread has used, so
+/// callstacks it has seen before can get their IDs without locking the
+/// AllocationTracker. Interned IDs never change, so this never goes stale.
+///
+/// Every thread has one, so to bound memory usage it's emptied once it has
+/// too many calls, after which it fills up again with the calls the thread
+/// is still using.
+///
+/// Calls are remembered before their bytecode indexes are resolved, so a line
+/// that allocates at several bytecode indexes takes that many entries, and
+/// the first allocation at each one locks the AllocationTracker.
+pub struct LocalCallstackInterner {
+    node_to_id: HashMap<(CallstackId, CallSiteId), CallstackId, ARandomState>,
+    // Calls the interner coalesced because it was full, with the resulting
+    // ID:
+    coalesced: HashMap<(CallstackId, CallSiteId), CallstackId, ARandomState>,
+    max_calls: usize,
+    overhead: PerThreadOverhead,
+}
+
+impl Default for LocalCallstackInterner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalCallstackInterner {
+    pub fn new() -> Self {
+        Self::with_max_calls(MAX_LOCAL_CALLS)
+    }
+
+    /// Remember at most this many calls.
+    pub fn with_max_calls(max_calls: usize) -> Self {
+        LocalCallstackInterner {
+            node_to_id: new_hashmap(),
+            coalesced: new_hashmap(),
+            max_calls,
+            overhead: PerThreadOverhead::default(),
         }
     }
 
-    /// Get map from IDs to Callstacks.
-    fn get_reverse_map(&self) -> HashMap<CallstackId, &Callstack, ARandomState> {
-        let mut result = new_hashmap();
-        for (call_site, csid) in self.callstack_to_id.iter() {
-            result.insert(*csid, call_site);
+    /// Get the ID for a Callstack, like CallstackInterner::get_or_insert_id().
+    /// `intern` is only called if some of the calls haven't been seen by this
+    /// thread before; it should intern the callstack in the shared
+    /// CallstackInterner.
+    pub fn get_or_insert_id<F>(&mut self, callstack: &mut Callstack, intern: F) -> CallstackId
+    where
+        F: FnOnce(&mut Callstack) -> CallstackId,
+    {
+        // The root is interned before anything else, so if we know of any
+        // calls it exists:
+        if callstack.node_ids.is_empty() && !self.node_to_id.is_empty() {
+            callstack.node_ids.push(0);
+        }
+        if !callstack.node_ids.is_empty() {
+            while callstack.node_ids.len() <= callstack.calls.len() {
+                let depth = callstack.node_ids.len();
+                let key = (callstack.node_ids[depth - 1], callstack.calls[depth - 1]);
+                if let Some(id) = self.node_to_id.get(&key) {
+                    callstack.node_ids.push(*id);
+                } else if let Some(id) = self.coalesced.get(&key) {
+                    callstack.coalesced_id = Some(*id);
+                    return *id;
+                } else {
+                    break;
+                }
+            }
+            if callstack.node_ids.len() == callstack.calls.len() + 1 {
+                return callstack.node_ids[callstack.calls.len()];
+            }
+        }
+
+        // Some calls are new to this thread, so ask the shared interner, and
+        // remember what it told us:
+        let known = callstack.node_ids.len().max(1);
+        let id = intern(callstack);
+        if self.node_to_id.len() + self.coalesced.len() + callstack.node_ids.len() - known
+            >= self.max_calls
+        {
+            self.node_to_id.clear();
+            self.coalesced.clear();
         }
-        result
+        for depth in known..callstack.node_ids.len() {
+            self.node_to_id.insert(
+                (callstack.node_ids[depth - 1], callstack.calls[depth - 1]),
+                callstack.node_ids[depth],
+            );
+        }
+        if let Some(coalesced_id) = callstack.coalesced_id {
+            let depth = callstack.node_ids.len();
+            self.coalesced.insert(
+                (callstack.node_ids[depth - 1], callstack.calls[depth - 1]),
+                coalesced_id,
+            );
+        }
+        self.overhead
+            .update(hashmap_overhead::<(CallstackId, CallSiteId), CallstackId>(
+                self.node_to_id.capacity() + self.coalesced.capacity(),
+            ));
+        id
     }
 }
 
@@ -359,7 +881,7 @@ const MIB: usize = 1024 * 1024;
 const HIGH_32BIT: u32 = 1 << 31;
 
 /// A unique identifier for a process.
-#[derive(Clone, Copy, Debug, PartialEq, Ord, PartialOrd, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Ord, PartialOrd, Eq, Hash, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct ProcessUid(pub u32);
 
@@ -401,14 +923,31 @@ impl Allocation {
 }
 
 /// A CallstackCleaner that leaves the callstack unchanged.
+#[derive(Clone, Copy)]
 pub struct IdentityCleaner;
 
 impl CallstackCleaner for IdentityCleaner {
-    fn cleanup<'a>(&self, callstack: &'a Callstack) -> Cow<'a, Callstack> {
+    fn cleanup<'a>(
+        &self,
+        callstack: &'a Callstack,
+        _functions: &dyn ReadFunctionLocations,
+    ) -> Cow<'a, Callstack> {
         Cow::Borrowed(callstack)
     }
 }
 
+/// Which allocation counts to combine, see
+/// AllocationTracker::combine_allocation_counts().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocationCounts {
+    /// Live allocations at the time of peak memory usage.
+    Peak,
+    /// Currently live allocations.
+    Current,
+    /// All allocations ever made, including those since freed.
+    Total,
+}
+
 /// The main data structure tracking everything.
 pub struct AllocationTracker<FL: WriteFunctionLocations> {
     // malloc()/calloc():
@@ -427,11 +966,40 @@ pub struct AllocationTracker<FL: WriteFunctionLocations> {
     // Both malloc() and mmap():
     current_memory_usage: ImVector<usize>, // Map CallstackId -> total memory usage
     peak_memory_usage: ImVector<usize>,    // Map CallstackId -> total memory usage
+    // malloc()/calloc() only, Map CallstackId -> number of live allocations:
+    current_allocation_counts: ImVector<usize>,
+    peak_allocation_counts: ImVector<usize>,
+    // malloc()/calloc() only, Map CallstackId -> number of allocations ever:
+    total_allocation_counts: ImVector<usize>,
+    // Both malloc() and mmap(), Map CallstackId -> bytes ever allocated,
+    // including those since freed:
+    churn_memory_usage: ImVector<usize>,
     current_allocated_bytes: usize,
     peak_allocated_bytes: usize,
     // Default directory to write out data lacking other info:
     pub default_path: String,
 
+    // Optional record of total memory usage over time:
+    timeline: Option<TimelineRecorder>,
+
+    // Optionally keep the K highest local peaks, not just the global peak:
+    top_peaks: Option<TopPeaks>,
+
+    // Named copies of current_memory_usage, for diffing, and the name of the
+    // most recent one:
+    snapshots: HashMap<String, ImVector<usize>, ARandomState>,
+    last_snapshot: Option<String>,
+
+    // Optionally figure out which allocations leaked:
+    leaks: Option<LeakTracker>,
+
+    // Whether only a sample of allocations is being tracked, with scaled-up
+    // sizes:
+    sampling: bool,
+
+    // Optionally get statistics from the allocator used for our own memory:
+    allocator_stats: Option<AllocatorStatsFn>,
+
     // Allocations that somehow disappeared. Not relevant for sampling profiler.
     missing_allocated_bytes: usize,
 
@@ -447,12 +1015,156 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
             interner: CallstackInterner::new(),
             current_memory_usage: ImVector::new(),
             peak_memory_usage: ImVector::new(),
+            current_allocation_counts: ImVector::new(),
+            peak_allocation_counts: ImVector::new(),
+            total_allocation_counts: ImVector::new(),
+            churn_memory_usage: ImVector::new(),
             functions,
             current_allocated_bytes: 0,
             peak_allocated_bytes: 0,
             missing_allocated_bytes: 0,
             failed_deallocations: 0,
             default_path,
+            timeline: None,
+            top_peaks: None,
+            snapshots: new_hashmap(),
+            last_snapshot: None,
+            leaks: None,
+            sampling: false,
+            allocator_stats: None,
+        }
+    }
+
+    /// Start recording total memory usage over time.
+    pub fn enable_timeline(&mut self, timeline: TimelineRecorder) {
+        self.timeline = Some(timeline);
+    }
+
+    /// The memory usage timeline, if it's enabled.
+    pub fn get_timeline(&self) -> Option<&TimelineRecorder> {
+        self.timeline.as_ref()
+    }
+
+    /// Start keeping track of the highest local peaks.
+    pub fn enable_top_peaks(&mut self, top_peaks: TopPeaks) {
+        self.top_peaks = Some(top_peaks);
+    }
+
+    /// The highest local peaks, highest first; empty if not enabled.
+    pub fn get_top_peaks(&mut self) -> Vec<LocalPeak> {
+        self.check_if_new_peak();
+        self.top_peaks
+            .as_ref()
+            .map(|top_peaks| top_peaks.get_peaks())
+            .unwrap_or_default()
+    }
+
+    /// Start keeping track of what is needed for leak reports.
+    pub fn enable_leaks(&mut self, leaks: LeakTracker) {
+        self.leaks = Some(leaks);
+    }
+
+    /// Only a sample of allocations will be added, so frees of unknown
+    /// addresses are expected.
+    pub fn enable_sampling(&mut self) {
+        self.sampling = true;
+    }
+
+    /// Coalesce new callstacks once this many have been interned, so memory
+    /// usage is bounded even if the program has endless distinct callstacks.
+    pub fn set_max_callstacks(&mut self, max_callstacks: usize) {
+        self.interner.set_max_callstacks(max_callstacks);
+    }
+
+    /// Bytes in coalesced callstacks, either at the peak or currently.
+    pub fn get_coalesced_bytes(&self, peak: bool) -> usize {
+        let memory_usage = if peak {
+            &self.peak_memory_usage
+        } else {
+            &self.current_memory_usage
+        };
+        memory_usage
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.interner.is_coalesced(*i as CallstackId))
+            .map(|(_, bytes)| bytes)
+            .sum()
+    }
+
+    /// Include statistics from the given function when reporting memory
+    /// overhead.
+    pub fn enable_allocator_stats(&mut self, allocator_stats: AllocatorStatsFn) {
+        self.allocator_stats = Some(allocator_stats);
+    }
+
+    /// How much memory the tracker itself is using.
+    pub fn memory_overhead(&self) -> MemoryOverhead {
+        let per_callstack_vectors = [
+            &self.current_memory_usage,
+            &self.peak_memory_usage,
+            &self.current_allocation_counts,
+            &self.peak_allocation_counts,
+            &self.total_allocation_counts,
+            &self.churn_memory_usage,
+        ];
+        MemoryOverhead {
+            allocations: self
+                .current_allocations
+                .values()
+                .map(|allocations| hashmap_overhead::<usize, Allocation>(allocations.capacity()))
+                .sum(),
+            anon_mmaps: self
+                .current_anon_mmaps
+                .values()
+                .map(|ranges| ranges.memory_overhead())
+                .sum(),
+            callstacks: self.interner.memory_overhead(),
+            callstack_statistics: per_callstack_vectors
+                .iter()
+                .map(|vector| vector.len() * std::mem::size_of::<usize>())
+                .sum(),
+            functions: self.functions.memory_overhead(),
+            saved_statistics: self
+                .snapshots
+                .values()
+                .map(|snapshot| snapshot.len() * std::mem::size_of::<usize>())
+                .sum::<usize>()
+                + self
+                    .top_peaks
+                    .as_ref()
+                    .map_or(0, |top_peaks| top_peaks.memory_overhead()),
+            leak_checkpoints: self
+                .leaks
+                .as_ref()
+                .map_or(0, |leaks| leaks.memory_overhead()),
+            per_thread: per_thread_overhead(),
+            allocator: self
+                .allocator_stats
+                .and_then(|allocator_stats| allocator_stats()),
+        }
+    }
+
+    fn live_allocation_addresses(&self) -> impl Iterator<Item = (ProcessUid, usize)> + '_ {
+        self.current_allocations
+            .iter()
+            .flat_map(|(process, allocations)| {
+                allocations.keys().map(|address| (*process, *address))
+            })
+    }
+
+    /// Allocations that are currently alive are long-lived state, not leaks.
+    pub fn leak_checkpoint_start(&mut self) {
+        if let Some(mut leaks) = self.leaks.take() {
+            leaks.start_checkpoint(self.live_allocation_addresses());
+            self.leaks = Some(leaks);
+        }
+    }
+
+    /// Allocations made from now on aren't leaks.
+    pub fn leak_checkpoint_end(&mut self) {
+        if let Some(mut leaks) = self.leaks.take() {
+            leaks.end_checkpoint(self.live_allocation_addresses());
+            self.leaks = Some(leaks);
         }
     }
 
@@ -461,12 +1173,12 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
     /// Should only be used with VecFunctionLocations, may cause deadlocks with
     /// others...
     fn print_traceback(&self, message: &'static str, callstack_id: CallstackId) {
-        let id_to_callstack = self.interner.get_reverse_map();
-        let callstack = id_to_callstack[&callstack_id];
+        let callstack = self.interner.get_callstack(callstack_id);
         eprintln!("=fil-profile= {}", message);
         eprintln!(
             "=| {}",
             callstack.as_string(
+                false,
                 false,
                 &self.functions.cheap_clone().to_reader(),
                 "\n=| ",
@@ -487,8 +1199,7 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         if let Some(allocation) = self
             .current_allocations
             .get(&process)
-            .map(|a| a.get(&address))
-            .flatten()
+            .and_then(|a| a.get(&address))
         {
             allocation.size()
         } else {
@@ -502,6 +1213,11 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
             self.peak_allocated_bytes = self.current_allocated_bytes;
             self.peak_memory_usage
                 .clone_from(&self.current_memory_usage);
+            self.peak_allocation_counts
+                .clone_from(&self.current_allocation_counts);
+        }
+        if let Some(top_peaks) = self.top_peaks.as_mut() {
+            top_peaks.maybe_new_candidate(self.current_allocated_bytes, &self.current_memory_usage);
         }
     }
 
@@ -509,6 +1225,10 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         self.current_allocated_bytes += bytes;
         let index = callstack_id as usize;
         self.current_memory_usage[index] += bytes;
+        self.churn_memory_usage[index] += bytes;
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.record(self.current_allocated_bytes);
+        }
     }
 
     fn remove_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
@@ -516,21 +1236,76 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         let index = callstack_id as usize;
         // TODO what if goes below zero? add a check I guess, in case of bugs.
         self.current_memory_usage[index] -= bytes;
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.record(self.current_allocated_bytes);
+        }
+        if let Some(top_peaks) = self.top_peaks.as_mut() {
+            top_peaks.memory_decreased(self.current_allocated_bytes);
+        }
+    }
+
+    fn allocation_started(&mut self, callstack_id: CallstackId) {
+        let index = callstack_id as usize;
+        self.current_allocation_counts[index] += 1;
+        self.total_allocation_counts[index] += 1;
+    }
+
+    fn allocation_finished(&mut self, callstack_id: CallstackId) {
+        self.current_allocation_counts[callstack_id as usize] -= 1;
     }
 
+    /// Get the ID for a Callstack, interning it if necessary.
     pub fn get_callstack_id(&mut self, callstack: &Callstack) -> CallstackId {
-        let current_memory_usage = &mut self.current_memory_usage;
-        self.interner
-            .get_or_insert_id(Cow::Borrowed(callstack), || {
-                current_memory_usage.push_back(0)
-            })
+        self.intern_callstack(&mut callstack.clone())
     }
 
-    /// Add a new allocation based off the current callstack.
-    pub fn add_allocation(
+    /// Like get_callstack_id(), but also updates the Callstack's cached IDs
+    /// so that interning it again after changes is cheap.
+    pub fn intern_callstack(&mut self, callstack: &mut Callstack) -> CallstackId {
+        self.intern_with(|interner, functions, call_on_new| {
+            interner.get_or_insert_resolved_id(
+                callstack,
+                |call| functions.resolve_line_number(call),
+                call_on_new,
+            )
+        })
+    }
+
+    /// Get the CallstackId for an interned callstack with additional calls
+    /// below it, e.g. native frames.
+    pub fn intern_child_callstack(
         &mut self,
-        process: ProcessUid,
-        address: usize,
+        parent: CallstackId,
+        calls: &[CallSiteId],
+    ) -> CallstackId {
+        self.intern_with(|interner, _, call_on_new| {
+            interner.get_or_insert_child_id(parent, calls, call_on_new)
+        })
+    }
+
+    /// Run an interning function, making sure per-callstack statistics get
+    /// added for any new IDs.
+    fn intern_with<F>(&mut self, intern: F) -> CallstackId
+    where
+        F: FnOnce(&mut CallstackInterner, &FL, &mut dyn FnMut()) -> CallstackId,
+    {
+        let current_memory_usage = &mut self.current_memory_usage;
+        let current_allocation_counts = &mut self.current_allocation_counts;
+        let total_allocation_counts = &mut self.total_allocation_counts;
+        let churn_memory_usage = &mut self.churn_memory_usage;
+        intern(&mut self.interner, &self.functions, &mut || {
+            current_memory_usage.push_back(0);
+            current_allocation_counts.push_back(0);
+            total_allocation_counts.push_back(0);
+            churn_memory_usage.push_back(0);
+        })
+    }
+
+    /// Add a new allocation based off the current callstack.
+    pub fn add_allocation(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
         size: usize,
         callstack_id: CallstackId,
     ) {
@@ -542,6 +1317,9 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
             .or_default()
             .insert(address, alloc)
         {
+            if let Some(leaks) = self.leaks.as_mut() {
+                leaks.freed(process, address);
+            }
             // In production use (proposed commercial product) allocations are
             // only sampled, so missing allocations are common and not the sign
             // of an error.
@@ -555,6 +1333,7 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
                 self.missing_allocated_bytes += previous.size();
                 // Cleanup the previous allocation, since we never saw its free():
                 self.remove_memory_usage(previous.callstack_id, previous.size());
+                self.allocation_finished(previous.callstack_id);
                 if *crate::util::DEBUG_MODE {
                     self.print_traceback(
                         "The allocation from this traceback disappeared:",
@@ -571,7 +1350,8 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
                 }
             }
         }
-        self.add_memory_usage(callstack_id, compressed_size as usize);
+        self.add_memory_usage(callstack_id, compressed_size);
+        self.allocation_started(callstack_id);
     }
 
     /// Free an existing allocation, return how much was removed, if any.
@@ -585,14 +1365,18 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
             .or_default()
             .remove(&address)
         {
+            if let Some(leaks) = self.leaks.as_mut() {
+                leaks.freed(process, address);
+            }
             self.remove_memory_usage(removed.callstack_id, removed.size());
+            self.allocation_finished(removed.callstack_id);
             Some(removed.size())
         } else {
             // This allocation doesn't exist; often this will be something
-            // allocated before Fil tracking was started, but it might also be a
-            // bug.
+            // allocated before Fil tracking was started, or an allocation that
+            // wasn't sampled, but it might also be a bug.
             #[cfg(not(feature = "fil4prod"))]
-            if *crate::util::DEBUG_MODE {
+            if !self.sampling && *crate::util::DEBUG_MODE {
                 self.failed_deallocations += 1;
                 eprintln!(
                     "=fil-profile= Your program attempted to free an allocation at an address we don't know about:"
@@ -611,11 +1395,22 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         size: usize,
         callstack_id: CallstackId,
     ) {
-        self.current_anon_mmaps
-            .entry(process)
-            .or_default()
-            .add(address, size, callstack_id);
+        let replaced =
+            self.current_anon_mmaps
+                .entry(process)
+                .or_default()
+                .add(address, size, callstack_id);
+        // mmap() with MAP_FIXED can replace existing mappings:
+        if !replaced.is_empty() {
+            self.check_if_new_peak();
+            for (callstack_id, removed) in replaced {
+                self.remove_memory_usage(callstack_id, removed);
+            }
+        }
         self.add_memory_usage(callstack_id, size);
+        if let Some(leaks) = self.leaks.as_mut() {
+            leaks.add_anon_mmap(process, address, size, callstack_id);
+        }
     }
 
     pub fn free_anon_mmap(&mut self, process: ProcessUid, address: usize, size: usize) {
@@ -630,6 +1425,9 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         {
             self.remove_memory_usage(callstack_id, removed);
         }
+        if let Some(leaks) = self.leaks.as_mut() {
+            leaks.free_anon_mmap(process, address, size);
+        }
     }
 
     /// The process just died, remove all the allocations.
@@ -643,11 +1441,15 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
                 self.remove_memory_usage(callstack_id, size);
             }
         }
+        if let Some(leaks) = self.leaks.as_mut() {
+            leaks.drop_process(process);
+        }
 
         // Drop allocations, call remove_memory_usage on all entries.
         if let Some(allocations_for_process) = self.current_allocations.remove(&process) {
             for allocation in allocations_for_process.values() {
                 self.remove_memory_usage(allocation.callstack_id, allocation.size());
+                self.allocation_finished(allocation.callstack_id);
             }
         }
     }
@@ -657,8 +1459,9 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
     ///
     /// We don't return the FlamegraphCallstacks, but rather a factory, because
     /// we don't want to hold any locks while doing any potentially expensive
-    /// WriteFunctionLocations->ReadFunctionLocations conversion; by returning a
-    /// factory, that can be appropriately delayed by the caller.
+    /// WriteFunctionLocations->ReadFunctionLocations conversion, or while
+    /// reconstructing and cleaning up callstacks (cleaners may need the GIL);
+    /// by returning a factory, that can be appropriately delayed by the caller.
     pub fn combine_callstacks<CC: CallstackCleaner>(
         &mut self,
         // If false, will do the current allocations:
@@ -675,36 +1478,269 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         // development mode.
         //self.validate();
 
-        // We get a LOT of tiny allocations. To reduce overhead of creating
-        // flamegraph (which currently loads EVERYTHING into memory), just do
-        // the top 99% of allocations.
         let callstacks = if peak {
             self.check_if_new_peak();
             &self.peak_memory_usage
         } else {
             &self.current_memory_usage
         };
-        let sum = callstacks.iter().sum();
-        let id_to_callstack = self.interner.get_reverse_map();
-        let data = filter_to_useful_callstacks(callstacks.iter().enumerate(), sum)
-            .into_iter()
-            .filter_map(|(k, v)| {
-                id_to_callstack
-                    .get(&(k as CallstackId))
-                    .map(|cs| ((**cs).clone(), v))
-            })
-            .collect();
+        self.combine_memory_usage(callstacks, callstack_cleaner)
+    }
+
+    /// Like combine_callstacks(), but the values are numbers of malloc()-style
+    /// allocations rather than bytes.
+    pub fn combine_allocation_counts<CC: CallstackCleaner>(
+        &mut self,
+        counts: AllocationCounts,
+        callstack_cleaner: CC,
+    ) -> impl FnOnce() -> FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL::Reader, CC>
+    {
+        let callstacks = match counts {
+            AllocationCounts::Peak => {
+                self.check_if_new_peak();
+                &self.peak_allocation_counts
+            }
+            AllocationCounts::Current => &self.current_allocation_counts,
+            AllocationCounts::Total => &self.total_allocation_counts,
+        };
+        self.combine_memory_usage(callstacks, callstack_cleaner)
+    }
+
+    /// Everything needed to write a state file, see the statefile module.
+    ///
+    /// Like combine_callstacks(), this returns a factory so that the
+    /// expensive parts can be done after any locks are released.
+    pub fn state_file_factory(&mut self) -> impl FnOnce() -> StateFile {
+        self.check_if_new_peak();
+        let metadata = RunMetadata::for_current_process(
+            self.current_allocated_bytes,
+            self.peak_allocated_bytes,
+            self.sampling,
+        );
+        let callstacks = self.interner.cheap_clone();
+        let functions = self.functions.cheap_clone();
+        // ImVector clones are cheap:
+        let current_memory_usage = self.current_memory_usage.clone();
+        let peak_memory_usage = self.peak_memory_usage.clone();
+        let current_allocation_counts = self.current_allocation_counts.clone();
+        let peak_allocation_counts = self.peak_allocation_counts.clone();
+        let total_allocation_counts = self.total_allocation_counts.clone();
+        let churn_memory_usage = self.churn_memory_usage.clone();
+        move || {
+            let callstacks = callstacks.to_state_file_entries();
+            let functions = functions.to_reader();
+            let function_ids: BTreeSet<u64> = callstacks
+                .iter()
+                .filter(|node| node.parent.is_some())
+                .map(|node| node.function)
+                .collect();
+            let functions = function_ids
+                .into_iter()
+                .map(FunctionId::new)
+                .filter(|id| *id != FunctionId::UNKNOWN && *id != FunctionId::OTHER)
+                .map(|id| {
+                    let (function_name, filename, _) =
+                        functions.get_function_and_filename_and_display_filename(id);
+                    FunctionEntry {
+                        id: id.as_u64(),
+                        filename: filename.to_string(),
+                        function_name: function_name.to_string(),
+                    }
+                })
+                .collect();
+            StateFile {
+                metadata: Some(metadata),
+                functions,
+                callstacks,
+                current_memory_usage: statefile::to_u64s(&current_memory_usage),
+                peak_memory_usage: statefile::to_u64s(&peak_memory_usage),
+                current_allocation_counts: statefile::to_u64s(&current_allocation_counts),
+                peak_allocation_counts: statefile::to_u64s(&peak_allocation_counts),
+                total_allocation_counts: statefile::to_u64s(&total_allocation_counts),
+                churn_memory_usage: statefile::to_u64s(&churn_memory_usage),
+            }
+        }
+    }
+
+    /// Like combine_callstacks(), but for all bytes ever allocated, including
+    /// those since freed, i.e. allocation churn.
+    pub fn combine_churn_callstacks<CC: CallstackCleaner>(
+        &self,
+        callstack_cleaner: CC,
+    ) -> impl FnOnce() -> FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL::Reader, CC>
+    {
+        self.combine_memory_usage(&self.churn_memory_usage, callstack_cleaner)
+    }
+
+    /// Total bytes ever allocated, including those since freed.
+    pub fn get_churn_allocated_bytes(&self) -> usize {
+        self.churn_memory_usage.iter().sum()
+    }
+
+    /// Like combine_callstacks(), but for one of the top local peaks.
+    pub fn combine_callstacks_for_local_peak<CC: CallstackCleaner>(
+        &self,
+        peak: &LocalPeak,
+        callstack_cleaner: CC,
+    ) -> impl FnOnce() -> FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL::Reader, CC>
+    {
+        self.combine_memory_usage(&peak.memory_usage, callstack_cleaner)
+    }
+
+    fn combine_memory_usage<CC: CallstackCleaner>(
+        &self,
+        callstacks: &ImVector<usize>,
+        callstack_cleaner: CC,
+    ) -> impl FnOnce() -> FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL::Reader, CC>
+    {
+        // ImVector clones are cheap, so reconstructing and cleaning up the
+        // callstacks can be delayed until the caller has released any locks:
+        let memory_usage = callstacks.clone();
+        let interned = self.interner.cheap_clone();
         let functions_writer = self.functions.cheap_clone();
 
-        // Return a closure, so we can delay doing the ReadFunctionLocations
-        // conversion if necessary:
-        || FlamegraphCallstacks::new(data, functions_writer.to_reader(), callstack_cleaner)
+        move || {
+            let functions = functions_writer.to_reader();
+            // We get a LOT of tiny allocations. To reduce overhead of creating
+            // flamegraph (which currently loads EVERYTHING into memory), just
+            // do the top 99% of allocations.
+            let sum = memory_usage.iter().sum();
+            let data = if callstack_cleaner.merges_callstacks() {
+                // Cleaning up can turn many callstacks into one, e.g.
+                // recursive callstacks that only differ in depth, so they need
+                // to be added up before we pick the biggest ones.
+                let mut cleaned: HashMap<Callstack, usize, ARandomState> = new_hashmap();
+                for (id, size) in memory_usage.iter().enumerate() {
+                    if *size == 0 {
+                        continue;
+                    }
+                    let callstack = interned.get_callstack(id as CallstackId);
+                    let callstack = callstack_cleaner.cleanup(&callstack, &functions);
+                    *cleaned.entry(callstack.into_owned()).or_default() += size;
+                }
+                filter_to_useful_callstacks(cleaned.iter(), sum)
+                    .map(|(k, v)| (k.clone(), v))
+                    .collect()
+            } else {
+                filter_to_useful_callstacks(memory_usage.iter().enumerate(), sum)
+                    .map(|(k, v)| (interned.get_callstack(k as CallstackId), v))
+                    .collect()
+            };
+            FlamegraphCallstacks::new(data, functions, callstack_cleaner)
+        }
+    }
+
+    /// Store a copy of the current memory usage under the given name,
+    /// replacing any existing snapshot with that name. Returns the name of the
+    /// previous snapshot, if any.
+    pub fn take_snapshot(&mut self, name: &str) -> Option<String> {
+        self.snapshots
+            .insert(name.to_string(), self.current_memory_usage.clone());
+        self.last_snapshot.replace(name.to_string())
+    }
+
+    /// Compare two named snapshots, returning a factory for the before and
+    /// after FlamegraphCallstacks, suitable for a differential flamegraph.
+    /// Only callstacks whose memory usage changed are included. Returns None
+    /// if either snapshot doesn't exist.
+    #[allow(clippy::type_complexity)]
+    pub fn diff_snapshots<CC: CallstackCleaner + Clone>(
+        &self,
+        before: &str,
+        after: &str,
+        callstack_cleaner: CC,
+    ) -> Option<
+        impl FnOnce() -> (
+            FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL::Reader, CC>,
+            FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL::Reader, CC>,
+        ),
+    > {
+        let before = self.snapshots.get(before)?;
+        let after = self.snapshots.get(after)?;
+        // Callstacks created after the first snapshot won't be in it:
+        let usage = |snapshot: &ImVector<usize>, i: usize| snapshot.get(i).copied().unwrap_or(0);
+        let changes: Vec<usize> = (0..before.len().max(after.len()))
+            .map(|i| usage(before, i).abs_diff(usage(after, i)))
+            .collect();
+        let sum = changes.iter().sum();
+        let mut before_data = new_hashmap();
+        let mut after_data = new_hashmap();
+        for (i, _) in filter_to_useful_callstacks(changes.iter().enumerate(), sum) {
+            let callstack = self.interner.get_callstack(i as CallstackId);
+            before_data.insert(callstack.clone(), usage(before, i));
+            after_data.insert(callstack, usage(after, i));
+        }
+        let before_functions = self.functions.cheap_clone();
+        let after_functions = self.functions.cheap_clone();
+        let before_cleaner = callstack_cleaner.clone();
+        Some(move || {
+            (
+                FlamegraphCallstacks::new(
+                    before_data,
+                    before_functions.to_reader(),
+                    before_cleaner,
+                ),
+                FlamegraphCallstacks::new(
+                    after_data,
+                    after_functions.to_reader(),
+                    callstack_cleaner,
+                ),
+            )
+        })
+    }
+
+    /// Combine the callstacks of allocations and anonymous mmap()s that
+    /// leaked, i.e. are still alive, excluding long-lived state and
+    /// allocations made after the end checkpoint. Returns None if leak
+    /// tracking isn't enabled.
+    #[allow(clippy::type_complexity)]
+    pub fn combine_leaked_callstacks<CC: CallstackCleaner>(
+        &self,
+        callstack_cleaner: CC,
+    ) -> Option<(
+        LeakSummary,
+        impl FnOnce() -> FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL::Reader, CC>,
+    )> {
+        let leaks = self.leaks.as_ref()?;
+        let mut summary = LeakSummary::default();
+        let mut leaked_memory_usage: ImVector<usize> =
+            std::iter::repeat_n(0, self.current_memory_usage.len()).collect();
+        let allocations = self
+            .current_allocations
+            .iter()
+            .flat_map(|(process, allocations)| {
+                allocations.iter().map(|(address, allocation)| {
+                    (
+                        allocation.size(),
+                        allocation.callstack_id,
+                        leaks.classify(*process, *address),
+                    )
+                })
+            });
+        for (size, callstack_id, kind) in allocations.chain(leaks.classify_anon_mmaps()) {
+            match kind {
+                LeakKind::Leaked => {
+                    summary.leaked_bytes += size;
+                    leaked_memory_usage[callstack_id as usize] += size;
+                }
+                LeakKind::LongLived => summary.long_lived_bytes += size,
+                LeakKind::AfterEndCheckpoint => summary.after_end_checkpoint_bytes += size,
+            }
+        }
+        Some((
+            summary,
+            self.combine_memory_usage(&leaked_memory_usage, callstack_cleaner),
+        ))
     }
 
     /// Clear memory we won't be needing anymore, since we're going to exit out.
     pub fn oom_break_glass(&mut self) {
         self.current_allocations.clear();
         self.peak_memory_usage.clear();
+        self.peak_allocation_counts.clear();
+        self.top_peaks = None;
+        self.snapshots.clear();
+        self.leaks = None;
     }
 
     /// Validate internal state is in a good state. This won't pass until
@@ -730,6 +1766,14 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         );
         assert!(self.current_memory_usage.iter().sum::<usize>() == self.current_allocated_bytes);
         assert!(self.peak_memory_usage.iter().sum::<usize>() == self.peak_allocated_bytes);
+        assert!(
+            self.current_allocation_counts.iter().sum::<usize>()
+                == self
+                    .current_allocations
+                    .values()
+                    .map(|allocs| allocs.len())
+                    .sum::<usize>()
+        );
     }
 
     /// Warn of untracked allocations; only relevant if you are profiling _all_
@@ -743,6 +1787,14 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         if self.missing_allocated_bytes > 0 {
             eprintln!("=fil-profile= WARNING: {:.2}% ({} bytes) of tracked memory somehow disappeared. If this is a small percentage you can just ignore this warning, since the missing allocations won't impact the profiling results. If the % is high, please run `export FIL_DEBUG=1` to get more output', re-run Fil on your script, and then file a bug report at https://github.com/pythonspeed/filprofiler/issues/new", self.missing_allocated_bytes as f64 * 100.0 / allocated_bytes as f64, self.missing_allocated_bytes);
         }
+        let coalesced_bytes = self.get_coalesced_bytes(peak);
+        if coalesced_bytes > 0 {
+            eprintln!("=fil-profile= The maximum number of callstacks was reached, so {:.2}% ({} bytes) of tracked memory is in callstacks that were coalesced, shown as \"[other]\". Totals are still exact.", coalesced_bytes as f64 * 100.0 / allocated_bytes as f64, coalesced_bytes);
+        }
+        if self.sampling {
+            eprintln!("=fil-profile= Only a sample of allocations was tracked, so memory usage is an estimate; allocation counts are of sampled allocations only.");
+        }
+        self.memory_overhead().print();
         if self.failed_deallocations > 0 {
             eprintln!("=fil-profile= WARNING: Encountered {} deallocations of untracked allocations. A certain number are expected in normal operation, of allocations created before Fil started tracking, and even more if you're using the Fil API to turn tracking on and off.", self.failed_deallocations);
         }
@@ -757,14 +1809,36 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
             *i = 0;
         }
         self.peak_memory_usage = ImVector::new();
+        for i in self.current_allocation_counts.iter_mut() {
+            *i = 0;
+        }
+        for i in self.total_allocation_counts.iter_mut() {
+            *i = 0;
+        }
+        for i in self.churn_memory_usage.iter_mut() {
+            *i = 0;
+        }
+        self.peak_allocation_counts = ImVector::new();
         self.current_allocated_bytes = 0;
         self.peak_allocated_bytes = 0;
         self.default_path = default_path;
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.reset();
+        }
+        if let Some(top_peaks) = self.top_peaks.as_mut() {
+            top_peaks.reset();
+        }
+        self.snapshots.clear();
+        self.last_snapshot = None;
+        if let Some(leaks) = self.leaks.as_mut() {
+            leaks.reset();
+        }
         self.validate();
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_range_loop)]
 mod tests {
     use crate::memorytracking::{
         IdentityCleaner, ProcessUid, ReadFunctionLocations, WriteFunctionLocations, PARENT_PROCESS,
@@ -772,12 +1846,20 @@ mod tests {
 
     use super::LineNumberInfo::LineNumber;
     use super::{
-        Allocation, AllocationTracker, CallSiteId, Callstack, CallstackInterner, FunctionId,
-        VecFunctionLocations, HIGH_32BIT, MIB,
+        display_filename, Allocation, AllocationCounts, AllocationTracker, CallSiteId, Callstack,
+        CallstackInterner, FunctionId, LineNumberInfo, LineTable, LocalCallstackInterner,
+        VecFunctionLocations, HIGH_32BIT, MIB, OTHER_CALL,
     };
+    use crate::cleanup::FoldRecursion;
+    use crate::leaks::{LeakSummary, LeakTracker};
+    use crate::overhead::AllocatorStats;
+    use crate::peaks::TopPeaks;
+    use crate::python::get_line_table;
+    use crate::timeline::TimelineRecorder;
     use proptest::prelude::*;
-    use std::borrow::Cow;
-    use std::collections::HashMap;
+    use pyo3::types::{PyAnyMethods, PyDict, PyDictMethods};
+    use pyo3::Python;
+    use std::time::Duration;
 
     fn new_tracker() -> AllocationTracker<VecFunctionLocations> {
         AllocationTracker::new(".".to_string(), VecFunctionLocations::new())
@@ -795,19 +1877,15 @@ mod tests {
         // loss of resolution.
         #[test]
         fn large_allocation(size in (HIGH_32BIT as usize)..(1 << 50)) {
-            let allocation = Allocation::new(0, size as usize);
+            let allocation = Allocation::new(0, size);
             let result_size = allocation.size();
-            let diff = if size < result_size {
-                result_size - size
-            } else {
-                size - result_size
-            };
+            let diff = result_size.abs_diff(size);
             prop_assert!(diff <= MIB / 2)
         }
 
         // Test for https://github.com/pythonspeed/filprofiler/issues/66
         #[test]
-        fn correct_allocation_size_tracked(size in (1 as usize)..(1<< 50)) {
+        fn correct_allocation_size_tracked(size in 1_usize..(1<< 50)) {
             let mut tracker = new_tracker();
             let cs_id = tracker.get_callstack_id(&Callstack::new());
             tracker.add_allocation(PARENT_PROCESS, 0, size, cs_id);
@@ -828,19 +1906,20 @@ mod tests {
         #[test]
         fn current_allocated_matches_sum_of_allocations(
             // Allocated bytes. Will use index as the memory address.
-            allocated_sizes in prop::collection::vec((0..2 as u32, 1..100 as usize), 10..20),
+            allocated_sizes in prop::collection::vec((0..2_u32, 1..100_usize), 10..20),
             // Allocations to free.
-            free_indices in prop::collection::btree_set(0..10 as usize, 1..5)
+            free_indices in prop::collection::btree_set(0..10_usize, 1..5)
         ) {
             let mut tracker = new_tracker();
-            let mut expected_memory_usage = im::vector![];
+            // The empty callstack is ID 0:
+            let mut expected_memory_usage = im::vector![0];
             for i in 0..allocated_sizes.len() {
                 let (process, allocation_size) = *allocated_sizes.get(i).unwrap();
                 let process = ProcessUid(process);
                 let mut cs = Callstack::new();
-                cs.start_call(0, CallSiteId::new(FunctionId::new(i as u64), LineNumber(0)));
+                cs.start_call(LineNumber(0), CallSiteId::new(FunctionId::new(i as u64), LineNumber(0)));
                 let cs_id = tracker.get_callstack_id(&cs);
-                tracker.add_allocation(process, i as usize, allocation_size, cs_id);
+                tracker.add_allocation(process, i, allocation_size, cs_id);
                 expected_memory_usage.push_back(allocation_size);
             }
             let mut expected_sum = allocated_sizes.iter().map(|t| t.1).sum();
@@ -853,7 +1932,7 @@ mod tests {
                 expected_sum -= expected_removed;
                 let removed = tracker.free_allocation(process, *i);
                 prop_assert_eq!(removed, Some(*expected_removed));
-                expected_memory_usage[*i] -= expected_removed;
+                expected_memory_usage[*i + 1] -= expected_removed;
                 prop_assert_eq!(tracker.current_allocated_bytes, expected_sum);
                 prop_assert_eq!(&tracker.current_memory_usage, &expected_memory_usage);
             }
@@ -865,21 +1944,22 @@ mod tests {
         #[test]
         fn current_allocated_anon_maps_matches_sum_of_allocations(
             // Allocated bytes. Will use index as the memory address.
-            allocated_sizes in prop::collection::vec((0..2 as u32, 1..100 as usize), 10..20),
+            allocated_sizes in prop::collection::vec((0..2_u32, 1..100_usize), 10..20),
             // Allocations to free.
-            free_indices in prop::collection::btree_set(0..10 as usize, 1..5)
+            free_indices in prop::collection::btree_set(0..10_usize, 1..5)
         ) {
             let mut tracker = new_tracker();
-            let mut expected_memory_usage = im::vector![];
+            // The empty callstack is ID 0:
+            let mut expected_memory_usage = im::vector![0];
             // Make sure addresses don't overlap:
             let addresses : Vec<usize> = (0..allocated_sizes.len()).map(|i| i * 10000).collect();
             for i in 0..allocated_sizes.len() {
                 let (process, allocation_size) = *allocated_sizes.get(i).unwrap();
                 let process = ProcessUid(process);
                 let mut cs = Callstack::new();
-                cs.start_call(0, CallSiteId::new(FunctionId::new(i as u64),  LineNumber(0)));
+                cs.start_call(LineNumber(0), CallSiteId::new(FunctionId::new(i as u64),  LineNumber(0)));
                 let csid = tracker.get_callstack_id(&cs);
-                tracker.add_anon_mmap(process, addresses[i] as usize, allocation_size, csid);
+                tracker.add_anon_mmap(process, addresses[i], allocation_size, csid);
                 expected_memory_usage.push_back(allocation_size);
             }
             let mut expected_sum = allocated_sizes.iter().map(|t|t.1).sum();
@@ -891,7 +1971,7 @@ mod tests {
                 let process = ProcessUid(process);
                 expected_sum -= allocation_size;
                 tracker.free_anon_mmap(process, addresses[*i], allocation_size);
-                expected_memory_usage[*i] -= allocation_size;
+                expected_memory_usage[*i + 1] -= allocation_size;
                 prop_assert_eq!(tracker.current_allocated_bytes, expected_sum);
                 prop_assert_eq!(&tracker.current_memory_usage, &expected_memory_usage);
             }
@@ -903,8 +1983,8 @@ mod tests {
         #[test]
         fn drop_process_removes_that_process_allocations_and_mmaps(
             // Allocated bytes. Will use index as the memory address.
-            allocated_sizes in prop::collection::vec((0..2 as u32, 1..100 as usize), 10..20),
-            allocated_mmaps in prop::collection::vec((0..2 as u32, 1..100 as usize), 10..20),
+            allocated_sizes in prop::collection::vec((0..2_u32, 1..100_usize), 10..20),
+            allocated_mmaps in prop::collection::vec((0..2_u32, 1..100_usize), 10..20),
         ) {
             let mut tracker = new_tracker();
             let mut expected_memory_usage : usize = 0;
@@ -914,18 +1994,18 @@ mod tests {
                 let (process, allocation_size) = *allocated_sizes.get(i).unwrap();
                 let process = ProcessUid(process);
                 let mut cs = Callstack::new();
-                cs.start_call(0, CallSiteId::new(FunctionId::new(i as u64), LineNumber(0)));
+                cs.start_call(LineNumber(0), CallSiteId::new(FunctionId::new(i as u64), LineNumber(0)));
                 let cs_id = tracker.get_callstack_id(&cs);
-                tracker.add_allocation(process, i as usize, allocation_size, cs_id);
+                tracker.add_allocation(process, i, allocation_size, cs_id);
                 expected_memory_usage += allocation_size;
             }
             for i in 0..allocated_mmaps.len() {
                 let (process, allocation_size) = *allocated_mmaps.get(i).unwrap();
                 let process = ProcessUid(process);
                 let mut cs = Callstack::new();
-                cs.start_call(0, CallSiteId::new(FunctionId::new(i as u64), LineNumber(0)));
+                cs.start_call(LineNumber(0), CallSiteId::new(FunctionId::new(i as u64), LineNumber(0)));
                 let csid = tracker.get_callstack_id(&cs);
-                tracker.add_anon_mmap(process, mmap_addresses[i] as usize, allocation_size, csid);
+                tracker.add_anon_mmap(process, mmap_addresses[i], allocation_size, csid);
                 expected_memory_usage += allocation_size;
             }
             prop_assert_eq!(tracker.current_allocated_bytes, expected_memory_usage);
@@ -960,18 +2040,18 @@ mod tests {
         let id1 = CallSiteId::new(fid1, LineNumber(2));
         let id2 = CallSiteId::new(fid3, LineNumber(45));
         let id3 = CallSiteId::new(fid5, LineNumber(6));
-        cs1.start_call(123, id1);
+        cs1.start_call(LineNumber(123), id1);
         assert_eq!(cs1.calls, vec![id1]);
 
         // Parent line number does nothing if it's 0:
-        cs1.start_call(0, id2);
+        cs1.start_call(LineNumber(0), id2);
         assert_eq!(cs1.calls, vec![id1, id2]);
 
         // Parent line number overrides previous level if it's non-0:
         let mut cs2 = Callstack::new();
-        cs2.start_call(0, id1);
-        cs2.start_call(10, id2);
-        cs2.start_call(12, id3);
+        cs2.start_call(LineNumber(0), id1);
+        cs2.start_call(LineNumber(10), id2);
+        cs2.start_call(LineNumber(12), id3);
         assert_eq!(
             cs2.calls,
             vec![
@@ -988,33 +2068,34 @@ mod tests {
         let fid3 = FunctionId::new(3u64);
 
         let mut cs1 = Callstack::new();
-        cs1.start_call(0, CallSiteId::new(fid1, LineNumber(2)));
+        cs1.start_call(LineNumber(0), CallSiteId::new(fid1, LineNumber(2)));
         let cs1b = cs1.clone();
         let mut cs2 = Callstack::new();
-        cs2.start_call(0, CallSiteId::new(fid3, LineNumber(4)));
+        cs2.start_call(LineNumber(0), CallSiteId::new(fid3, LineNumber(4)));
         let cs3 = Callstack::new();
         let cs3b = Callstack::new();
 
         let mut interner = CallstackInterner::new();
 
         let mut new = false;
-        let id1 = interner.get_or_insert_id(Cow::Borrowed(&cs1), || new = true);
+        let id1 = interner.get_or_insert_id(&mut cs1.clone(), || new = true);
         assert!(new);
 
         new = false;
-        let id1b = interner.get_or_insert_id(Cow::Borrowed(&cs1b), || new = true);
+        let id1b = interner.get_or_insert_id(&mut cs1b.clone(), || new = true);
         assert!(!new);
 
         new = false;
-        let id2 = interner.get_or_insert_id(Cow::Borrowed(&cs2), || new = true);
+        let id2 = interner.get_or_insert_id(&mut cs2.clone(), || new = true);
         assert!(new);
 
+        // The empty callstack is the root, so it was already added:
         new = false;
-        let id3 = interner.get_or_insert_id(Cow::Borrowed(&cs3), || new = true);
-        assert!(new);
+        let id3 = interner.get_or_insert_id(&mut cs3.clone(), || new = true);
+        assert!(!new);
 
         new = false;
-        let id3b = interner.get_or_insert_id(Cow::Borrowed(&cs3b), || new = true);
+        let id3b = interner.get_or_insert_id(&mut cs3b.clone(), || new = true);
         assert!(!new);
 
         assert_eq!(id1, id1b);
@@ -1022,11 +2103,230 @@ mod tests {
         assert_ne!(id1, id3);
         assert_ne!(id2, id3);
         assert_eq!(id3, id3b);
-        let mut expected = HashMap::default();
-        expected.insert(id1, &cs1);
-        expected.insert(id2, &cs2);
-        expected.insert(id3, &cs3);
-        assert_eq!(interner.get_reverse_map(), expected);
+        assert_eq!(interner.get_callstack(id1), cs1);
+        assert_eq!(interner.get_callstack(id2), cs2);
+        assert_eq!(interner.get_callstack(id3), cs3);
+    }
+
+    #[test]
+    fn callstackinterner_shares_prefixes() {
+        let fid1 = FunctionId::new(1u64);
+        let mut interner = CallstackInterner::new();
+        let mut new_ids = 0;
+
+        // A deep recursive callstack adds one ID per level:
+        let mut deep = Callstack::new();
+        for i in 0..100 {
+            deep.start_call(LineNumber(i), CallSiteId::new(fid1, LineNumber(1)));
+        }
+        let deep_id = interner.get_or_insert_id(&mut deep.clone(), || new_ids += 1);
+        assert_eq!(new_ids, 101);
+        assert_eq!(interner.get_callstack(deep_id), deep);
+
+        // Calling some other function from the middle of it only adds the new
+        // frames:
+        let mut other = Callstack::from_vec(deep.to_vec()[..50].to_vec());
+        other.start_call(LineNumber(2), CallSiteId::new(fid1, LineNumber(3)));
+        let other_id = interner.get_or_insert_id(&mut other.clone(), || new_ids += 1);
+        assert_eq!(new_ids, 103);
+        assert_eq!(interner.get_callstack(other_id), other);
+
+        // The cached IDs are used, so only the changed frames are looked up:
+        let mut callstack = deep.clone();
+        interner.get_or_insert_id(&mut callstack, || ());
+        assert_eq!(callstack.node_ids.len(), 101);
+        callstack.finish_call();
+        assert_eq!(callstack.node_ids.len(), 100);
+        callstack.start_call(LineNumber(5), CallSiteId::new(fid1, LineNumber(1)));
+        assert_eq!(callstack.node_ids.len(), 99);
+        let id = interner.get_or_insert_id(&mut callstack, || new_ids += 1);
+        assert_eq!(new_ids, 105);
+        assert_eq!(callstack.node_ids.len(), 101);
+        assert_eq!(interner.get_callstack(id), callstack);
+    }
+
+    #[test]
+    fn callstackinterner_adds_children() {
+        let fid1 = FunctionId::new(1u64);
+        let fid2 = FunctionId::new(2u64);
+        let mut interner = CallstackInterner::new();
+        let mut new_ids = 0;
+        let mut cs = Callstack::new();
+        cs.start_call(LineNumber(0), CallSiteId::new(fid1, LineNumber(1)));
+        let parent_id = interner.get_or_insert_id(&mut cs.clone(), || new_ids += 1);
+        assert_eq!(new_ids, 2);
+
+        let native = [
+            CallSiteId::new(fid2, LineNumber(10)),
+            CallSiteId::new(fid2, LineNumber(20)),
+        ];
+        let child_id = interner.get_or_insert_child_id(parent_id, &native, || new_ids += 1);
+        assert_eq!(new_ids, 4);
+        let mut expected = cs.clone();
+        expected.start_call(LineNumber(0), native[0]);
+        expected.start_call(LineNumber(0), native[1]);
+        assert_eq!(interner.get_callstack(child_id), expected);
+        // Same as interning the whole thing:
+        assert_eq!(
+            interner.get_or_insert_id(&mut expected, || new_ids += 1),
+            child_id
+        );
+        assert_eq!(
+            interner.get_or_insert_child_id(parent_id, &[], || new_ids += 1),
+            parent_id
+        );
+        assert_eq!(new_ids, 4);
+
+        // Once full, children are coalesced:
+        interner.set_max_callstacks(4);
+        let other_id = interner.get_or_insert_child_id(parent_id, &native[1..], || new_ids += 1);
+        assert!(interner.is_coalesced(other_id));
+        assert_eq!(
+            interner.get_or_insert_child_id(other_id, &native, || new_ids += 1),
+            other_id
+        );
+        assert_eq!(new_ids, 5);
+    }
+
+    #[test]
+    fn callstackinterner_coalesces_once_full() {
+        let fid1 = FunctionId::new(1u64);
+        let fid2 = FunctionId::new(2u64);
+        let mut interner = CallstackInterner::new();
+        interner.set_max_callstacks(3);
+        let mut new_ids = 0;
+
+        // Root, plus two levels:
+        let mut cs = Callstack::new();
+        cs.start_call(LineNumber(0), CallSiteId::new(fid1, LineNumber(1)));
+        cs.start_call(LineNumber(0), CallSiteId::new(fid1, LineNumber(2)));
+        let full_id = interner.get_or_insert_id(&mut cs.clone(), || new_ids += 1);
+        assert_eq!(new_ids, 3);
+        assert!(!interner.is_coalesced(full_id));
+
+        // Deeper callstacks get coalesced into the deepest interned prefix:
+        let mut deeper = cs.clone();
+        deeper.start_call(LineNumber(0), CallSiteId::new(fid2, LineNumber(3)));
+        deeper.start_call(LineNumber(0), CallSiteId::new(fid2, LineNumber(4)));
+        let deeper_id = interner.get_or_insert_id(&mut deeper, || new_ids += 1);
+        assert_eq!(new_ids, 4);
+        assert!(interner.is_coalesced(deeper_id));
+        let mut expected = cs.clone();
+        expected.start_call(LineNumber(0), OTHER_CALL);
+        assert_eq!(interner.get_callstack(deeper_id), expected);
+
+        // The coalesced ID is cached until the first uninterned call changes:
+        assert_eq!(
+            deeper.id_for_new_allocation(LineNumber(7), |_| unreachable!()),
+            deeper_id
+        );
+        deeper.finish_call();
+        assert_eq!(
+            deeper.id_for_new_allocation(LineNumber(0), |_| unreachable!()),
+            deeper_id
+        );
+        deeper.finish_call();
+        assert_eq!(
+            deeper.id_for_new_allocation(LineNumber(2), |cs| interner
+                .get_or_insert_id(cs, || new_ids += 1)),
+            full_id
+        );
+
+        // Different callstacks with no interned prefix share a top-level
+        // "[other]":
+        let mut unrelated = Callstack::new();
+        unrelated.start_call(LineNumber(0), CallSiteId::new(fid2, LineNumber(5)));
+        let unrelated_id = interner.get_or_insert_id(&mut unrelated, || new_ids += 1);
+        let mut unrelated2 = Callstack::new();
+        unrelated2.start_call(LineNumber(0), CallSiteId::new(fid2, LineNumber(6)));
+        assert_eq!(
+            interner.get_or_insert_id(&mut unrelated2, || new_ids += 1),
+            unrelated_id
+        );
+        assert_eq!(new_ids, 5);
+        assert_eq!(
+            interner.get_callstack(unrelated_id),
+            Callstack::from_vec(vec![OTHER_CALL])
+        );
+    }
+
+    #[test]
+    fn local_callstackinterner_only_asks_about_new_calls() {
+        let fid1 = FunctionId::new(1u64);
+        let fid2 = FunctionId::new(2u64);
+        let mut interner = CallstackInterner::new();
+        interner.set_max_callstacks(4);
+        let mut local = LocalCallstackInterner::new();
+        let shared_calls = std::cell::Cell::new(0);
+        let mut intern = |cs: &mut Callstack| {
+            shared_calls.set(shared_calls.get() + 1);
+            interner.get_or_insert_id(cs, || ())
+        };
+
+        let mut cs = Callstack::new();
+        cs.start_call(LineNumber(0), CallSiteId::new(fid1, LineNumber(1)));
+        cs.start_call(LineNumber(0), CallSiteId::new(fid1, LineNumber(2)));
+        let id = local.get_or_insert_id(&mut cs.clone(), &mut intern);
+        assert_eq!(shared_calls.get(), 1);
+
+        // Seen before, so no need to ask the shared interner, even starting
+        // from scratch or after the last call changed back and forth:
+        assert_eq!(local.get_or_insert_id(&mut cs.clone(), &mut intern), id);
+        let mut other_line = cs.clone();
+        other_line.set_last_line_number(LineNumber(3));
+        let other_line_id = local.get_or_insert_id(&mut other_line, &mut intern);
+        assert_eq!(shared_calls.get(), 2);
+        other_line.set_last_line_number(LineNumber(2));
+        assert_eq!(local.get_or_insert_id(&mut other_line, &mut intern), id);
+        other_line.set_last_line_number(LineNumber(3));
+        assert_eq!(
+            local.get_or_insert_id(&mut other_line, &mut intern),
+            other_line_id
+        );
+        assert_eq!(shared_calls.get(), 2);
+
+        // Coalesced callstacks are remembered too:
+        let mut deeper = cs.clone();
+        deeper.start_call(LineNumber(0), CallSiteId::new(fid2, LineNumber(4)));
+        let deeper_id = local.get_or_insert_id(&mut deeper.clone(), &mut intern);
+        assert_eq!(shared_calls.get(), 3);
+        assert_eq!(local.get_or_insert_id(&mut deeper, &mut intern), deeper_id);
+        assert_eq!(deeper.coalesced_id, Some(deeper_id));
+        assert_eq!(shared_calls.get(), 3);
+        assert!(interner.is_coalesced(deeper_id));
+    }
+
+    #[test]
+    fn local_callstackinterner_is_bounded() {
+        let fid = FunctionId::new(1u64);
+        let mut interner = CallstackInterner::new();
+        let mut local = LocalCallstackInterner::with_max_calls(3);
+        let shared_calls = std::cell::Cell::new(0);
+        let mut intern = |cs: &mut Callstack| {
+            shared_calls.set(shared_calls.get() + 1);
+            interner.get_or_insert_id(cs, || ())
+        };
+        let callstack = |lines: &[u32]| {
+            let mut cs = Callstack::new();
+            for line in lines {
+                cs.start_call(LineNumber(0), CallSiteId::new(fid, LineNumber(*line)));
+            }
+            cs
+        };
+
+        let cs1 = callstack(&[1, 2]);
+        let id1 = local.get_or_insert_id(&mut cs1.clone(), &mut intern);
+        assert_eq!(local.get_or_insert_id(&mut cs1.clone(), &mut intern), id1);
+        assert_eq!(shared_calls.get(), 1);
+
+        // Remembering two more calls would be too many, so cs1 is forgotten:
+        let cs2 = callstack(&[3, 4]);
+        let id2 = local.get_or_insert_id(&mut cs2.clone(), &mut intern);
+        assert_eq!(local.get_or_insert_id(&mut cs2.clone(), &mut intern), id2);
+        assert_eq!(shared_calls.get(), 2);
+        assert!(local.node_to_id.len() < 3);
+        assert_eq!(local.get_or_insert_id(&mut cs1.clone(), &mut intern), id1);
+        assert_eq!(shared_calls.get(), 3);
     }
 
     #[test]
@@ -1035,43 +2335,43 @@ mod tests {
 
         let mut cs1 = Callstack::new();
         let id0 =
-            cs1.id_for_new_allocation(0, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(LineNumber(0), |cs| interner.get_or_insert_id(cs, || ()));
         let id0b =
-            cs1.id_for_new_allocation(0, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(LineNumber(0), |cs| interner.get_or_insert_id(cs, || ()));
         assert_eq!(id0, id0b);
 
         let fid1 = FunctionId::new(1u64);
 
-        cs1.start_call(0, CallSiteId::new(fid1, LineNumber(2)));
+        cs1.start_call(LineNumber(0), CallSiteId::new(fid1, LineNumber(2)));
         let id1 =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(LineNumber(1), |cs| interner.get_or_insert_id(cs, || ()));
         let id2 =
-            cs1.id_for_new_allocation(2, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(LineNumber(2), |cs| interner.get_or_insert_id(cs, || ()));
         let id1b =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(LineNumber(1), |cs| interner.get_or_insert_id(cs, || ()));
         assert_eq!(id1, id1b);
         assert_ne!(id2, id0);
         assert_ne!(id2, id1);
 
-        cs1.start_call(3, CallSiteId::new(fid1, LineNumber(2)));
+        cs1.start_call(LineNumber(3), CallSiteId::new(fid1, LineNumber(2)));
         let id3 =
-            cs1.id_for_new_allocation(4, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(LineNumber(4), |cs| interner.get_or_insert_id(cs, || ()));
         assert_ne!(id3, id0);
         assert_ne!(id3, id1);
         assert_ne!(id3, id2);
 
         cs1.finish_call();
         let id2b =
-            cs1.id_for_new_allocation(2, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(LineNumber(2), |cs| interner.get_or_insert_id(cs, || ()));
         assert_eq!(id2, id2b);
         let id1c =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(LineNumber(1), |cs| interner.get_or_insert_id(cs, || ()));
         assert_eq!(id1, id1c);
 
         // Check for cache invalidation in start_call:
-        cs1.start_call(1, CallSiteId::new(fid1, LineNumber(1)));
+        cs1.start_call(LineNumber(1), CallSiteId::new(fid1, LineNumber(1)));
         let id4 =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(LineNumber(1), |cs| interner.get_or_insert_id(cs, || ()));
         assert_ne!(id4, id0);
         assert_ne!(id4, id1);
         assert_ne!(id4, id2);
@@ -1080,7 +2380,7 @@ mod tests {
         // Check for cache invalidation in finish_call:
         cs1.finish_call();
         let id1d =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(LineNumber(1), |cs| interner.get_or_insert_id(cs, || ()));
         assert_eq!(id1, id1d);
     }
 
@@ -1091,16 +2391,17 @@ mod tests {
 
         let mut tracker = new_tracker();
         let mut cs1 = Callstack::new();
-        cs1.start_call(0, CallSiteId::new(fid1, LineNumber(2)));
+        cs1.start_call(LineNumber(0), CallSiteId::new(fid1, LineNumber(2)));
         let mut cs2 = Callstack::new();
-        cs2.start_call(0, CallSiteId::new(fid3, LineNumber(4)));
+        cs2.start_call(LineNumber(0), CallSiteId::new(fid3, LineNumber(4)));
 
+        // ID 0 is the empty callstack, the root of all callstacks:
         let cs1_id = tracker.get_callstack_id(&cs1);
 
         tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
         tracker.check_if_new_peak();
         // Peak should now match current allocations:
-        assert_eq!(tracker.current_memory_usage, im::vector![1000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 1000]);
         assert_eq!(tracker.current_memory_usage, tracker.peak_memory_usage);
         assert_eq!(tracker.peak_allocated_bytes, 1000);
         let previous_peak = tracker.peak_memory_usage.clone();
@@ -1108,13 +2409,13 @@ mod tests {
         // Free the allocation:
         tracker.free_allocation(PARENT_PROCESS, 1);
         assert_eq!(tracker.current_allocated_bytes, 0);
-        assert_eq!(tracker.current_memory_usage, im::vector![0]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 0]);
         assert_eq!(previous_peak, tracker.peak_memory_usage);
         assert_eq!(tracker.peak_allocated_bytes, 1000);
 
         // Add allocation, still less than 1000:
         tracker.add_allocation(PARENT_PROCESS, 3, 123, cs1_id);
-        assert_eq!(tracker.current_memory_usage, im::vector![123]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123]);
         tracker.check_if_new_peak();
         assert_eq!(previous_peak, tracker.peak_memory_usage);
         assert_eq!(tracker.peak_allocated_bytes, 1000);
@@ -1123,16 +2424,16 @@ mod tests {
         let cs2_id = tracker.get_callstack_id(&cs2);
         tracker.add_allocation(PARENT_PROCESS, 2, 2000, cs2_id);
         tracker.check_if_new_peak();
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 2000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 2000]);
         assert_eq!(tracker.current_memory_usage, tracker.peak_memory_usage);
         assert_eq!(tracker.peak_allocated_bytes, 2123);
         let previous_peak = tracker.peak_memory_usage.clone();
 
         // Add anonymous mmap() that doesn't go past previous peak:
         tracker.free_allocation(PARENT_PROCESS, 2);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 0]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 0]);
         tracker.add_anon_mmap(PARENT_PROCESS, 50000, 1000, cs2_id);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 1000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 1000]);
         tracker.check_if_new_peak();
         assert_eq!(tracker.current_allocated_bytes, 1123);
         assert_eq!(tracker.peak_allocated_bytes, 2123);
@@ -1143,7 +2444,7 @@ mod tests {
 
         // Add anonymous mmap() that does go past previous peak:
         tracker.add_anon_mmap(PARENT_PROCESS, 600000, 2000, cs2_id);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 3000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 3000]);
         tracker.check_if_new_peak();
         assert_eq!(tracker.current_memory_usage, tracker.peak_memory_usage);
         assert_eq!(tracker.current_allocated_bytes, 3123);
@@ -1151,7 +2452,7 @@ mod tests {
 
         // Remove mmap():
         tracker.free_anon_mmap(PARENT_PROCESS, 50000, 1000);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 2000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 2000]);
         tracker.check_if_new_peak();
         assert_eq!(tracker.current_allocated_bytes, 2123);
         assert_eq!(tracker.peak_allocated_bytes, 3123);
@@ -1162,7 +2463,7 @@ mod tests {
 
         // Partial removal of anonmyous mmap():
         tracker.free_anon_mmap(PARENT_PROCESS, 600100, 1000);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 1000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 1000]);
         assert_eq!(tracker.current_allocated_bytes, 1123);
         assert_eq!(tracker.peak_allocated_bytes, 3123);
         assert_eq!(tracker.current_anon_mmaps[&PARENT_PROCESS].size(), 1000);
@@ -1170,6 +2471,430 @@ mod tests {
         tracker.validate();
     }
 
+    #[test]
+    fn timeline_records_total_memory_changes() {
+        let mut tracker = new_tracker();
+        tracker.enable_timeline(TimelineRecorder::new(100, 100, Duration::from_secs(1000)));
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs_id);
+        // Too small a change to record:
+        tracker.add_allocation(PARENT_PROCESS, 2, 10, cs_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 3000, 500, cs_id);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        let bytes: Vec<usize> = tracker
+            .get_timeline()
+            .unwrap()
+            .samples()
+            .map(|s| s.bytes)
+            .collect();
+        assert_eq!(bytes, vec![1000, 1510, 510]);
+
+        tracker.reset("/tmp".to_string());
+        assert_eq!(tracker.get_timeline().unwrap().samples().len(), 0);
+    }
+
+    #[test]
+    fn top_peaks_are_tracked() {
+        let mut tracker = new_tracker();
+        tracker.enable_top_peaks(TopPeaks::new(2, 100));
+        let cs1_id = tracker.get_callstack_id(&Callstack::new());
+        let mut cs2 = Callstack::new();
+        cs2.start_call(
+            LineNumber(0),
+            CallSiteId::new(FunctionId::new(1), LineNumber(2)),
+        );
+        let cs2_id = tracker.get_callstack_id(&cs2);
+
+        // First phase peaks at 1000 bytes:
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        // Second phase, peaks at 700 bytes:
+        tracker.add_allocation(PARENT_PROCESS, 2, 500, cs2_id);
+        tracker.add_allocation(PARENT_PROCESS, 3, 200, cs1_id);
+        tracker.free_allocation(PARENT_PROCESS, 2);
+        // A dip that isn't deep enough, then third phase with 750 bytes,
+        // still ongoing:
+        tracker.add_allocation(PARENT_PROCESS, 4, 550, cs2_id);
+
+        let peaks = tracker.get_top_peaks();
+        let sizes: Vec<usize> = peaks.iter().map(|p| p.allocated_bytes).collect();
+        assert_eq!(sizes, vec![1000, 750]);
+        assert_eq!(peaks[0].memory_usage, im::vector![1000, 0]);
+        assert_eq!(peaks[1].memory_usage, im::vector![200, 550]);
+        // Global peak is unaffected:
+        assert_eq!(tracker.peak_allocated_bytes, 1000);
+        assert!(peaks[0].elapsed <= peaks[1].elapsed);
+
+        tracker.reset("/tmp".to_string());
+        assert_eq!(tracker.get_top_peaks(), vec![]);
+    }
+
+    #[test]
+    fn allocation_counts_are_tracked() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(LineNumber(0), CallSiteId::new(fid, LineNumber(1)));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(LineNumber(0), CallSiteId::new(fid, LineNumber(2)));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+
+        for address in 1..=10 {
+            tracker.add_allocation(PARENT_PROCESS, address, 10, cs1_id);
+        }
+        tracker.add_allocation(PARENT_PROCESS, 100, 1000, cs2_id);
+        // Anonymous mmap()s aren't counted:
+        tracker.add_anon_mmap(PARENT_PROCESS, 5000, 5000, cs2_id);
+        for address in 1..=7 {
+            tracker.free_allocation(PARENT_PROCESS, address);
+        }
+        tracker.add_allocation(PARENT_PROCESS, 200, 1, cs2_id);
+        tracker.validate();
+
+        let counts = |tracker: &mut AllocationTracker<VecFunctionLocations>,
+                      kind: AllocationCounts| {
+            let mut lines: Vec<String> = tracker.combine_allocation_counts(kind, IdentityCleaner)()
+                .to_lines(false)
+                .collect();
+            lines.sort();
+            lines
+        };
+        assert_eq!(
+            counts(&mut tracker, AllocationCounts::Peak),
+            vec!["a:1 (af) 10", "a:2 (af) 1"]
+        );
+        assert_eq!(
+            counts(&mut tracker, AllocationCounts::Current),
+            vec!["a:1 (af) 3", "a:2 (af) 2"]
+        );
+        assert_eq!(
+            counts(&mut tracker, AllocationCounts::Total),
+            vec!["a:1 (af) 10", "a:2 (af) 2"]
+        );
+
+        tracker.reset("/tmp".to_string());
+        assert_eq!(
+            counts(&mut tracker, AllocationCounts::Total),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn churn_includes_freed_memory() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(LineNumber(0), CallSiteId::new(fid, LineNumber(1)));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(LineNumber(0), CallSiteId::new(fid, LineNumber(2)));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+
+        // A hot loop creating and throwing away temporaries:
+        for _ in 0..100 {
+            tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+            tracker.free_allocation(PARENT_PROCESS, 1);
+        }
+        tracker.add_anon_mmap(PARENT_PROCESS, 5000, 20000, cs2_id);
+        tracker.free_anon_mmap(PARENT_PROCESS, 5000, 20000);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 20000);
+        assert_eq!(tracker.get_churn_allocated_bytes(), 120000);
+
+        let mut lines: Vec<String> = tracker.combine_churn_callstacks(IdentityCleaner)()
+            .to_lines(false)
+            .collect();
+        lines.sort();
+        assert_eq!(lines, vec!["a:1 (af) 100000", "a:2 (af) 20000"]);
+
+        tracker.reset("/tmp".to_string());
+        assert_eq!(tracker.get_churn_allocated_bytes(), 0);
+    }
+
+    #[test]
+    fn recursion_is_folded_before_picking_callstacks() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let main = tracker
+            .functions
+            .add_function("a.py".to_string(), "main".to_string());
+        let walk = tracker
+            .functions
+            .add_function("a.py".to_string(), "walk".to_string());
+        // Walk binary trees 8 to 15 levels deep, always going left at the
+        // top, giving 16320 different callstacks, more than the 10,000 that
+        // get rendered:
+        let mut address = 0;
+        for depth in 8..=15 {
+            for leaf in 0..(1 << (depth - 2)) {
+                let mut cs = Callstack::new();
+                cs.start_call(LineNumber(0), CallSiteId::new(main, LineNumber(1)));
+                for level in 0..depth {
+                    let line = match level {
+                        0 => 1,
+                        1 => 10,
+                        _ if leaf & (1 << (level - 2)) == 0 => 10,
+                        _ => 11,
+                    };
+                    cs.start_call(LineNumber(line), CallSiteId::new(walk, LineNumber(12)));
+                }
+                let id = tracker.get_callstack_id(&cs);
+                tracker.add_allocation(PARENT_PROCESS, address, 10, id);
+                address += 1;
+            }
+        }
+        tracker.check_if_new_peak();
+
+        let total = |lines: Vec<String>| -> usize {
+            lines
+                .iter()
+                .map(|line| line.rsplit_once(' ').unwrap().1.parse::<usize>().unwrap())
+                .sum()
+        };
+        let lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+            .to_lines(false)
+            .collect();
+        assert!(total(lines) < 16320 * 10);
+
+        // Whatever the depth, only the first and last iterations are kept,
+        // so everything ends up in a single callstack:
+        let lines: Vec<String> = tracker.combine_callstacks(true, FoldRecursion::new())()
+            .to_lines(false)
+            .collect();
+        assert_eq!(
+            lines,
+            vec!["a.py:1 (main);a.py:10 (walk);(recursive);a.py:12 (walk) 163200"]
+        );
+    }
+
+    #[test]
+    fn callstacks_are_cleaned_up_by_the_factory() {
+        use crate::flamegraph::CallstackCleaner;
+        use std::borrow::Cow;
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        struct CountingCleaner(Rc<Cell<usize>>);
+
+        impl CallstackCleaner for CountingCleaner {
+            fn cleanup<'a>(
+                &self,
+                callstack: &'a Callstack,
+                _functions: &dyn ReadFunctionLocations,
+            ) -> Cow<'a, Callstack> {
+                self.0.set(self.0.get() + 1);
+                Cow::Borrowed(callstack)
+            }
+
+            fn merges_callstacks(&self) -> bool {
+                true
+            }
+        }
+
+        let mut tracker = new_tracker();
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs = Callstack::new();
+        cs.start_call(LineNumber(0), CallSiteId::new(fid, LineNumber(1)));
+        let id = tracker.get_callstack_id(&cs);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, id);
+
+        // Nothing is cleaned up until the factory is called, since callers
+        // hold a lock until then:
+        let cleanups = Rc::new(Cell::new(0));
+        let factory = tracker.combine_callstacks(true, CountingCleaner(cleanups.clone()));
+        tracker.add_allocation(PARENT_PROCESS, 2, 500, id);
+        assert_eq!(cleanups.get(), 0);
+        let lines: Vec<String> = factory().to_lines(false).collect();
+        assert!(cleanups.get() > 0);
+        // The factory has the data from when it was created:
+        assert_eq!(lines, vec!["a:1 (af) 1000"]);
+    }
+
+    #[test]
+    fn coalesced_callstacks_keep_totals_exact() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        tracker.set_max_callstacks(2);
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut ids = vec![];
+        for line in 1..5 {
+            let mut cs = Callstack::new();
+            cs.start_call(LineNumber(0), CallSiteId::new(fid, LineNumber(line)));
+            ids.push(tracker.get_callstack_id(&cs));
+        }
+        for (i, id) in ids.iter().enumerate() {
+            tracker.add_allocation(PARENT_PROCESS, i, 1000 * (i + 1), *id);
+        }
+        tracker.check_if_new_peak();
+        assert_eq!(tracker.get_peak_allocated_bytes(), 10000);
+        assert_eq!(tracker.get_coalesced_bytes(true), 9000);
+        assert_eq!(tracker.get_coalesced_bytes(false), 9000);
+
+        let mut lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+            .to_lines(false)
+            .collect();
+        lines.sort();
+        assert_eq!(lines, vec!["[other] 9000", "a:1 (af) 1000"]);
+        tracker.validate();
+    }
+
+    #[test]
+    fn memory_overhead_grows_with_tracked_data() {
+        let mut tracker = new_tracker();
+        let empty = tracker.memory_overhead();
+        assert_eq!(empty.allocator, None);
+
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs = Callstack::new();
+        cs.start_call(LineNumber(0), CallSiteId::new(fid, LineNumber(1)));
+        let cs_id = tracker.get_callstack_id(&cs);
+        for i in 0..1000 {
+            tracker.add_allocation(PARENT_PROCESS, i, 10, cs_id);
+        }
+        tracker.add_anon_mmap(PARENT_PROCESS, 1_000_000, 20000, cs_id);
+        let overhead = tracker.memory_overhead();
+        assert!(overhead.allocations >= 1000 * std::mem::size_of::<(usize, Allocation)>());
+        assert!(overhead.anon_mmaps > empty.anon_mmaps);
+        assert!(overhead.callstacks > empty.callstacks);
+        assert!(overhead.callstack_statistics > empty.callstack_statistics);
+        assert!(overhead.functions > empty.functions);
+        assert_eq!(overhead.saved_statistics, 0);
+        assert_eq!(overhead.leak_checkpoints, 0);
+
+        // Snapshots, local peaks and leak checkpoints keep more data:
+        tracker.enable_top_peaks(TopPeaks::new(3, 0));
+        tracker.enable_leaks(LeakTracker::new());
+        tracker.take_snapshot("start");
+        tracker.leak_checkpoint_start();
+        tracker.free_allocation(PARENT_PROCESS, 0);
+        let overhead = tracker.memory_overhead();
+        assert!(overhead.saved_statistics >= 2 * std::mem::size_of::<usize>());
+        assert!(overhead.leak_checkpoints >= 999 * std::mem::size_of::<(ProcessUid, usize)>());
+        assert!(overhead.estimated_bytes() > overhead.allocations + overhead.callstacks);
+
+        // Per-thread data structures count too:
+        let mut local = LocalCallstackInterner::new();
+        local.get_or_insert_id(&mut cs.clone(), |cs| tracker.intern_callstack(cs));
+        assert!(tracker.memory_overhead().per_thread > 0);
+        drop(local);
+
+        tracker.enable_allocator_stats(|| {
+            Some(AllocatorStats {
+                allocated: 123,
+                resident: 456,
+            })
+        });
+        assert_eq!(tracker.memory_overhead().allocator.unwrap().resident, 456);
+    }
+
+    #[test]
+    fn leaked_allocations_are_combined() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        assert!(tracker.combine_leaked_callstacks(IdentityCleaner).is_none());
+        tracker.enable_leaks(LeakTracker::new());
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(LineNumber(0), CallSiteId::new(fid, LineNumber(1)));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(LineNumber(0), CallSiteId::new(fid, LineNumber(2)));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 100_000, 300, cs1_id);
+        tracker.leak_checkpoint_start();
+        tracker.add_allocation(PARENT_PROCESS, 2, 200, cs2_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 200_000, 7000, cs2_id);
+        tracker.add_allocation(PARENT_PROCESS, 3, 30, cs2_id);
+        tracker.add_allocation(PARENT_PROCESS, 4, 4, cs1_id);
+        // Freed between the checkpoints, so not a leak:
+        tracker.free_allocation(PARENT_PROCESS, 3);
+        tracker.leak_checkpoint_end();
+        tracker.add_allocation(PARENT_PROCESS, 5, 50000, cs1_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 6000, 6000, cs1_id);
+
+        let (summary, factory) = tracker.combine_leaked_callstacks(IdentityCleaner).unwrap();
+        assert_eq!(
+            summary,
+            LeakSummary {
+                leaked_bytes: 7204,
+                long_lived_bytes: 1300,
+                after_end_checkpoint_bytes: 56000,
+            }
+        );
+        let mut lines: Vec<String> = factory().to_lines(false).collect();
+        lines.sort();
+        assert_eq!(lines, vec!["a:1 (af) 4", "a:2 (af) 7200"]);
+    }
+
+    #[test]
+    fn snapshots_are_diffed_per_callstack() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let fid1 = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function("b".to_string(), "bf".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(LineNumber(0), CallSiteId::new(fid1, LineNumber(1)));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(LineNumber(0), CallSiteId::new(fid2, LineNumber(2)));
+        let mut cs3 = Callstack::new();
+        cs3.start_call(LineNumber(0), CallSiteId::new(fid2, LineNumber(3)));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 500, cs2_id);
+        assert_eq!(tracker.take_snapshot("start"), None);
+
+        // cs1 shrinks, cs2 is unchanged, and cs3 is new since the snapshot:
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        tracker.add_allocation(PARENT_PROCESS, 3, 300, cs1_id);
+        let cs3_id = tracker.get_callstack_id(&cs3);
+        tracker.add_allocation(PARENT_PROCESS, 4, 2000, cs3_id);
+        assert_eq!(tracker.take_snapshot("end"), Some("start".to_string()));
+
+        assert!(tracker
+            .diff_snapshots("start", "nonexistent", IdentityCleaner)
+            .is_none());
+        let (before, after) = tracker
+            .diff_snapshots("start", "end", IdentityCleaner)
+            .unwrap()();
+        let sorted = |mut lines: Vec<String>| {
+            lines.sort();
+            lines
+        };
+        assert_eq!(
+            sorted(before.to_lines(false).collect()),
+            vec!["a:1 (af) 1000", "b:3 (bf) 0"]
+        );
+        assert_eq!(
+            sorted(after.to_lines(false).collect()),
+            vec!["a:1 (af) 300", "b:3 (bf) 2000"]
+        );
+
+        tracker.reset("/tmp".to_string());
+        assert_eq!(tracker.take_snapshot("again"), None);
+    }
+
     #[test]
     fn combine_callstacks_and_sum_allocations() {
         pyo3::prepare_freethreaded_python();
@@ -1191,13 +2916,13 @@ mod tests {
 
         let id3 = CallSiteId::new(fid3, LineNumber(3));
         let mut cs1 = Callstack::new();
-        cs1.start_call(0, id1);
-        cs1.start_call(0, id2.clone());
+        cs1.start_call(LineNumber(0), id1);
+        cs1.start_call(LineNumber(0), id2);
         let mut cs2 = Callstack::new();
-        cs2.start_call(0, id3);
+        cs2.start_call(LineNumber(0), id3);
         let mut cs3 = Callstack::new();
-        cs3.start_call(0, id1_different);
-        cs3.start_call(0, id2);
+        cs3.start_call(LineNumber(0), id1_different);
+        cs3.start_call(LineNumber(0), id2);
         let cs1_id = tracker.get_callstack_id(&cs1);
         let cs2_id = tracker.get_callstack_id(&cs2);
         let cs3_id = tracker.get_callstack_id(&cs3);
@@ -1236,6 +2961,90 @@ mod tests {
         assert_eq!(expected2, result2);
     }
 
+    #[test]
+    fn bytecode_indexes_are_resolved_when_interning() {
+        pyo3::prepare_freethreaded_python();
+        let (line_table, offset) = Python::with_gil(|py| {
+            let locals = PyDict::new_bound(py);
+            py.run_bound(
+                "import dis\n\
+                 code = compile('x = 1\\ny = 2\\n\\nz = [y]\\n', 'resolved.py', 'exec')\n\
+                 offset = [o for (o, line) in dis.findlinestarts(code) if line == 4][0]\n",
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+            let code = locals.get_item("code").unwrap().unwrap();
+            let offset: i32 = locals
+                .get_item("offset")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            (get_line_table(&code).unwrap(), offset)
+        });
+        let mut tracker = new_tracker();
+        let fid = tracker.functions.add_function_with_line_table(
+            "resolved.py".to_string(),
+            "<module>".to_string(),
+            line_table,
+        );
+
+        // Different instructions on the same line get the same callstack:
+        let mut ids = vec![];
+        for bytecode_index in [offset, offset + 2] {
+            let mut cs = Callstack::new();
+            cs.start_call(
+                LineNumber(0),
+                CallSiteId::new(fid, LineNumberInfo::BytecodeIndex(bytecode_index)),
+            );
+            ids.push(tracker.get_callstack_id(&cs));
+        }
+        assert_eq!(ids[0], ids[1]);
+        let cs_id = ids[0];
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs_id);
+        let lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+            .to_lines(false)
+            .collect();
+        assert_eq!(lines, vec!["resolved.py:4 (<module>) 1000"]);
+
+        // The state file only needs the line number:
+        let state = tracker.state_file_factory()();
+        let node = &state.callstacks[cs_id as usize];
+        assert_eq!(node.line_number, 4);
+        assert_eq!(node.bytecode_index, None);
+
+        // Before the first instruction is the first line:
+        let call = CallSiteId::new(fid, LineNumberInfo::BytecodeIndex(-1));
+        assert_eq!(
+            tracker.functions.resolve_line_number(call),
+            CallSiteId::new(fid, LineNumber(1))
+        );
+    }
+
+    #[test]
+    fn line_table_lookups() {
+        let table = LineTable::new(
+            10,
+            vec![
+                (0, 2, None),
+                (2, 6, Some(11)),
+                (6, 8, Some(11)),
+                (8, 10, Some(13)),
+                (10, 12, Some(0)),
+            ],
+        );
+        assert_eq!(table.starts, vec![(0, 10), (2, 11), (8, 13), (10, 10)]);
+        assert_eq!(table.line_number(-1), 10);
+        assert_eq!(table.line_number(0), 10);
+        assert_eq!(table.line_number(4), 11);
+        assert_eq!(table.line_number(7), 11);
+        assert_eq!(table.line_number(8), 13);
+        assert_eq!(table.line_number(11), 10);
+        assert_eq!(table.line_number(1000), 10);
+        assert_eq!(LineTable::default().line_number(4), 0);
+    }
+
     #[test]
     fn test_unknown_function_id() {
         let func_locations = VecFunctionLocations::new().to_reader();
@@ -1246,5 +3055,60 @@ mod tests {
         assert_eq!(function, "UNKNOWN");
     }
 
+    #[test]
+    fn display_filenames() {
+        let cwd = Some("/home/user/project/");
+        assert_eq!(
+            display_filename(
+                "/usr/lib/python3.11/site-packages/numpy/core/numeric.py",
+                cwd
+            ),
+            "numpy/core/numeric.py"
+        );
+        assert_eq!(
+            display_filename("/usr/lib/python3/dist-packages/yaml/__init__.py", cwd),
+            "yaml/__init__.py"
+        );
+        assert_eq!(
+            display_filename("/home/user/project/pkg/mod.py", cwd),
+            "pkg/mod.py"
+        );
+        assert_eq!(
+            display_filename("/home/user/project2/mod.py", cwd),
+            "/home/user/project2/mod.py"
+        );
+        assert_eq!(display_filename("/tmp/x.py", Some("/")), "/tmp/x.py");
+        assert_eq!(display_filename("/tmp/x.py", None), "/tmp/x.py");
+        assert_eq!(display_filename("<string>", cwd), "<string>");
+        assert_eq!(
+            display_filename("<ipython-input-12-0123456789ab>", cwd),
+            "Cell [12]"
+        );
+        assert_eq!(
+            display_filename("<ipython-input-x-0123456789ab>", cwd),
+            "<ipython-input-x-0123456789ab>"
+        );
+    }
+
+    #[test]
+    fn notebook_cells_are_displayed_by_number() {
+        let mut functions = VecFunctionLocations::new();
+        let cell = "/tmp/ipykernel_123/456.py";
+        let before = functions.add_function(cell.to_string(), "f".to_string());
+        let other = functions.add_function("/tmp/other.py".to_string(), "f".to_string());
+        functions.set_notebook_cell(cell, 3);
+        let after = functions.add_function(cell.to_string(), "g".to_string());
+        let functions = functions.to_reader();
+        for id in [before, after] {
+            let (_, filename, display_filename) =
+                functions.get_function_and_filename_and_display_filename(id);
+            assert_eq!(filename, cell);
+            assert_eq!(display_filename, "Cell [3]");
+        }
+        let (_, _, display_filename) =
+            functions.get_function_and_filename_and_display_filename(other);
+        assert_eq!(display_filename, "/tmp/other.py");
+    }
+
     // TODO test to_lines(false)
 }
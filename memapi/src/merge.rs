@@ -0,0 +1,231 @@
+//! Merging `.prof` files, e.g. from several worker processes or several runs
+//! of the same job, into a single profile.
+//!
+//! The `.prof` files are the inferno collapsed format written by
+//! `FlamegraphCallstacks::write_flamegraphs()`: one `frame;frame;... size`
+//! line per callstack, where each frame is `filename:line (function)`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+use crate::flamegraph::{parse_lines, FlamegraphCallstacks};
+use crate::memorytracking::{
+    CallSiteId, Callstack, FunctionId, IdentityCleaner, LineNumberInfo, VecFunctionLocations,
+};
+
+/// How to combine the sizes of a callstack that appears in more than one
+/// source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeMode {
+    /// Add them up, e.g. total memory used by all workers.
+    Sum,
+    /// Take the largest, e.g. the worst case across runs.
+    Max,
+    /// Keep sources separate, under a root frame named after each source.
+    PerSource,
+}
+
+impl std::str::FromStr for MergeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sum" => Ok(MergeMode::Sum),
+            "max" => Ok(MergeMode::Max),
+            "per-source" => Ok(MergeMode::PerSource),
+            _ => Err(format!(
+                "Unknown merge mode {:?}, expected sum, max or per-source",
+                s
+            )),
+        }
+    }
+}
+
+/// The merged callstacks, ready for rendering.
+pub type MergedCallstacks =
+    FlamegraphCallstacks<HashMap<Callstack, usize>, VecFunctionLocations, IdentityCleaner>;
+
+/// Accumulates profiles from multiple sources into one.
+pub struct ProfileMerger {
+    mode: MergeMode,
+    functions: VecFunctionLocations,
+    function_ids: HashMap<(String, String), FunctionId>,
+    callstacks: HashMap<Callstack, usize>,
+}
+
+impl ProfileMerger {
+    pub fn new(mode: MergeMode) -> Self {
+        Self {
+            mode,
+            functions: VecFunctionLocations::new(),
+            function_ids: HashMap::new(),
+            callstacks: HashMap::new(),
+        }
+    }
+
+    /// Add the contents of a `.prof` file; `source` is used to name the root
+    /// frame in MergeMode::PerSource.
+    pub fn add_profile(&mut self, source: &str, contents: &str) -> Result<(), Box<dyn Error>> {
+        // Sum within a source first, so that Max compares per-source totals:
+        let mut profile: HashMap<Callstack, usize> = HashMap::new();
+        for (stack, size) in parse_lines(contents).map_err(|e| format!("{}, {}", source, e))? {
+            let mut calls = vec![];
+            if self.mode == MergeMode::PerSource {
+                calls.push(self.synthetic_call(source));
+            }
+            if stack == "[No Python stack]" {
+                if self.mode == MergeMode::PerSource {
+                    calls.push(self.synthetic_call(stack));
+                }
+            } else {
+                for frame in stack.split(';') {
+                    calls.push(self.parse_call(frame));
+                }
+            }
+            *profile.entry(Callstack::from_vec(calls)).or_default() += size;
+        }
+
+        for (callstack, size) in profile {
+            let merged = self.callstacks.entry(callstack).or_default();
+            match self.mode {
+                MergeMode::Sum | MergeMode::PerSource => *merged += size,
+                MergeMode::Max => *merged = (*merged).max(size),
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a `.prof` file, using its path as the source name.
+    pub fn add_file(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        self.add_profile(&path.display().to_string(), &contents)
+    }
+
+    /// Parse a `filename:line (function)` frame. Anything else, e.g. the root
+    /// frames added by MergeMode::PerSource, becomes a synthetic frame.
+    fn parse_call(&mut self, frame: &str) -> CallSiteId {
+        if frame == "[other]" {
+            return CallSiteId::new(FunctionId::OTHER, LineNumberInfo::LineNumber(0));
+        }
+        let parsed = frame
+            .strip_suffix(')')
+            .and_then(|frame| frame.rsplit_once(" ("))
+            .and_then(|(location, function)| {
+                let (filename, line) = location.rsplit_once(':')?;
+                Some((filename, line.parse().ok()?, function))
+            });
+        match parsed {
+            Some((filename, line, function)) => CallSiteId::new(
+                self.function_id(filename, function),
+                LineNumberInfo::LineNumber(line),
+            ),
+            None => self.synthetic_call(frame),
+        }
+    }
+
+    /// A frame with no filename, which is rendered as just its name.
+    fn synthetic_call(&mut self, name: &str) -> CallSiteId {
+        CallSiteId::new(self.function_id("", name), LineNumberInfo::LineNumber(0))
+    }
+
+    fn function_id(&mut self, filename: &str, function: &str) -> FunctionId {
+        let key = (filename.to_string(), function.to_string());
+        if let Some(id) = self.function_ids.get(&key) {
+            return *id;
+        }
+        let id = self.functions.add_function(key.0.clone(), key.1.clone());
+        self.function_ids.insert(key, id);
+        id
+    }
+
+    pub fn finish(self) -> MergedCallstacks {
+        FlamegraphCallstacks::new(self.callstacks, self.functions, IdentityCleaner)
+    }
+}
+
+/// Merge the given `.prof` files.
+pub fn merge_prof_files<P: AsRef<Path>>(
+    paths: &[P],
+    mode: MergeMode,
+) -> Result<MergedCallstacks, Box<dyn Error>> {
+    let mut merger = ProfileMerger::new(mode);
+    for path in paths {
+        merger.add_file(path.as_ref())?;
+    }
+    Ok(merger.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MergeMode, ProfileMerger};
+
+    const WORKER1: &str = "\
+a.py:1 (<module>);a.py:5 (f) 100
+a.py:1 (<module>);b.py:2 (g) 30
+[No Python stack] 7
+";
+    const WORKER2: &str = "\
+a.py:1 (<module>);a.py:5 (f) 50
+a.py:1 (<module>);a.py:9 (h);[other] 20
+";
+
+    fn merged_lines(mode: MergeMode) -> Vec<String> {
+        pyo3::prepare_freethreaded_python();
+        let mut merger = ProfileMerger::new(mode);
+        merger.add_profile("worker1", WORKER1).unwrap();
+        merger.add_profile("worker2", WORKER2).unwrap();
+        let merged = merger.finish();
+        let mut lines: Vec<String> = merged.to_lines(false).collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn merge_sum() {
+        assert_eq!(
+            merged_lines(MergeMode::Sum),
+            vec![
+                "[No Python stack] 7",
+                "a.py:1 (<module>);a.py:5 (f) 150",
+                "a.py:1 (<module>);a.py:9 (h);[other] 20",
+                "a.py:1 (<module>);b.py:2 (g) 30",
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_max() {
+        assert_eq!(
+            merged_lines(MergeMode::Max),
+            vec![
+                "[No Python stack] 7",
+                "a.py:1 (<module>);a.py:5 (f) 100",
+                "a.py:1 (<module>);a.py:9 (h);[other] 20",
+                "a.py:1 (<module>);b.py:2 (g) 30",
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_per_source() {
+        assert_eq!(
+            merged_lines(MergeMode::PerSource),
+            vec![
+                "worker1;[No Python stack] 7",
+                "worker1;a.py:1 (<module>);a.py:5 (f) 100",
+                "worker1;a.py:1 (<module>);b.py:2 (g) 30",
+                "worker2;a.py:1 (<module>);a.py:5 (f) 50",
+                "worker2;a.py:1 (<module>);a.py:9 (h);[other] 20",
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_bad_input() {
+        let mut merger = ProfileMerger::new(MergeMode::Sum);
+        assert!(merger.add_profile("bad", "a.py:1 (f) lots").is_err());
+        assert!(merger.add_profile("bad", "nosize").is_err());
+    }
+}
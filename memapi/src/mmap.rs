@@ -2,7 +2,7 @@ use super::ffi::LIBC;
 /// mmap API business logic.
 use std::os::raw::{c_int, c_void};
 
-/// Need to use pattern here: https://stackoverflow.com/a/37608197/6214034
+// Need to use pattern here: https://stackoverflow.com/a/37608197/6214034
 
 pub trait MmapAPI {
     /// Call if we're not reentrant.
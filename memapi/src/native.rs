@@ -0,0 +1,530 @@
+//! Native (C/C++/Cython/Rust) backtraces for allocations.
+//!
+//! The Python callstack only tells us which Python line called into an
+//! extension, not which code inside it did the allocating. Optionally we can
+//! capture the native backtrace of each allocation, and add the frames
+//! between the Python interpreter and the allocation as extra calls below the
+//! Python ones.
+//!
+//! Unwinding is done for every allocation, but symbolization, which is much
+//! more expensive, is cached per instruction pointer.
+//!
+//! A much cheaper alternative is to only attribute each allocation to the
+//! shared library that called `malloc()` or `mmap()`, based on the return
+//! address, and add that as a single pseudo-frame.
+
+use std::collections::HashMap;
+use std::ffi::{c_void, CStr};
+
+use ahash::RandomState as ARandomState;
+
+use crate::memorytracking::{CallSiteId, CallstackId, FunctionId, LineNumberInfo};
+use crate::overhead::{hashmap_overhead, PerThreadOverhead};
+use crate::util::new_hashmap;
+
+/// At most this many native frames are added to a callstack.
+const MAX_NATIVE_FRAMES: usize = 32;
+
+/// Frames inside the profiler, which get skipped, plus the frames we keep.
+const MAX_FRAMES_SCANNED: usize = MAX_NATIVE_FRAMES + 32;
+
+/// Allocations made via Python's APIs (PyMem_Malloc() and friends) have a
+/// few interpreter frames before the native code that called them.
+const MAX_LEADING_PYTHON_FRAMES: usize = 4;
+
+/// The loaded shared object, or executable, containing the given address:
+/// its base address and path.
+pub fn object_for_address(address: usize) -> Option<(usize, String)> {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    // SAFETY: dladdr() only reads the address, and fills in info.
+    if unsafe { libc::dladdr(address as *const c_void, &mut info) } == 0 {
+        return None;
+    }
+    let path = if info.dli_fname.is_null() {
+        String::new()
+    } else {
+        // SAFETY: dladdr() gave us a NUL-terminated string.
+        unsafe { CStr::from_ptr(info.dli_fname) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    Some((info.dli_fbase as usize, path))
+}
+
+/// Where an instruction pointer is.
+#[derive(Clone, Copy)]
+enum NativeFrame {
+    /// Inside the profiler itself.
+    Profiler,
+    /// Inside the Python interpreter.
+    Python,
+    /// Anywhere else, e.g. an extension module or a library it uses.
+    Other(CallSiteId),
+}
+
+/// An instruction pointer's object, function and line.
+pub struct ResolvedFrame {
+    ip: usize,
+    object: Option<(usize, String)>,
+    function_name: Option<String>,
+    filename: Option<String>,
+    line_number: u32,
+}
+
+/// Turns native backtraces into calls, caching symbolization.
+///
+/// Symbolization takes the dynamic loader's lock, and a thread holding that
+/// lock may be in the middle of allocating, so it mustn't happen while
+/// holding the tracker's lock. So turning instruction pointers into calls
+/// happens in three steps: finding the `unresolved()` ones while holding the
+/// lock, resolving them with `resolve()` without it, and then adding them
+/// with `add_resolved()` before calling `calls()`.
+pub struct NativeBacktraces {
+    profiler_base: usize,
+    python_base: usize,
+    frames: HashMap<usize, NativeFrame, ARandomState>,
+    function_ids: HashMap<(String, String), FunctionId, ARandomState>,
+}
+
+impl NativeBacktraces {
+    pub fn new() -> Self {
+        Self::with_bases(
+            Self::capture as *const () as usize,
+            pyo3::ffi::Py_IsInitialized as *const () as usize,
+        )
+    }
+
+    /// Frames in the objects containing `profiler_address` are skipped, and
+    /// frames in the object containing `python_address` are the Python
+    /// interpreter.
+    fn with_bases(profiler_address: usize, python_address: usize) -> Self {
+        let base = |address| object_for_address(address).map_or(0, |(base, _)| base);
+        Self {
+            profiler_base: base(profiler_address),
+            python_base: base(python_address),
+            frames: new_hashmap(),
+            function_ids: new_hashmap(),
+        }
+    }
+
+    /// Enabled if `FIL_NATIVE_BACKTRACES` is set to 1.
+    pub fn enabled_in_env() -> bool {
+        std::env::var("FIL_NATIVE_BACKTRACES").as_deref() == Ok("1")
+    }
+
+    /// Capture the instruction pointers of the current native backtrace,
+    /// innermost first. This doesn't need any of the tracker's locks.
+    pub fn capture() -> Vec<usize> {
+        let mut ips = Vec::with_capacity(MAX_FRAMES_SCANNED);
+        backtrace::trace(|frame| {
+            ips.push(frame.ip() as usize);
+            ips.len() < MAX_FRAMES_SCANNED
+        });
+        ips
+    }
+
+    /// The instruction pointers that haven't been resolved yet.
+    pub fn unresolved(&self, ips: &[usize]) -> Vec<usize> {
+        ips.iter()
+            .filter(|ip| !self.frames.contains_key(ip))
+            .copied()
+            .collect()
+    }
+
+    /// Figure out the object, function and line for each instruction
+    /// pointer. This must be done without holding the tracker's lock.
+    pub fn resolve(ips: &[usize]) -> Vec<ResolvedFrame> {
+        ips.iter()
+            .map(|ip| {
+                let mut frame = ResolvedFrame {
+                    ip: *ip,
+                    object: object_for_address(*ip),
+                    function_name: None,
+                    filename: None,
+                    line_number: 0,
+                };
+                // The instruction pointer is the return address, which may be
+                // the start of the next line or even function, so look up the
+                // call instruction before it:
+                backtrace::resolve(ip.saturating_sub(1) as *mut c_void, |symbol| {
+                    // Inlined functions are reported first; the last one is
+                    // the function actually containing the address.
+                    if let Some(name) = symbol.name() {
+                        frame.function_name = Some(format!("{:#}", name));
+                    }
+                    if let Some(path) = symbol.filename() {
+                        frame.filename = Some(path.to_string_lossy().into_owned());
+                        frame.line_number = symbol.lineno().unwrap_or(0);
+                    }
+                });
+                frame
+            })
+            .collect()
+    }
+
+    /// Add resolved frames to the cache.
+    ///
+    /// `add_function` registers a new function, given its filename and name.
+    pub fn add_resolved<F>(&mut self, frames: Vec<ResolvedFrame>, mut add_function: F)
+    where
+        F: FnMut(String, String) -> FunctionId,
+    {
+        for frame in frames {
+            if self.frames.contains_key(&frame.ip) {
+                // Another thread got here first.
+                continue;
+            }
+            let ip = frame.ip;
+            let frame = match frame.object {
+                Some((base, _)) if base == self.profiler_base => NativeFrame::Profiler,
+                Some((base, _)) if base == self.python_base => NativeFrame::Python,
+                _ => NativeFrame::Other(self.call_site(frame, &mut add_function)),
+            };
+            self.frames.insert(ip, frame);
+        }
+    }
+
+    /// Convert captured instruction pointers to calls, outermost first, to
+    /// be added below the Python callstack. Only the frames between the
+    /// Python interpreter and the profiler are kept.
+    pub fn calls(&self, ips: &[usize]) -> Vec<CallSiteId> {
+        let mut calls = vec![];
+        let mut leading_python_frames = 0;
+        for ip in ips {
+            match self.frames.get(ip) {
+                Some(NativeFrame::Profiler) if calls.is_empty() => {}
+                Some(NativeFrame::Python) if calls.is_empty() => {
+                    leading_python_frames += 1;
+                    if leading_python_frames > MAX_LEADING_PYTHON_FRAMES {
+                        break;
+                    }
+                }
+                Some(NativeFrame::Other(call)) => {
+                    calls.push(*call);
+                    if calls.len() == MAX_NATIVE_FRAMES {
+                        break;
+                    }
+                }
+                // Back in the interpreter, or not resolved:
+                _ => break,
+            }
+        }
+        calls.reverse();
+        calls
+    }
+
+    /// The call for a resolved frame, falling back to the shared object and
+    /// address if there are no symbols.
+    fn call_site<F>(&mut self, frame: ResolvedFrame, add_function: &mut F) -> CallSiteId
+    where
+        F: FnMut(String, String) -> FunctionId,
+    {
+        let (base, object_path) = frame.object.unwrap_or_default();
+        let function_name = frame
+            .function_name
+            .unwrap_or_else(|| format!("0x{:x}", frame.ip - base));
+        let mut filename = frame.filename.unwrap_or(object_path);
+        if filename.is_empty() {
+            filename = "[unknown]".to_string();
+        }
+        let function_id = *self
+            .function_ids
+            .entry((filename, function_name))
+            .or_insert_with_key(|(filename, function_name)| {
+                add_function(filename.clone(), function_name.clone())
+            });
+        CallSiteId::new(function_id, LineNumberInfo::LineNumber(frame.line_number))
+    }
+}
+
+impl Default for NativeBacktraces {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Attributes allocations to the shared library, or executable, whose code
+/// called the allocation function, cached per return address.
+///
+/// Like symbolization, finding the library takes the dynamic loader's lock, so
+/// it's done with `object_for_address()` without holding the tracker's lock,
+/// and then added with `add()`.
+pub struct SharedLibraries {
+    profiler_base: usize,
+    calls: HashMap<usize, Option<CallSiteId>, ARandomState>,
+    function_ids: HashMap<String, FunctionId, ARandomState>,
+}
+
+impl SharedLibraries {
+    pub fn new() -> Self {
+        Self::with_profiler_address(Self::new as *const () as usize)
+    }
+
+    /// Calls from the object containing `profiler_address` aren't attributed.
+    fn with_profiler_address(profiler_address: usize) -> Self {
+        Self {
+            profiler_base: object_for_address(profiler_address).map_or(0, |(base, _)| base),
+            calls: new_hashmap(),
+            function_ids: new_hashmap(),
+        }
+    }
+
+    /// Enabled if `FIL_NATIVE_LIBRARIES` is set to 1.
+    pub fn enabled_in_env() -> bool {
+        std::env::var("FIL_NATIVE_LIBRARIES").as_deref() == Ok("1")
+    }
+
+    /// The pseudo-call for the library containing the return address, if
+    /// it's been added. `Some(None)` means it's not attributed to a library.
+    pub fn get(&self, return_address: usize) -> Option<Option<CallSiteId>> {
+        self.calls.get(&return_address).copied()
+    }
+
+    /// Add the pseudo-call for a return address, given the object that
+    /// contains it.
+    ///
+    /// `add_function` registers a new function, given its filename and name.
+    pub fn add<F>(
+        &mut self,
+        return_address: usize,
+        object: Option<(usize, String)>,
+        add_function: F,
+    ) -> Option<CallSiteId>
+    where
+        F: FnOnce(String, String) -> FunctionId,
+    {
+        let call = match object {
+            Some((base, _)) if base == self.profiler_base => None,
+            Some((_, path)) if !path.is_empty() => {
+                let function_id = match self.function_ids.get(&path) {
+                    Some(function_id) => *function_id,
+                    None => {
+                        let name = path.rsplit('/').next().unwrap_or_default();
+                        // An empty filename makes this a pseudo-frame that is
+                        // rendered as just its name:
+                        let function_id = add_function(String::new(), format!("[{}]", name));
+                        self.function_ids.insert(path, function_id);
+                        function_id
+                    }
+                };
+                Some(CallSiteId::new(function_id, LineNumberInfo::LineNumber(0)))
+            }
+            _ => None,
+        };
+        self.calls.insert(return_address, call);
+        call
+    }
+}
+
+impl Default for SharedLibraries {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A per-thread cache in front of SharedLibraries, so that allocations from
+/// return addresses and callstacks the thread has seen before don't need the
+/// tracker's lock.
+pub struct LocalSharedLibraries {
+    calls: HashMap<usize, Option<CallSiteId>, ARandomState>,
+    callstacks: HashMap<(CallstackId, Option<CallSiteId>), CallstackId, ARandomState>,
+    overhead: PerThreadOverhead,
+}
+
+impl LocalSharedLibraries {
+    pub fn new() -> Self {
+        Self {
+            calls: new_hashmap(),
+            callstacks: new_hashmap(),
+            overhead: PerThreadOverhead::default(),
+        }
+    }
+
+    /// The ID of the callstack with the pseudo-call for the return address
+    /// added below it.
+    ///
+    /// On a cache miss, `get_call` finds the pseudo-call for a return address
+    /// using the shared SharedLibraries, and `intern` adds a call, if any, to
+    /// a callstack.
+    pub fn get_or_insert_id<G, I>(
+        &mut self,
+        callstack_id: CallstackId,
+        return_address: usize,
+        get_call: G,
+        intern: I,
+    ) -> CallstackId
+    where
+        G: FnOnce(usize) -> Option<CallSiteId>,
+        I: FnOnce(CallstackId, Option<CallSiteId>) -> CallstackId,
+    {
+        let call = *self
+            .calls
+            .entry(return_address)
+            .or_insert_with(|| get_call(return_address));
+        let id = *self
+            .callstacks
+            .entry((callstack_id, call))
+            .or_insert_with(|| intern(callstack_id, call));
+        self.overhead.update(
+            hashmap_overhead::<usize, Option<CallSiteId>>(self.calls.capacity())
+                + hashmap_overhead::<(CallstackId, Option<CallSiteId>), CallstackId>(
+                    self.callstacks.capacity(),
+                ),
+        );
+        id
+    }
+}
+
+impl Default for LocalSharedLibraries {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{object_for_address, LocalSharedLibraries, NativeBacktraces, SharedLibraries};
+    use crate::memorytracking::{
+        CallSiteId, FunctionId, LineNumberInfo, ReadFunctionLocations, VecFunctionLocations,
+    };
+
+    #[inline(never)]
+    fn allocating_native_function() -> Vec<usize> {
+        let ips = NativeBacktraces::capture();
+        std::hint::black_box(ips)
+    }
+
+    fn function_names(
+        native: &mut NativeBacktraces,
+        functions: &mut VecFunctionLocations,
+        ips: &[usize],
+    ) -> Vec<String> {
+        let resolved = NativeBacktraces::resolve(&native.unresolved(ips));
+        native.add_resolved(resolved, |filename, function_name| {
+            functions.add_function(filename, function_name)
+        });
+        native
+            .calls(ips)
+            .iter()
+            .map(|call| {
+                functions
+                    .get_function_and_filename_and_display_filename(call.function)
+                    .0
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn native_frames_are_symbolized_and_cached() {
+        let ips = allocating_native_function();
+        let mut functions = VecFunctionLocations::new();
+        // Nothing is the profiler or Python:
+        let mut native = NativeBacktraces::with_bases(0, 0);
+        let names = function_names(&mut native, &mut functions, &ips);
+        assert!(!names.is_empty());
+        // Outermost first, so the function that captured the backtrace is
+        // near the end:
+        let position = names
+            .iter()
+            .position(|name| name.ends_with("allocating_native_function"))
+            .unwrap();
+        assert!(position > names.len() / 2);
+
+        // The second time around everything is cached:
+        assert!(native.unresolved(&ips).is_empty());
+        assert_eq!(native.calls(&ips).len(), names.len());
+    }
+
+    #[test]
+    fn profiler_and_python_frames_are_dropped() {
+        let ips = allocating_native_function();
+        let test_binary = allocating_native_function as *const () as usize;
+        assert!(object_for_address(test_binary).is_some());
+        let mut functions = VecFunctionLocations::new();
+
+        // If the test binary is the profiler, all its frames get skipped, and
+        // only libc's frames, e.g. from starting the thread, remain:
+        let mut native = NativeBacktraces::with_bases(test_binary, 0);
+        let names = function_names(&mut native, &mut functions, &ips);
+        assert!(!names
+            .iter()
+            .any(|name| name.ends_with("allocating_native_function")));
+
+        // If the test binary is Python, there's nothing in between:
+        let mut native = NativeBacktraces::with_bases(0, test_binary);
+        assert!(function_names(&mut native, &mut functions, &ips).is_empty());
+    }
+
+    #[test]
+    fn shared_libraries_become_pseudo_frames() {
+        let mut functions = VecFunctionLocations::new();
+        let mut add_function =
+            |filename, function_name| functions.add_function(filename, function_name);
+        // A function in libc:
+        let libc_address = libc::getpid as *const () as usize;
+        let test_binary = allocating_native_function as *const () as usize;
+        let mut libraries = SharedLibraries::with_profiler_address(test_binary);
+
+        assert_eq!(libraries.get(libc_address), None);
+        let call = libraries
+            .add(
+                libc_address,
+                object_for_address(libc_address),
+                &mut add_function,
+            )
+            .unwrap();
+        assert_eq!(libraries.get(libc_address), Some(Some(call)));
+
+        // Another address in the same library gets the same function:
+        let other_call = libraries
+            .add(123, object_for_address(libc_address), &mut add_function)
+            .unwrap();
+        assert_eq!(call, other_call);
+
+        // The profiler and unknown addresses aren't attributed:
+        assert_eq!(
+            libraries.add(1, object_for_address(test_binary), &mut add_function),
+            None
+        );
+        assert_eq!(libraries.add(2, None, &mut add_function), None);
+        assert_eq!(libraries.get(2), Some(None));
+
+        let (function, filename, _) =
+            functions.get_function_and_filename_and_display_filename(call.function);
+        assert!(function.starts_with("[libc"), "{}", function);
+        assert_eq!(filename, "");
+    }
+
+    #[test]
+    fn local_shared_libraries_cache_calls_and_callstacks() {
+        let call = CallSiteId::new(FunctionId::new(7), LineNumberInfo::LineNumber(0));
+        let mut local = LocalSharedLibraries::new();
+        let mut lookups = vec![];
+        let mut interned = vec![];
+        let mut id = |callstack_id, return_address| {
+            local.get_or_insert_id(
+                callstack_id,
+                return_address,
+                |address| {
+                    lookups.push(address);
+                    (address != 2).then_some(call)
+                },
+                |callstack_id, call| {
+                    interned.push((callstack_id, call));
+                    callstack_id + if call.is_some() { 100 } else { 0 }
+                },
+            )
+        };
+        assert_eq!(id(1, 1), 101);
+        assert_eq!(id(1, 1), 101);
+        // Same library call below a different callstack:
+        assert_eq!(id(3, 1), 103);
+        // Another address for the same library, and one with no library:
+        assert_eq!(id(3, 4), 103);
+        assert_eq!(id(3, 2), 3);
+        assert_eq!(id(3, 2), 3);
+        assert_eq!(lookups, vec![1, 4, 2]);
+        assert_eq!(interned, vec![(1, Some(call)), (3, Some(call)), (3, None)]);
+    }
+}
@@ -1,6 +1,6 @@
 use std::fs::read_to_string;
 
-/// Logic for handling out-of-memory situations.
+// Logic for handling out-of-memory situations.
 
 pub trait MemoryInfo {
     /// Return how much memory the computer has, as bytes.
@@ -126,6 +126,12 @@ impl OutOfMemoryEstimator {
         }
     }
 
+    /// How many more bytes can be allocated before too_big_allocation()
+    /// checks actual memory availability.
+    pub fn bytes_until_next_check(&self) -> usize {
+        self.check_threshold_bytes
+    }
+
     pub fn print_info(&self) {
         self.memory_info.print_info();
     }
@@ -206,7 +212,7 @@ impl Default for RealMemoryInfo {
 impl RealMemoryInfo {
     #[cfg(target_os = "linux")]
     pub fn get_cgroup_available_memory(&self) -> usize {
-        let mut result = std::usize::MAX;
+        let mut result = usize::MAX;
         if let Some(cgroup) = &self.cgroup {
             if let Some(mem) = cgroup.controller_of::<cgroups_rs::memory::MemController>() {
                 let mem = mem.memory_stat();
@@ -227,7 +233,7 @@ impl RealMemoryInfo {
 
     #[cfg(target_os = "macos")]
     pub fn get_cgroup_available_memory(&self) -> usize {
-        std::usize::MAX
+        usize::MAX
     }
 }
 
@@ -244,7 +250,7 @@ impl MemoryInfo for RealMemoryInfo {
         // filesystem buffers to disk, which is probably what we want.
         let available = psutil::memory::virtual_memory()
             .map(|vm| vm.available() as usize)
-            .unwrap_or(std::usize::MAX);
+            .unwrap_or(usize::MAX);
         let cgroup_available = self.get_cgroup_available_memory();
         std::cmp::min(available, cgroup_available)
     }
@@ -336,7 +342,7 @@ mod tests {
             *self.swap.borrow_mut() += size;
         }
 
-        fn get_checks(&self) -> Ref<Vec<usize>> {
+        fn get_checks(&self) -> Ref<'_, Vec<usize>> {
             self.checks.borrow()
         }
 
@@ -0,0 +1,192 @@
+//! Accounting for the profiler's own memory usage.
+//!
+//! If a program runs out of memory under Fil but not without it, the
+//! difference is Fil's overhead. Most of it is the tracker's own data
+//! structures, whose size we estimate from their length and capacity. The
+//! allocator Fil uses for its own memory can also give exact totals, if it
+//! supports that.
+
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Serialize;
+
+/// Statistics from the allocator used for the profiler's own memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct AllocatorStats {
+    /// Bytes allocated by the profiler.
+    pub allocated: usize,
+    /// Bytes of physical memory used by the allocator, including
+    /// fragmentation and metadata.
+    pub resident: usize,
+}
+
+/// A way to get AllocatorStats, if the allocator supports it.
+pub type AllocatorStatsFn = fn() -> Option<AllocatorStats>;
+
+/// Estimated bytes used by the tracker's data structures.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryOverhead {
+    /// Live malloc() allocations.
+    pub allocations: usize,
+    /// Live anonymous mmap()s.
+    pub anon_mmaps: usize,
+    /// Interned callstacks.
+    pub callstacks: usize,
+    /// Memory usage and allocation counts for each callstack.
+    pub callstack_statistics: usize,
+    /// Function names and filenames.
+    pub functions: usize,
+    /// Copies of per-callstack memory usage kept for named snapshots and
+    /// local peaks. They share storage with each other where unchanged, so
+    /// this is an upper bound.
+    pub saved_statistics: usize,
+    /// The live allocations remembered at leak checkpoints.
+    pub leak_checkpoints: usize,
+    /// Per-thread callstack caches and buffered allocation events, see
+    /// PerThreadOverhead.
+    pub per_thread: usize,
+    /// Exact totals from the allocator, if available; these include all of
+    /// the above, and everything else the profiler allocated.
+    pub allocator: Option<AllocatorStats>,
+}
+
+impl MemoryOverhead {
+    /// Sum of the estimates for the tracker's data structures.
+    pub fn estimated_bytes(&self) -> usize {
+        self.allocations
+            + self.anon_mmaps
+            + self.callstacks
+            + self.callstack_statistics
+            + self.functions
+            + self.saved_statistics
+            + self.leak_checkpoints
+            + self.per_thread
+    }
+
+    /// Print a summary to stderr.
+    pub fn print(&self) {
+        const MIB: f64 = 1024.0 * 1024.0;
+        eprintln!(
+            "=fil-profile= Fil's own memory overhead: {:.1} MiB estimated for tracking data ({:.1} MiB live allocations, {:.1} MiB callstacks, {:.1} MiB per-callstack statistics, {:.1} MiB snapshots and peaks, {:.1} MiB leak checkpoints, {:.1} MiB per-thread caches and buffers).",
+            self.estimated_bytes() as f64 / MIB,
+            (self.allocations + self.anon_mmaps) as f64 / MIB,
+            self.callstacks as f64 / MIB,
+            self.callstack_statistics as f64 / MIB,
+            self.saved_statistics as f64 / MIB,
+            self.leak_checkpoints as f64 / MIB,
+            self.per_thread as f64 / MIB,
+        );
+        if let Some(allocator) = self.allocator {
+            eprintln!(
+                "=fil-profile= Fil's allocator: {:.1} MiB allocated, {:.1} MiB resident.",
+                allocator.allocated as f64 / MIB,
+                allocator.resident as f64 / MIB,
+            );
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Write out as JSON to `fil-overhead.json` in the given directory.
+    pub fn write_json(&self, directory_path: &Path) {
+        let path = directory_path.join("fil-overhead.json");
+        if let Err(e) = std::fs::write(&path, self.to_json()) {
+            eprintln!("=fil-profile= Error writing {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Estimated bytes used by a hash map with the given capacity: the entries,
+/// plus a control byte per entry.
+pub fn hashmap_overhead<K, V>(capacity: usize) -> usize {
+    capacity * (size_of::<(K, V)>() + 1)
+}
+
+/// Estimated bytes used by all the per-thread data structures, which the
+/// tracker can't get to itself.
+static PER_THREAD_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Estimated bytes currently used by per-thread data structures.
+pub fn per_thread_overhead() -> usize {
+    PER_THREAD_BYTES.load(Ordering::Relaxed)
+}
+
+/// Keeps a per-thread data structure's size included in
+/// per_thread_overhead(). Call update() with the new estimate whenever it
+/// might have changed; it's removed again when this is dropped.
+#[derive(Debug, Default)]
+pub struct PerThreadOverhead {
+    bytes: usize,
+}
+
+impl PerThreadOverhead {
+    pub fn update(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            PER_THREAD_BYTES.fetch_add(bytes - self.bytes, Ordering::Relaxed);
+        } else if bytes < self.bytes {
+            PER_THREAD_BYTES.fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+        }
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for PerThreadOverhead {
+    fn drop(&mut self) {
+        self.update(0);
+    }
+}
+
+/// Estimated bytes used by a BTreeMap with the given number of entries. Nodes
+/// are typically between half and fully full, so assume 3/4.
+pub fn btreemap_overhead<K, V>(len: usize) -> usize {
+    len * size_of::<(K, V)>() * 4 / 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{per_thread_overhead, AllocatorStats, MemoryOverhead, PerThreadOverhead};
+
+    #[test]
+    fn overhead_is_summed_and_serialized() {
+        let overhead = MemoryOverhead {
+            allocations: 1,
+            anon_mmaps: 2,
+            callstacks: 30,
+            callstack_statistics: 400,
+            functions: 5000,
+            saved_statistics: 60_000,
+            leak_checkpoints: 700_000,
+            per_thread: 8_000_000,
+            allocator: Some(AllocatorStats {
+                allocated: 10_000,
+                resident: 20_000,
+            }),
+        };
+        assert_eq!(overhead.estimated_bytes(), 8_765_433);
+        let json: serde_json::Value = serde_json::from_str(&overhead.to_json()).unwrap();
+        assert_eq!(json["callstacks"], 30);
+        assert_eq!(json["allocator"]["resident"], 20_000);
+        assert!(MemoryOverhead::default()
+            .to_json()
+            .contains("\"allocator\": null"));
+    }
+
+    #[test]
+    fn per_thread_overhead_is_updated_and_removed() {
+        // Other tests' threads may be changing the total at the same time,
+        // so use an amount they won't reach:
+        const BYTES: usize = 1 << 40;
+        let mut overhead = PerThreadOverhead::default();
+        overhead.update(BYTES);
+        assert!(per_thread_overhead() >= BYTES);
+        overhead.update(BYTES / 2);
+        assert!(per_thread_overhead() < BYTES);
+        assert!(per_thread_overhead() >= BYTES / 2);
+        drop(overhead);
+        assert!(per_thread_overhead() < BYTES / 2);
+    }
+}
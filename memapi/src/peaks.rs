@@ -0,0 +1,212 @@
+//! Track the K highest local memory peaks, rather than just the single global
+//! peak. Batch jobs often have multiple phases each with their own local
+//! maximum, and all of the ones close to the memory limit are interesting.
+
+use std::time::{Duration, Instant};
+
+use im::Vector as ImVector;
+use serde::Serialize;
+
+/// A local maximum of total memory usage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalPeak {
+    /// Total tracked bytes at the peak.
+    pub allocated_bytes: usize,
+    /// When the peak happened, relative to when tracking started.
+    pub elapsed: Duration,
+    /// Map CallstackId -> memory usage at the peak.
+    pub(crate) memory_usage: ImVector<usize>,
+}
+
+/// Two local peaks are considered distinct if memory usage dropped by at
+/// least `min_drop_bytes` between them. The current, still rising or not yet
+/// dropped enough, candidate peak is tracked separately until it's either
+/// finalized by a big enough drop or superseded by a higher value.
+pub struct TopPeaks {
+    k: usize,
+    min_drop_bytes: usize,
+    start: Instant,
+    // Finalized local peaks, sorted by descending allocated_bytes, at most k:
+    peaks: Vec<LocalPeak>,
+    candidate: Option<LocalPeak>,
+}
+
+impl TopPeaks {
+    pub fn new(k: usize, min_drop_bytes: usize) -> Self {
+        Self {
+            k,
+            min_drop_bytes,
+            start: Instant::now(),
+            peaks: vec![],
+            candidate: None,
+        }
+    }
+
+    /// Create from environment variables, or None if not enabled:
+    ///
+    /// * `FIL_TOP_PEAKS` is the number of local peaks to keep.
+    /// * `FIL_PEAK_SEPARATION_MB` is how many MiB memory needs to drop for
+    ///   the next peak to be considered distinct, default 100.
+    pub fn from_env() -> Option<Self> {
+        let k: usize = std::env::var("FIL_TOP_PEAKS").ok()?.parse().ok()?;
+        if k == 0 {
+            return None;
+        }
+        let min_drop_mb: usize = std::env::var("FIL_PEAK_SEPARATION_MB")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(100);
+        Some(Self::new(k, min_drop_mb * 1024 * 1024))
+    }
+
+    /// Estimated bytes of memory used by the saved memory usage of the
+    /// peaks. Unchanged parts are shared, so this is an upper bound.
+    pub fn memory_overhead(&self) -> usize {
+        self.peaks
+            .iter()
+            .chain(self.candidate.as_ref())
+            .map(|peak| peak.memory_usage.len() * std::mem::size_of::<usize>())
+            .sum()
+    }
+
+    /// Forget all peaks and restart the clock.
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+        self.peaks.clear();
+        self.candidate = None;
+    }
+
+    /// Called when memory usage might be at a local maximum, i.e. right
+    /// before it's about to go down.
+    pub fn maybe_new_candidate(&mut self, allocated_bytes: usize, memory_usage: &ImVector<usize>) {
+        self.maybe_new_candidate_at(self.start.elapsed(), allocated_bytes, memory_usage);
+    }
+
+    fn maybe_new_candidate_at(
+        &mut self,
+        elapsed: Duration,
+        allocated_bytes: usize,
+        memory_usage: &ImVector<usize>,
+    ) {
+        if allocated_bytes == 0 {
+            return;
+        }
+        if let Some(candidate) = &self.candidate {
+            if allocated_bytes <= candidate.allocated_bytes {
+                return;
+            }
+        }
+        // Don't bother snapshotting if it wouldn't make the top K anyway:
+        if self.peaks.len() == self.k && allocated_bytes <= self.peaks[self.k - 1].allocated_bytes {
+            return;
+        }
+        self.candidate = Some(LocalPeak {
+            allocated_bytes,
+            elapsed,
+            memory_usage: memory_usage.clone(),
+        });
+    }
+
+    /// Called after memory usage went down.
+    pub fn memory_decreased(&mut self, allocated_bytes: usize) {
+        let dropped_enough = match &self.candidate {
+            Some(candidate) => {
+                candidate.allocated_bytes.saturating_sub(allocated_bytes) >= self.min_drop_bytes
+            }
+            None => false,
+        };
+        if dropped_enough {
+            let candidate = self.candidate.take().unwrap();
+            Self::insert(&mut self.peaks, self.k, candidate);
+        }
+    }
+
+    fn insert(peaks: &mut Vec<LocalPeak>, k: usize, peak: LocalPeak) {
+        let index = peaks.partition_point(|p| p.allocated_bytes >= peak.allocated_bytes);
+        if index < k {
+            peaks.insert(index, peak);
+            peaks.truncate(k);
+        }
+    }
+
+    /// The top K local peaks, highest first, including the current candidate.
+    pub fn get_peaks(&self) -> Vec<LocalPeak> {
+        let mut peaks = self.peaks.clone();
+        if let Some(candidate) = &self.candidate {
+            Self::insert(&mut peaks, self.k, candidate.clone());
+        }
+        peaks
+    }
+}
+
+/// A JSON summary of the peaks; `base_filename` is formatted with the
+/// 1-based rank of the peak to give the name of its files.
+pub fn summary_json(peaks: &[LocalPeak], base_filename: impl Fn(usize) -> String) -> String {
+    #[derive(Serialize)]
+    struct Summary {
+        rank: usize,
+        allocated_bytes: usize,
+        elapsed_seconds: f64,
+        base_filename: String,
+    }
+    let summaries: Vec<Summary> = peaks
+        .iter()
+        .enumerate()
+        .map(|(i, peak)| Summary {
+            rank: i + 1,
+            allocated_bytes: peak.allocated_bytes,
+            elapsed_seconds: peak.elapsed.as_secs_f64(),
+            base_filename: base_filename(i + 1),
+        })
+        .collect();
+    serde_json::to_string_pretty(&summaries).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TopPeaks;
+    use std::time::Duration;
+
+    /// Feed a series of total memory usage values, calling the hooks the way
+    /// AllocationTracker does.
+    fn run(top_peaks: &mut TopPeaks, usage: &[usize]) {
+        let mut previous = 0;
+        for (i, bytes) in usage.iter().enumerate() {
+            if *bytes < previous {
+                top_peaks.maybe_new_candidate_at(
+                    Duration::from_secs(i as u64 - 1),
+                    previous,
+                    &im::vector![previous],
+                );
+                top_peaks.memory_decreased(*bytes);
+            }
+            previous = *bytes;
+        }
+    }
+
+    fn summary(top_peaks: &TopPeaks) -> Vec<(usize, u64)> {
+        top_peaks
+            .get_peaks()
+            .iter()
+            .map(|p| (p.allocated_bytes, p.elapsed.as_secs()))
+            .collect()
+    }
+
+    #[test]
+    fn distinct_peaks_need_big_enough_drop() {
+        let mut top_peaks = TopPeaks::new(3, 10);
+        // The dip at 95 isn't deep enough to separate 100 and 105, but the
+        // drop to 20 is.
+        run(&mut top_peaks, &[100, 95, 105, 20, 50, 30, 80, 75]);
+        assert_eq!(summary(&top_peaks), vec![(105, 2), (80, 6), (50, 4)]);
+    }
+
+    #[test]
+    fn only_top_k_kept() {
+        let mut top_peaks = TopPeaks::new(2, 10);
+        run(&mut top_peaks, &[50, 0, 70, 0, 60, 0, 10, 0, 65, 64]);
+        assert_eq!(summary(&top_peaks), vec![(70, 2), (65, 8)]);
+        top_peaks.reset();
+        assert_eq!(summary(&top_peaks), vec![]);
+    }
+}
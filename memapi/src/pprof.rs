@@ -0,0 +1,240 @@
+//! Export profiles in pprof's profile.proto format, so they can be loaded by
+//! `go tool pprof` and other compatible viewers. The schema is documented at
+//! https://github.com/google/pprof/blob/main/proto/profile.proto; we only
+//! define the subset of fields we actually write.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+use ahash::RandomState as ARandomState;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prost::Message;
+
+use crate::util::new_hashmap;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Profile {
+    #[prost(message, repeated, tag = "1")]
+    pub sample_type: Vec<ValueType>,
+    #[prost(message, repeated, tag = "2")]
+    pub sample: Vec<Sample>,
+    #[prost(message, repeated, tag = "4")]
+    pub location: Vec<Location>,
+    #[prost(message, repeated, tag = "5")]
+    pub function: Vec<Function>,
+    /// All strings are stored here and referred to by index; index 0 must be
+    /// the empty string.
+    #[prost(string, repeated, tag = "6")]
+    pub string_table: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ValueType {
+    #[prost(int64, tag = "1")]
+    pub r#type: i64,
+    #[prost(int64, tag = "2")]
+    pub unit: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    /// Leaf location first.
+    #[prost(uint64, repeated, tag = "1")]
+    pub location_id: Vec<u64>,
+    #[prost(int64, repeated, tag = "2")]
+    pub value: Vec<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Location {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(message, repeated, tag = "4")]
+    pub line: Vec<Line>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Line {
+    #[prost(uint64, tag = "1")]
+    pub function_id: u64,
+    #[prost(int64, tag = "2")]
+    pub line: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Function {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(int64, tag = "2")]
+    pub name: i64,
+    #[prost(int64, tag = "3")]
+    pub system_name: i64,
+    #[prost(int64, tag = "4")]
+    pub filename: i64,
+}
+
+/// Build up a Profile one sample at a time, deduplicating strings, functions
+/// and locations as we go.
+pub struct ProfileBuilder {
+    profile: Profile,
+    strings: HashMap<String, i64, ARandomState>,
+    // (name, filename) string ids -> function id
+    functions: HashMap<(i64, i64), u64, ARandomState>,
+    // (function id, line number) -> location id
+    locations: HashMap<(u64, i64), u64, ARandomState>,
+}
+
+impl ProfileBuilder {
+    /// Create a builder for a profile with a single value per sample, e.g.
+    /// sample type "peak-memory" with unit "bytes".
+    pub fn new(sample_type: &str, unit: &str) -> Self {
+        let mut builder = Self {
+            profile: Profile::default(),
+            strings: new_hashmap(),
+            functions: new_hashmap(),
+            locations: new_hashmap(),
+        };
+        builder.string_id("");
+        let value_type = ValueType {
+            r#type: builder.string_id(sample_type),
+            unit: builder.string_id(unit),
+        };
+        builder.profile.sample_type.push(value_type);
+        builder
+    }
+
+    fn string_id(&mut self, s: &str) -> i64 {
+        if let Some(id) = self.strings.get(s) {
+            return *id;
+        }
+        let id = self.profile.string_table.len() as i64;
+        self.profile.string_table.push(s.to_string());
+        self.strings.insert(s.to_string(), id);
+        id
+    }
+
+    fn location_id(&mut self, function: &str, filename: &str, line: u32) -> u64 {
+        let name = self.string_id(function);
+        let filename = self.string_id(filename);
+        let next_function_id = self.profile.function.len() as u64 + 1;
+        let function_id = *self
+            .functions
+            .entry((name, filename))
+            .or_insert(next_function_id);
+        if function_id == next_function_id {
+            self.profile.function.push(Function {
+                id: function_id,
+                name,
+                system_name: name,
+                filename,
+            });
+        }
+
+        let line = line as i64;
+        let next_location_id = self.profile.location.len() as u64 + 1;
+        let location_id = *self
+            .locations
+            .entry((function_id, line))
+            .or_insert(next_location_id);
+        if location_id == next_location_id {
+            self.profile.location.push(Location {
+                id: location_id,
+                line: vec![Line { function_id, line }],
+            });
+        }
+        location_id
+    }
+
+    /// Add a sample. Frames are (function, filename, line number), ordered
+    /// root first, the same order as a Callstack.
+    pub fn add_sample<'a, I>(&mut self, frames: I, value: i64)
+    where
+        I: DoubleEndedIterator<Item = (&'a str, &'a str, u32)>,
+    {
+        let mut location_id: Vec<u64> = frames
+            .rev()
+            .map(|(function, filename, line)| self.location_id(function, filename, line))
+            .collect();
+        if location_id.is_empty() {
+            location_id.push(self.location_id("[No Python stack]", "", 0));
+        }
+        self.profile.sample.push(Sample {
+            location_id,
+            value: vec![value],
+        });
+    }
+
+    pub fn build(self) -> Profile {
+        self.profile
+    }
+}
+
+/// Write the profile to disk, gzipped, which is what pprof tools expect.
+pub fn write_gzipped(profile: &Profile, path: &Path) -> std::io::Result<()> {
+    let file = std::fs::File::create(path)?;
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    encoder.write_all(&profile.encode_to_vec())?;
+    encoder.finish()?.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_gzipped, Profile, ProfileBuilder};
+    use flate2::read::GzDecoder;
+    use prost::Message;
+    use std::io::Read;
+
+    #[test]
+    fn functions_and_locations_are_deduplicated() {
+        let mut builder = ProfileBuilder::new("peak-memory", "bytes");
+        builder.add_sample(
+            [("main", "a.py", 1), ("helper", "b.py", 10)].into_iter(),
+            100,
+        );
+        builder.add_sample(
+            [("main", "a.py", 1), ("helper", "b.py", 12)].into_iter(),
+            50,
+        );
+        builder.add_sample(std::iter::empty(), 7);
+        let profile = builder.build();
+
+        let string = |i: i64| profile.string_table[i as usize].as_str();
+        assert_eq!(string(0), "");
+        assert_eq!(string(profile.sample_type[0].r#type), "peak-memory");
+        assert_eq!(string(profile.sample_type[0].unit), "bytes");
+
+        // main, helper, and the "no stack" placeholder:
+        assert_eq!(profile.function.len(), 3);
+        // main:1, helper:10, helper:12, placeholder:
+        assert_eq!(profile.location.len(), 4);
+
+        // Leaf comes first, so it's also the first to get an id:
+        assert_eq!(profile.sample[0].location_id, vec![1, 2]);
+        assert_eq!(profile.sample[1].location_id, vec![3, 2]);
+        assert_eq!(profile.sample[0].value, vec![100]);
+        assert_eq!(profile.sample[2].location_id, vec![4]);
+        let helper = &profile.function[0];
+        assert_eq!(helper.id, 1);
+        assert_eq!(string(helper.name), "helper");
+        assert_eq!(string(helper.filename), "b.py");
+        assert_eq!(profile.location[2].line[0].line, 12);
+    }
+
+    #[test]
+    fn gzipped_profile_roundtrips() {
+        let mut builder = ProfileBuilder::new("peak-memory", "bytes");
+        builder.add_sample([("main", "a.py", 1)].into_iter(), 100);
+        let profile = builder.build();
+        let f = tempfile::NamedTempFile::new().unwrap();
+        write_gzipped(&profile, f.path()).unwrap();
+
+        let mut decoded = vec![];
+        GzDecoder::new(std::fs::File::open(f.path()).unwrap())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(Profile::decode(decoded.as_slice()).unwrap(), profile);
+    }
+}
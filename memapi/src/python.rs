@@ -2,6 +2,9 @@
 
 use once_cell::sync::Lazy;
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::memorytracking::LineTable;
 
 // Get the source code line from a given filename.
 pub fn get_source_line(filename: &str, line_number: usize) -> PyResult<String> {
@@ -25,3 +28,67 @@ pub fn get_runpy_path() -> &'static str {
     });
     PATH.as_str()
 }
+
+// Return the directory of the stdlib's Python modules.
+pub fn get_stdlib_path() -> &'static str {
+    static PATH: Lazy<String> = Lazy::new(|| {
+        Python::with_gil(|py| {
+            let os = PyModule::import_bound(py, "os").unwrap();
+            let filename = os.filename().unwrap().to_string();
+            match filename.rsplit_once('/') {
+                Some((directory, _)) => directory.to_string(),
+                None => filename,
+            }
+        })
+    });
+    PATH.as_str()
+}
+
+// Read the line table of a Python code object.
+pub fn get_line_table(code: &Bound<'_, PyAny>) -> PyResult<LineTable> {
+    let first_line: u32 = code.getattr("co_firstlineno")?.extract()?;
+    if code.hasattr("co_lines")? {
+        let ranges = code
+            .call_method0("co_lines")?
+            .iter()?
+            .map(|range| range?.extract())
+            .collect::<PyResult<Vec<(i32, i32, Option<u32>)>>>()?;
+        return Ok(LineTable::new(first_line, ranges));
+    }
+
+    // Python 3.9 doesn't have co_lines(), only co_lnotab, which has pairs of
+    // bytecode index and (signed) line number increments. This is the same
+    // algorithm as dis.findlinestarts().
+    let lnotab = code.getattr("co_lnotab")?;
+    let lnotab = lnotab.downcast::<PyBytes>()?.as_bytes();
+    let mut starts = vec![];
+    let mut bytecode_index = 0;
+    let mut line = first_line as i64;
+    let mut last_line = None;
+    for increments in lnotab.chunks_exact(2) {
+        if increments[0] != 0 {
+            if last_line != Some(line) {
+                starts.push((bytecode_index, line));
+                last_line = Some(line);
+            }
+            bytecode_index += increments[0] as i32;
+        }
+        line += increments[1] as i8 as i64;
+    }
+    if last_line != Some(line) {
+        starts.push((bytecode_index, line));
+    }
+    let ends: Vec<i32> = starts
+        .iter()
+        .skip(1)
+        .map(|(start, _)| *start)
+        .chain(std::iter::once(i32::MAX))
+        .collect();
+    Ok(LineTable::new(
+        first_line,
+        starts
+            .into_iter()
+            .zip(ends)
+            .map(|((start, line), end)| (start, end, u32::try_from(line).ok())),
+    ))
+}
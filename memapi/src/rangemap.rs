@@ -1,60 +1,41 @@
-use std::cmp::{max, min};
+use std::collections::BTreeMap;
+
+use crate::overhead::btreemap_overhead;
 #[cfg(test)]
 use std::collections::HashMap;
 
-/// Open-ended range in memory, [A...B).
-#[derive(Clone, Debug, PartialEq)]
-struct Range {
-    start: usize,
-    end: usize,
-}
-
-impl Range {
-    fn new(start: usize, length: usize) -> Self {
-        assert!(length > 0);
-        Range {
-            start,
-            end: start + length,
-        }
-    }
-
-    fn intersection(&self, other: &Range) -> Option<Range> {
-        let max_start = max(self.start, other.start);
-        let min_end = min(self.end, other.end);
-        if min_end <= max_start {
-            None
-        } else {
-            Some(Range {
-                start: max_start,
-                end: min_end,
-            })
-        }
-    }
-
-    fn size(&self) -> usize {
-        self.end - self.start
-    }
-}
-
 /// Map from memory address range to some other object, typically a CallStack.
 ///
 /// The intended use case is tracking anonymous mmap(), where munmap() can
 /// deallocate chunks of an allocation, or even multiple allocations.
 #[derive(Clone, Debug, PartialEq, Default)]
 pub struct RangeMap<V: Clone> {
-    ranges: Vec<(Range, V)>,
+    // Map start address to (end address, value) of the open-ended range
+    // [start...end). Ranges never overlap.
+    ranges: BTreeMap<usize, (usize, V)>,
 }
 
 impl<V: Clone> RangeMap<V> {
     pub fn new() -> Self {
-        RangeMap { ranges: vec![] }
+        RangeMap {
+            ranges: BTreeMap::new(),
+        }
     }
 
-    pub fn add(&mut self, start: usize, length: usize, value: V) {
+    /// Add a range. Like mmap() with MAP_FIXED, this replaces any overlapping
+    /// parts of existing ranges; returns how many bytes were replaced.
+    pub fn add(&mut self, start: usize, length: usize, value: V) -> Vec<(V, usize)> {
         if length == 0 {
-            return;
+            return vec![];
         }
-        self.ranges.push((Range::new(start, length), value));
+        let replaced = self.remove(start, length);
+        self.ranges.insert(start, (start + length, value));
+        replaced
+    }
+
+    /// Estimated bytes of memory used by the map itself.
+    pub fn memory_overhead(&self) -> usize {
+        btreemap_overhead::<usize, (usize, V)>(self.ranges.len())
     }
 
     /// Return how many bytes were removed.
@@ -62,75 +43,65 @@ impl<V: Clone> RangeMap<V> {
         if length == 0 {
             return vec![];
         }
-        let mut new_ranges = vec![];
+        let end = start + length;
         let mut removed = vec![];
-        let remove = Range::new(start, length);
-        for (range, value) in self.ranges.iter() {
-            match range.intersection(&remove) {
-                // Total overlap, remove it all:
-                Some(i) if (i.start == range.start) && (i.end == range.end) => {
-                    removed.push((value.clone(), range.size()));
-                }
-                // Remove chunk from start:
-                Some(i) if (i.start == range.start) && (i.end < range.end) => {
-                    let new_range = Range {
-                        start: i.end,
-                        end: range.end,
-                    };
-                    removed.push((value.clone(), range.size() - new_range.size()));
-                    new_ranges.push((new_range, value.clone()));
-                }
-                // Remove chunk from end:
-                Some(i) if (i.start > range.start) && (i.end == range.end) => {
-                    let new_range = Range {
-                        start: range.start,
-                        end: i.start,
-                    };
-                    removed.push((value.clone(), range.size() - new_range.size()));
-                    new_ranges.push((new_range, value.clone()));
-                }
-                // Remove chunk from the middle:
-                Some(i) => {
-                    new_ranges.push((
-                        Range {
-                            start: range.start,
-                            end: i.start,
-                        },
-                        value.clone(),
-                    ));
-                    new_ranges.push((
-                        Range {
-                            start: i.end,
-                            end: range.end,
-                        },
-                        value.clone(),
-                    ));
-                    removed.push((value.clone(), length));
-                }
-                // No overlap, remove nothing:
-                None => {
-                    new_ranges.push((range.clone(), value.clone()));
+
+        // A range starting before the removed chunk might overlap it:
+        if let Some((&range_start, &(range_end, _))) = self.ranges.range(..start).next_back() {
+            if range_end > start {
+                let (_, value) = self.ranges.remove(&range_start).unwrap();
+                // Keep the part before the removed chunk:
+                self.ranges.insert(range_start, (start, value.clone()));
+                if range_end > end {
+                    // Removed chunk is in the middle, keep the part after it
+                    // too:
+                    self.ranges.insert(end, (range_end, value.clone()));
+                    removed.push((value, length));
+                } else {
+                    removed.push((value, range_end - start));
                 }
             }
         }
-        self.ranges = new_ranges;
+
+        // Ranges starting inside the removed chunk are removed entirely,
+        // except for a possible remainder past its end:
+        let overlapping: Vec<usize> = self.ranges.range(start..end).map(|(s, _)| *s).collect();
+        for range_start in overlapping {
+            let (range_end, value) = self.ranges.remove(&range_start).unwrap();
+            if range_end > end {
+                self.ranges.insert(end, (range_end, value.clone()));
+                removed.push((value, end - range_start));
+            } else {
+                removed.push((value, range_end - range_start));
+            }
+        }
         removed
     }
 
     pub fn size(&self) -> usize {
-        self.ranges.iter().map(|(r, _)| r.size()).sum()
+        self.ranges
+            .iter()
+            .map(|(start, (end, _))| end - start)
+            .sum()
     }
 
     /// Return iterator of (length, value).
     pub fn into_iter(self) -> impl Iterator<Item = (usize, V)> {
-        self.ranges.into_iter().map(|(r, v)| (r.size(), v))
+        self.ranges
+            .into_iter()
+            .map(|(start, (end, v))| (end - start, v))
+    }
+
+    /// Return iterator of (length, &value).
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &V)> {
+        self.ranges.iter().map(|(start, (end, v))| (end - start, v))
     }
 
     #[cfg(test)]
     pub fn as_hashmap(&self) -> HashMap<usize, (usize, &V)> {
         self.ranges
             .iter()
-            .map(|(range, v)| (range.start, (range.size(), v)))
+            .map(|(start, (end, v))| (*start, (end - start, v)))
             .collect()
     }
 }
@@ -220,6 +191,21 @@ mod tests {
             .boxed()
     }
 
+    #[test]
+    fn adding_overlapping_range_replaces() {
+        let mut rangemap: RangeMap<usize> = RangeMap::new();
+        rangemap.add(10, 10, 1);
+        rangemap.add(30, 10, 2);
+        let mut replaced = rangemap.add(15, 20, 3);
+        replaced.sort();
+        assert_eq!(replaced, vec![(1, 5), (2, 5)]);
+        assert_eq!(
+            rangemap.as_hashmap(),
+            HashMap::from([(10, (5, &1)), (15, (20, &3)), (35, (5, &2))])
+        );
+        assert_eq!(rangemap.size(), 30);
+    }
+
     proptest! {
         /// We can add and remove ranges and get the same result in the real and
         /// stupid range maps.
@@ -228,8 +214,8 @@ mod tests {
             let mut real_rangemap : RangeMap<usize> = RangeMap::new();
             let mut stupid_rangemap: StupidRangeMap<usize> = StupidRangeMap::new();
             for (start, length) in add_ranges {
-                real_rangemap.add(start, length, start * (length as usize));
-                stupid_rangemap.add(start, length, start * (length as usize));
+                real_rangemap.add(start, length, start * length);
+                stupid_rangemap.add(start, length, start * length);
                 prop_assert_eq!(real_rangemap.size(), stupid_rangemap.size());
                 prop_assert_eq!(real_rangemap.as_hashmap(), stupid_rangemap.as_hashmap());
             }
@@ -246,5 +232,21 @@ mod tests {
                 prop_assert_eq!(real_rangemap.as_hashmap(), stupid_rangemap.as_hashmap());
             }
         }
+
+        /// Overlapping adds replace existing ranges, the same way as in the
+        /// stupid range map.
+        #[test]
+        fn adding_overlapping_ranges(add_ranges in proptest::collection::vec((0..200usize, 1..30usize), 1..20)) {
+            let mut real_rangemap : RangeMap<usize> = RangeMap::new();
+            let mut stupid_rangemap: StupidRangeMap<usize> = StupidRangeMap::new();
+            for (i, (start, length)) in add_ranges.into_iter().enumerate() {
+                let size_before = real_rangemap.size();
+                let replaced: usize = real_rangemap.add(start, length, i).iter().map(|(_, size)| size).sum();
+                stupid_rangemap.add(start, length, i);
+                prop_assert_eq!(real_rangemap.size(), size_before + length - replaced);
+                prop_assert_eq!(real_rangemap.size(), stupid_rangemap.size());
+                prop_assert_eq!(real_rangemap.as_hashmap(), stupid_rangemap.as_hashmap());
+            }
+        }
     }
 }
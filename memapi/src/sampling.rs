@@ -0,0 +1,101 @@
+//! Poisson sampling of allocations by bytes, for lower overhead.
+//!
+//! Conceptually every allocated byte is sampled with probability
+//! 1/mean_bytes, and an allocation is recorded if any of its bytes are. So an
+//! allocation of `size` bytes is sampled with probability
+//! `1 - exp(-size / mean_bytes)`, and to get unbiased totals its reported size
+//! is scaled up by the inverse of that probability. Large allocations are
+//! almost always sampled, with little scaling; small ones rarely are, but
+//! stand in for many others when they are.
+
+/// Decides which allocations to sample. Not thread-safe, so typically there
+/// is one per thread.
+pub struct Sampler {
+    mean_bytes: f64,
+    // Distance to the next sampled byte:
+    bytes_until_sample: f64,
+    rng: fastrand::Rng,
+}
+
+impl Sampler {
+    pub fn new(mean_bytes: usize) -> Self {
+        Self::with_rng(mean_bytes, fastrand::Rng::new())
+    }
+
+    fn with_rng(mean_bytes: usize, rng: fastrand::Rng) -> Self {
+        assert!(mean_bytes > 0);
+        let mut sampler = Self {
+            mean_bytes: mean_bytes as f64,
+            bytes_until_sample: 0.0,
+            rng,
+        };
+        sampler.bytes_until_sample = sampler.next_interval();
+        sampler
+    }
+
+    /// The mean sampling interval in bytes, from `FIL_SAMPLING_BYTES`, or None
+    /// if sampling isn't enabled.
+    pub fn mean_bytes_from_env() -> Option<usize> {
+        std::env::var("FIL_SAMPLING_BYTES")
+            .ok()?
+            .parse()
+            .ok()
+            .filter(|mean_bytes| *mean_bytes > 0)
+    }
+
+    /// Exponentially distributed, so sampled bytes form a Poisson process.
+    fn next_interval(&mut self) -> f64 {
+        // 1 - f64() is in (0, 1], so the log is finite:
+        -(1.0 - self.rng.f64()).ln() * self.mean_bytes
+    }
+
+    /// Called for every allocation. Returns the size to record if it was
+    /// sampled, or None if it should be ignored.
+    pub fn sample(&mut self, size: usize) -> Option<usize> {
+        self.bytes_until_sample -= size as f64;
+        if self.bytes_until_sample > 0.0 {
+            return None;
+        }
+        // Skip any further sample points inside this allocation; it's only
+        // recorded once either way.
+        while self.bytes_until_sample <= 0.0 {
+            self.bytes_until_sample += self.next_interval();
+        }
+        Some(self.scaled_size(size))
+    }
+
+    fn scaled_size(&self, size: usize) -> usize {
+        let probability = -(-(size as f64) / self.mean_bytes).exp_m1();
+        (size as f64 / probability).round() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sampler;
+
+    fn sampler(mean_bytes: usize) -> Sampler {
+        Sampler::with_rng(mean_bytes, fastrand::Rng::with_seed(12345))
+    }
+
+    #[test]
+    fn scaled_totals_are_unbiased() {
+        for size in [16, 1000, 10_000, 200_000] {
+            let mut sampler = sampler(10_000);
+            let count = 1_000_000;
+            let total: usize = (0..count).filter_map(|_| sampler.sample(size)).sum();
+            let expected = size * count;
+            let error = (total as f64 - expected as f64).abs() / expected as f64;
+            assert!(error < 0.05, "size {}: {} vs {}", size, total, expected);
+        }
+    }
+
+    #[test]
+    fn big_allocations_are_barely_scaled() {
+        let mut sampler = sampler(1024);
+        for _ in 0..1000 {
+            let size = 1024 * 1024;
+            assert_eq!(sampler.sample(size), Some(size));
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Export profiles in speedscope's JSON format, using the "sampled" profile
+//! schema with bytes as weights. The schema is documented at
+//! https://www.speedscope.app/file-format-schema.json.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use ahash::RandomState as ARandomState;
+use serde::Serialize;
+
+use crate::util::new_hashmap;
+
+#[derive(Serialize)]
+pub struct File {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    shared: Shared,
+    profiles: Vec<SampledProfile>,
+    name: String,
+    exporter: &'static str,
+}
+
+#[derive(Serialize)]
+struct Shared {
+    frames: Vec<Frame>,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+struct Frame {
+    name: String,
+    file: String,
+    line: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SampledProfile {
+    #[serde(rename = "type")]
+    profile_type: &'static str,
+    name: String,
+    unit: String,
+    start_value: u64,
+    end_value: u64,
+    // Each sample is a list of frame indexes, root first.
+    samples: Vec<Vec<usize>>,
+    weights: Vec<u64>,
+}
+
+/// Build up a speedscope File one sample at a time, deduplicating frames.
+pub struct FileBuilder {
+    file: File,
+    // (function, filename, line) -> index into frames
+    frame_indexes: HashMap<(String, String, u32), usize, ARandomState>,
+}
+
+impl FileBuilder {
+    /// Create a builder for a file with a single sampled profile. The unit
+    /// should be one speedscope knows about, e.g. "bytes" or "none".
+    pub fn new(name: &str, unit: &str) -> Self {
+        Self {
+            file: File {
+                schema: "https://www.speedscope.app/file-format-schema.json",
+                shared: Shared { frames: vec![] },
+                profiles: vec![SampledProfile {
+                    profile_type: "sampled",
+                    name: name.to_string(),
+                    unit: unit.to_string(),
+                    start_value: 0,
+                    end_value: 0,
+                    samples: vec![],
+                    weights: vec![],
+                }],
+                name: name.to_string(),
+                exporter: "Fil",
+            },
+            frame_indexes: new_hashmap(),
+        }
+    }
+
+    fn frame_index(&mut self, function: &str, filename: &str, line: u32) -> usize {
+        let frames = &mut self.file.shared.frames;
+        *self
+            .frame_indexes
+            .entry((function.to_string(), filename.to_string(), line))
+            .or_insert_with(|| {
+                frames.push(Frame {
+                    name: format!("{}:{} ({})", filename, line, function),
+                    file: filename.to_string(),
+                    line,
+                });
+                frames.len() - 1
+            })
+    }
+
+    /// Add a sample. Frames are (function, filename, line number), ordered
+    /// root first, the same order as a Callstack.
+    pub fn add_sample<'a, I>(&mut self, frames: I, weight: u64)
+    where
+        I: Iterator<Item = (&'a str, &'a str, u32)>,
+    {
+        let mut sample: Vec<usize> = frames
+            .map(|(function, filename, line)| self.frame_index(function, filename, line))
+            .collect();
+        if sample.is_empty() {
+            sample.push(self.frame_index("[No Python stack]", "", 0));
+        }
+        let profile = &mut self.file.profiles[0];
+        profile.samples.push(sample);
+        profile.weights.push(weight);
+        profile.end_value += weight;
+    }
+
+    pub fn build(self) -> File {
+        self.file
+    }
+}
+
+/// Write the file to disk as JSON.
+pub fn write_json(file: &File, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+    serde_json::to_writer(writer, file)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileBuilder, Frame};
+
+    #[test]
+    fn frames_are_deduplicated_and_weights_summed() {
+        let mut builder = FileBuilder::new("Peak Tracked Memory Usage", "bytes");
+        builder.add_sample(
+            [("main", "a.py", 1), ("helper", "b.py", 10)].into_iter(),
+            100,
+        );
+        builder.add_sample(
+            [("main", "a.py", 1), ("helper", "b.py", 12)].into_iter(),
+            50,
+        );
+        builder.add_sample(std::iter::empty(), 7);
+        let file = builder.build();
+
+        assert_eq!(
+            file.shared.frames[1],
+            Frame {
+                name: "b.py:10 (helper)".to_string(),
+                file: "b.py".to_string(),
+                line: 10
+            }
+        );
+        assert_eq!(file.shared.frames.len(), 4);
+        let profile = &file.profiles[0];
+        assert_eq!(profile.samples, vec![vec![0, 1], vec![0, 2], vec![3]]);
+        assert_eq!(profile.weights, vec![100, 50, 7]);
+        assert_eq!(profile.end_value, 157);
+
+        let json: serde_json::Value = serde_json::to_value(&file).unwrap();
+        assert_eq!(
+            json["$schema"],
+            "https://www.speedscope.app/file-format-schema.json"
+        );
+        assert_eq!(json["profiles"][0]["type"], "sampled");
+        assert_eq!(json["profiles"][0]["unit"], "bytes");
+        assert_eq!(json["profiles"][0]["endValue"], 157);
+    }
+}
@@ -0,0 +1,424 @@
+//! A versioned binary file with the tracker's full state: per-callstack memory
+//! usage and allocation counts, the interned callstacks, and the function
+//! locations they refer to.
+//!
+//! Unlike the `.prof` files, which only include the callstacks responsible
+//! for the top 99% of memory usage, nothing is dropped, so it can be used for
+//! later analysis.
+//!
+//! The file starts with an uncompressed header, `MAGIC` followed by the
+//! format version as a little-endian u32, and then a gzipped protobuf-encoded
+//! `StateFile`. New fields can be added to the protobuf messages without
+//! changing the version; the version only needs to change for incompatible
+//! changes.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prost::Message;
+
+use crate::flamegraph::{CallstackCleaner, FlamegraphCallstacks};
+use crate::memorytracking::{
+    CallSiteId, Callstack, CallstackId, FunctionId, LineNumberInfo, VecFunctionLocations,
+};
+
+/// Identifies Fil state files.
+pub const MAGIC: &[u8; 8] = b"FILSTATE";
+
+/// The current version of the format.
+pub const VERSION: u32 = 1;
+
+/// The name of the file written alongside the SVGs.
+pub const FILENAME: &str = "fil-state.bin";
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StateFile {
+    #[prost(message, optional, tag = "1")]
+    pub metadata: Option<RunMetadata>,
+    #[prost(message, repeated, tag = "2")]
+    pub functions: Vec<FunctionEntry>,
+    /// Interned callstacks, indexed by CallstackId.
+    #[prost(message, repeated, tag = "3")]
+    pub callstacks: Vec<CallstackNodeEntry>,
+    // The per-callstack statistics are indexed by CallstackId, and may be
+    // shorter than the callstacks, in which case the rest are 0:
+    #[prost(uint64, repeated, tag = "4")]
+    pub current_memory_usage: Vec<u64>,
+    #[prost(uint64, repeated, tag = "5")]
+    pub peak_memory_usage: Vec<u64>,
+    #[prost(uint64, repeated, tag = "6")]
+    pub current_allocation_counts: Vec<u64>,
+    #[prost(uint64, repeated, tag = "7")]
+    pub peak_allocation_counts: Vec<u64>,
+    #[prost(uint64, repeated, tag = "8")]
+    pub total_allocation_counts: Vec<u64>,
+    #[prost(uint64, repeated, tag = "9")]
+    pub churn_memory_usage: Vec<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RunMetadata {
+    #[prost(string, repeated, tag = "1")]
+    pub command_line: Vec<String>,
+    #[prost(uint32, tag = "2")]
+    pub pid: u32,
+    /// When the file was written, in seconds since the Unix epoch.
+    #[prost(uint64, tag = "3")]
+    pub unix_time_secs: u64,
+    #[prost(uint64, tag = "4")]
+    pub current_allocated_bytes: u64,
+    #[prost(uint64, tag = "5")]
+    pub peak_allocated_bytes: u64,
+    /// Whether allocations were sampled, in which case sizes are estimates.
+    #[prost(bool, tag = "6")]
+    pub sampled: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FunctionEntry {
+    /// The FunctionId used by the callstacks.
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub filename: String,
+    #[prost(string, tag = "3")]
+    pub function_name: String,
+}
+
+/// A callstack is its parent callstack plus one more call; the root, the
+/// empty callstack, has no parent.
+#[derive(Clone, PartialEq, Message)]
+pub struct CallstackNodeEntry {
+    #[prost(uint32, optional, tag = "1")]
+    pub parent: Option<u32>,
+    #[prost(uint64, tag = "2")]
+    pub function: u64,
+    #[prost(uint32, tag = "3")]
+    pub line_number: u32,
+    /// If set, the call's location is a bytecode index rather than a line
+    /// number.
+    #[prost(int32, optional, tag = "4")]
+    pub bytecode_index: Option<i32>,
+}
+
+impl CallstackNodeEntry {
+    pub fn root() -> Self {
+        Self {
+            parent: None,
+            function: 0,
+            line_number: 0,
+            bytecode_index: None,
+        }
+    }
+
+    pub fn new(parent: CallstackId, call: CallSiteId) -> Self {
+        let (line_number, bytecode_index) = match call.line_number {
+            LineNumberInfo::LineNumber(line_number) => (line_number, None),
+            LineNumberInfo::BytecodeIndex(index) => (0, Some(index)),
+        };
+        Self {
+            parent: Some(parent),
+            function: call.function.as_u64(),
+            line_number,
+            bytecode_index,
+        }
+    }
+}
+
+/// Convert per-callstack statistics for storage.
+pub(crate) fn to_u64s<'a>(values: impl IntoIterator<Item = &'a usize>) -> Vec<u64> {
+    values.into_iter().map(|value| *value as u64).collect()
+}
+
+impl RunMetadata {
+    /// Metadata for the current process.
+    pub fn for_current_process(
+        current_allocated_bytes: usize,
+        peak_allocated_bytes: usize,
+        sampled: bool,
+    ) -> Self {
+        Self {
+            command_line: std::env::args().collect(),
+            pid: std::process::id(),
+            unix_time_secs: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+            current_allocated_bytes: current_allocated_bytes as u64,
+            peak_allocated_bytes: peak_allocated_bytes as u64,
+            sampled,
+        }
+    }
+}
+
+impl StateFile {
+    /// Write to the given path.
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&VERSION.to_le_bytes())?;
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder.write_all(&self.encode_to_vec())?;
+        encoder.finish()?.flush()?;
+        Ok(())
+    }
+
+    /// Write to `FILENAME` in the given directory.
+    pub fn write_to_directory(&self, directory_path: &Path) {
+        let path = directory_path.join(FILENAME);
+        if let Err(e) = self.write(&path) {
+            eprintln!("=fil-profile= Error writing {:?}: {}", path, e);
+        }
+    }
+
+    /// Read from the given path, without validating the contents.
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut file = std::fs::File::open(path)?;
+        let mut header = [0u8; 12];
+        file.read_exact(&mut header)
+            .map_err(|_| format!("{} is not a Fil state file", path.display()))?;
+        if &header[..8] != MAGIC {
+            return Err(format!("{} is not a Fil state file", path.display()).into());
+        }
+        let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if version > VERSION {
+            return Err(format!(
+                "{} has format version {}, but only versions up to {} are supported",
+                path.display(),
+                version,
+                VERSION
+            )
+            .into());
+        }
+        let mut data = vec![];
+        GzDecoder::new(file).read_to_end(&mut data)?;
+        Ok(Self::decode(data.as_slice())?)
+    }
+}
+
+/// A loaded state file, converted back into the tracker's types.
+pub struct LoadedState {
+    pub metadata: RunMetadata,
+    pub functions: VecFunctionLocations,
+    /// Indexed by CallstackId.
+    pub callstacks: Vec<Callstack>,
+    // These are all indexed by CallstackId, and have the same length as
+    // callstacks:
+    pub current_memory_usage: Vec<usize>,
+    pub peak_memory_usage: Vec<usize>,
+    pub current_allocation_counts: Vec<usize>,
+    pub peak_allocation_counts: Vec<usize>,
+    pub total_allocation_counts: Vec<usize>,
+    pub churn_memory_usage: Vec<usize>,
+}
+
+/// Load a state file written by Fil.
+pub fn load(path: &Path) -> Result<LoadedState, Box<dyn Error>> {
+    LoadedState::from_state_file(StateFile::read(path)?)
+}
+
+impl LoadedState {
+    pub fn from_state_file(state: StateFile) -> Result<Self, Box<dyn Error>> {
+        // Function IDs are renumbered, since VecFunctionLocations assigns its
+        // own:
+        let mut functions = VecFunctionLocations::new();
+        let mut function_ids = HashMap::new();
+        for function in state.functions {
+            let id = functions.add_function(function.filename, function.function_name);
+            function_ids.insert(function.id, id);
+        }
+
+        let mut callstacks: Vec<Callstack> = Vec::with_capacity(state.callstacks.len());
+        for (id, node) in state.callstacks.iter().enumerate() {
+            let callstack = match node.parent {
+                None => Callstack::new(),
+                Some(parent) => {
+                    // Parents are always interned before their children:
+                    let mut calls = callstacks
+                        .get(parent as usize)
+                        .filter(|_| (parent as usize) < id)
+                        .ok_or_else(|| format!("callstack {} has bad parent {}", id, parent))?
+                        .to_vec();
+                    let function = FunctionId::new(node.function);
+                    let function =
+                        if function == FunctionId::UNKNOWN || function == FunctionId::OTHER {
+                            function
+                        } else {
+                            *function_ids
+                                .get(&node.function)
+                                .ok_or_else(|| format!("unknown function {}", node.function))?
+                        };
+                    let line_number = match node.bytecode_index {
+                        Some(index) => LineNumberInfo::BytecodeIndex(index),
+                        None => LineNumberInfo::LineNumber(node.line_number),
+                    };
+                    calls.push(CallSiteId::new(function, line_number));
+                    Callstack::from_vec(calls)
+                }
+            };
+            callstacks.push(callstack);
+        }
+
+        let length = callstacks.len();
+        let to_usage = |values: Vec<u64>| -> Result<Vec<usize>, Box<dyn Error>> {
+            if values.len() > length {
+                return Err("more statistics than callstacks".into());
+            }
+            let mut values: Vec<usize> = values.into_iter().map(|value| value as usize).collect();
+            values.resize(length, 0);
+            Ok(values)
+        };
+        Ok(Self {
+            metadata: state.metadata.unwrap_or_default(),
+            functions,
+            current_memory_usage: to_usage(state.current_memory_usage)?,
+            peak_memory_usage: to_usage(state.peak_memory_usage)?,
+            current_allocation_counts: to_usage(state.current_allocation_counts)?,
+            peak_allocation_counts: to_usage(state.peak_allocation_counts)?,
+            total_allocation_counts: to_usage(state.total_allocation_counts)?,
+            churn_memory_usage: to_usage(state.churn_memory_usage)?,
+            callstacks,
+        })
+    }
+
+    /// Combine one of the per-callstack statistics, e.g.
+    /// `&loaded.peak_memory_usage`, for rendering, with the given cleaner,
+    /// e.g. a `cleanup::CleanerChain`. Unlike the tracker's own reports, no
+    /// callstacks are filtered out.
+    pub fn combine_callstacks<CC: CallstackCleaner>(
+        &self,
+        usage: &[usize],
+        callstack_cleaner: CC,
+    ) -> FlamegraphCallstacks<HashMap<Callstack, usize>, VecFunctionLocations, CC> {
+        let mut callstacks: HashMap<Callstack, usize> = HashMap::new();
+        for (callstack, size) in self.callstacks.iter().zip(usage) {
+            if *size > 0 {
+                *callstacks.entry(callstack.clone()).or_default() += size;
+            }
+        }
+        FlamegraphCallstacks::new(callstacks, self.functions.clone(), callstack_cleaner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        load, CallstackNodeEntry, FunctionEntry, LoadedState, StateFile, FILENAME, MAGIC, VERSION,
+    };
+    use crate::memorytracking::{
+        AllocationTracker, CallSiteId, Callstack, FunctionId, IdentityCleaner, LineNumberInfo,
+        VecFunctionLocations, PARENT_PROCESS,
+    };
+    use std::io::Write;
+
+    #[test]
+    fn state_file_round_trip() {
+        let mut tracker = AllocationTracker::new(".".to_string(), VecFunctionLocations::new());
+        let fid1 = tracker
+            .functions
+            .add_function("a.py".to_string(), "af".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function("b.py".to_string(), "bf".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(
+            LineNumberInfo::LineNumber(0),
+            CallSiteId::new(fid1, LineNumberInfo::LineNumber(1)),
+        );
+        let mut cs2 = cs1.clone();
+        cs2.start_call(
+            LineNumberInfo::LineNumber(2),
+            CallSiteId::new(fid2, LineNumberInfo::LineNumber(7)),
+        );
+        let mut cs3 = cs1.clone();
+        cs3.start_call(
+            LineNumberInfo::LineNumber(3),
+            CallSiteId::new(FunctionId::OTHER, LineNumberInfo::LineNumber(0)),
+        );
+        let id1 = tracker.get_callstack_id(&cs1);
+        let id2 = tracker.get_callstack_id(&cs2);
+        let id3 = tracker.get_callstack_id(&cs3);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, id1);
+        tracker.add_allocation(PARENT_PROCESS, 2, 1, id2);
+        tracker.add_allocation(PARENT_PROCESS, 3, 30, id3);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+
+        let state = tracker.state_file_factory()();
+        let dir = tempfile::tempdir().unwrap();
+        state.write_to_directory(dir.path());
+        let loaded = load(&dir.path().join(FILENAME)).unwrap();
+
+        assert_eq!(loaded.metadata.pid, std::process::id());
+        assert_eq!(loaded.metadata.peak_allocated_bytes, 1031);
+        assert_eq!(loaded.metadata.current_allocated_bytes, 31);
+        // Functions were added in order, so they have the same IDs:
+        // Prefixes of callstacks are interned too, e.g. cs1 with line 2:
+        assert_eq!(loaded.callstacks.len(), 6);
+        assert_eq!(loaded.callstacks[0], Callstack::new());
+        for (id, callstack, current, peak, total) in [
+            (id1, &cs1, 0, 1000, 1),
+            (id2, &cs2, 1, 1, 1),
+            (id3, &cs3, 30, 30, 1),
+        ] {
+            let id = id as usize;
+            assert_eq!(&loaded.callstacks[id], callstack);
+            assert_eq!(loaded.current_memory_usage[id], current);
+            assert_eq!(loaded.peak_memory_usage[id], peak);
+            assert_eq!(loaded.total_allocation_counts[id], total);
+            assert_eq!(loaded.churn_memory_usage[id], peak);
+        }
+        assert_eq!(loaded.current_memory_usage.iter().sum::<usize>(), 31);
+
+        pyo3::prepare_freethreaded_python();
+        let mut lines: Vec<String> = loaded
+            .combine_callstacks(&loaded.peak_memory_usage, IdentityCleaner)
+            .to_lines(false)
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "a.py:1 (af) 1000",
+                "a.py:2 (af);b.py:7 (bf) 1",
+                "a.py:3 (af);[other] 30"
+            ]
+        );
+    }
+
+    #[test]
+    fn bytecode_indexes_are_kept() {
+        let call = CallSiteId::new(FunctionId::new(0), LineNumberInfo::BytecodeIndex(12));
+        let state = StateFile {
+            functions: vec![FunctionEntry {
+                id: 0,
+                filename: "a.py".to_string(),
+                function_name: "af".to_string(),
+            }],
+            callstacks: vec![CallstackNodeEntry::root(), CallstackNodeEntry::new(0, call)],
+            ..Default::default()
+        };
+        let loaded = LoadedState::from_state_file(state).unwrap();
+        assert_eq!(loaded.callstacks[1], Callstack::from_vec(vec![call]));
+        assert_eq!(loaded.peak_memory_usage, vec![0, 0]);
+    }
+
+    #[test]
+    fn state_file_header_is_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILENAME);
+        std::fs::write(&path, b"not a state file").unwrap();
+        assert!(load(&path).is_err());
+
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(MAGIC).unwrap();
+        file.write_all(&(VERSION + 1).to_le_bytes()).unwrap();
+        drop(file);
+        let error = StateFile::read(&path).err().unwrap().to_string();
+        assert!(error.contains("only versions up to"), "{}", error);
+    }
+}
@@ -0,0 +1,310 @@
+//! Record total tracked memory over time, so you can see _when_ memory grows
+//! and not just what was allocated at the peak.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// A single point on the timeline.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct TimelineSample {
+    /// Nanoseconds since the recorder was started, from a monotonic clock.
+    pub elapsed_ns: u64,
+    /// Total tracked bytes at that point.
+    pub bytes: usize,
+}
+
+/// Records a sample whenever total memory has moved by at least
+/// `byte_granularity` bytes, or `time_granularity` has passed, since the last
+/// sample. Samples are kept in a bounded ring buffer, so the oldest ones are
+/// dropped once it fills up.
+#[derive(Clone, Debug)]
+pub struct TimelineRecorder {
+    start: Instant,
+    samples: VecDeque<TimelineSample>,
+    capacity: usize,
+    dropped_samples: usize,
+    byte_granularity: usize,
+    time_granularity: Duration,
+}
+
+impl TimelineRecorder {
+    pub fn new(capacity: usize, byte_granularity: usize, time_granularity: Duration) -> Self {
+        assert!(capacity > 0);
+        Self {
+            start: Instant::now(),
+            samples: VecDeque::new(),
+            capacity,
+            dropped_samples: 0,
+            byte_granularity,
+            time_granularity,
+        }
+    }
+
+    /// Create a recorder configured from environment variables, or None if
+    /// the timeline isn't enabled:
+    ///
+    /// * `FIL_TIMELINE=1` enables it.
+    /// * `FIL_TIMELINE_BYTES` is the byte granularity, default 1 MiB.
+    /// * `FIL_TIMELINE_MS` is the time granularity, default 100ms.
+    /// * `FIL_TIMELINE_CAPACITY` is the maximum number of samples kept,
+    ///   default 100,000.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("FIL_TIMELINE").as_deref() != Ok("1") {
+            return None;
+        }
+        fn parse_var(name: &str, default: usize) -> usize {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        }
+        Some(Self::new(
+            parse_var("FIL_TIMELINE_CAPACITY", 100_000).max(1),
+            parse_var("FIL_TIMELINE_BYTES", 1024 * 1024),
+            Duration::from_millis(parse_var("FIL_TIMELINE_MS", 100) as u64),
+        ))
+    }
+
+    /// Drop all samples and restart the clock.
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+        self.samples.clear();
+        self.dropped_samples = 0;
+    }
+
+    /// Called whenever total memory changes.
+    pub fn record(&mut self, bytes: usize) {
+        self.record_at(self.start.elapsed(), bytes);
+    }
+
+    fn record_at(&mut self, elapsed: Duration, bytes: usize) {
+        let elapsed_ns = elapsed.as_nanos() as u64;
+        if let Some(last) = self.samples.back() {
+            let time_passed = elapsed_ns.saturating_sub(last.elapsed_ns)
+                >= self.time_granularity.as_nanos() as u64;
+            if bytes.abs_diff(last.bytes) < self.byte_granularity && !time_passed {
+                return;
+            }
+        }
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+            self.dropped_samples += 1;
+        }
+        self.samples.push_back(TimelineSample { elapsed_ns, bytes });
+    }
+
+    pub fn samples(&self) -> impl ExactSizeIterator<Item = &TimelineSample> {
+        self.samples.iter()
+    }
+
+    /// How many old samples were dropped because the buffer was full.
+    pub fn dropped_samples(&self) -> usize {
+        self.dropped_samples
+    }
+
+    /// CSV with a header line.
+    pub fn to_csv(&self) -> String {
+        let mut result = "elapsed_ns,bytes\n".to_string();
+        for sample in self.samples() {
+            writeln!(result, "{},{}", sample.elapsed_ns, sample.bytes).unwrap();
+        }
+        result
+    }
+
+    pub fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct Timeline<'a> {
+            dropped_samples: usize,
+            samples: Vec<&'a TimelineSample>,
+        }
+        serde_json::to_string(&Timeline {
+            dropped_samples: self.dropped_samples,
+            samples: self.samples().collect(),
+        })
+        .unwrap()
+    }
+
+    /// A simple SVG line chart of memory usage over time.
+    pub fn to_svg(&self, title: &str) -> String {
+        const WIDTH: f64 = 1200.0;
+        const HEIGHT: f64 = 500.0;
+        const LEFT: f64 = 90.0;
+        const RIGHT: f64 = 30.0;
+        const TOP: f64 = 50.0;
+        const BOTTOM: f64 = 60.0;
+        const MIB: f64 = 1024.0 * 1024.0;
+        let plot_width = WIDTH - LEFT - RIGHT;
+        let plot_height = HEIGHT - TOP - BOTTOM;
+
+        let first_ns = self.samples.front().map(|s| s.elapsed_ns).unwrap_or(0);
+        let last_ns = self.samples.back().map(|s| s.elapsed_ns).unwrap_or(0);
+        let max_seconds = ((last_ns - first_ns) as f64 / 1e9).max(1e-3);
+        let max_mib = (self.samples().map(|s| s.bytes).max().unwrap_or(0) as f64 / MIB).max(1.0);
+        let x = |ns: u64| LEFT + ((ns - first_ns) as f64 / 1e9) / max_seconds * plot_width;
+        let y = |bytes: usize| TOP + plot_height - (bytes as f64 / MIB) / max_mib * plot_height;
+
+        let mut svg = String::new();
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" viewBox="0 0 {WIDTH} {HEIGHT}" font-family="monospace" font-size="14">"#
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<rect width="{WIDTH}" height="{HEIGHT}" fill="white"/>"#
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<text x="{}" y="30" text-anchor="middle" font-size="18">{}</text>"#,
+            WIDTH / 2.0,
+            escape_xml(title)
+        )
+        .unwrap();
+
+        // Axes, with min/max labels:
+        let bottom = TOP + plot_height;
+        writeln!(
+            svg,
+            r#"<path d="M{LEFT},{TOP} L{LEFT},{bottom} L{},{bottom}" stroke="black" fill="none"/>"#,
+            LEFT + plot_width
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="end">{:.1} MiB</text>"#,
+            LEFT - 5.0,
+            TOP + 5.0,
+            max_mib
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="end">0 MiB</text>"#,
+            LEFT - 5.0,
+            bottom
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<text x="{LEFT}" y="{}" text-anchor="middle">0s</text>"#,
+            bottom + 20.0
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="middle">{:.2}s</text>"#,
+            LEFT + plot_width,
+            bottom + 20.0,
+            max_seconds
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="middle">Time since tracking started</text>"#,
+            LEFT + plot_width / 2.0,
+            bottom + 45.0
+        )
+        .unwrap();
+
+        let points = self
+            .samples()
+            .map(|s| format!("{:.1},{:.1}", x(s.elapsed_ns), y(s.bytes)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(
+            svg,
+            r#"<polyline points="{points}" stroke="rgb(220,60,30)" stroke-width="2" fill="none"/>"#
+        )
+        .unwrap();
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// Write memory-timeline.json, .csv and .svg to the given directory.
+    pub fn write_files(&self, directory_path: &Path, title: &str) {
+        let files = [
+            ("memory-timeline.json", self.to_json()),
+            ("memory-timeline.csv", self.to_csv()),
+            ("memory-timeline.svg", self.to_svg(title)),
+        ];
+        for (filename, contents) in files {
+            let path = directory_path.join(filename);
+            if let Err(e) = std::fs::write(&path, contents) {
+                eprintln!("=fil-profile= Error writing {:?}: {}", path, e);
+                return;
+            }
+        }
+        eprintln!(
+            "=fil-profile= Wrote memory usage timeline to {:?}",
+            directory_path.join("memory-timeline.svg")
+        );
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TimelineRecorder, TimelineSample};
+    use std::time::Duration;
+
+    fn samples(recorder: &TimelineRecorder) -> Vec<(u64, usize)> {
+        recorder
+            .samples()
+            .map(|TimelineSample { elapsed_ns, bytes }| (*elapsed_ns / 1_000_000, *bytes))
+            .collect()
+    }
+
+    #[test]
+    fn samples_taken_at_byte_or_time_granularity() {
+        let mut recorder = TimelineRecorder::new(100, 1000, Duration::from_millis(10));
+        let ms = Duration::from_millis;
+        recorder.record_at(ms(0), 0);
+        // Not enough change in bytes or time:
+        recorder.record_at(ms(1), 999);
+        // Enough change in bytes, up or down:
+        recorder.record_at(ms(2), 1000);
+        recorder.record_at(ms(3), 0);
+        // Enough time passed:
+        recorder.record_at(ms(13), 5);
+        recorder.record_at(ms(14), 6);
+        assert_eq!(samples(&recorder), vec![(0, 0), (2, 1000), (3, 0), (13, 5)]);
+    }
+
+    #[test]
+    fn oldest_samples_dropped_when_full() {
+        let mut recorder = TimelineRecorder::new(3, 1, Duration::from_secs(1000));
+        for i in 0..5 {
+            recorder.record_at(Duration::from_millis(i), i as usize);
+        }
+        assert_eq!(samples(&recorder), vec![(2, 2), (3, 3), (4, 4)]);
+        assert_eq!(recorder.dropped_samples(), 2);
+        assert_eq!(
+            recorder.to_csv(),
+            "elapsed_ns,bytes\n2000000,2\n3000000,3\n4000000,4\n"
+        );
+        recorder.reset();
+        assert_eq!(samples(&recorder), vec![]);
+        assert_eq!(recorder.dropped_samples(), 0);
+    }
+
+    #[test]
+    fn svg_has_a_point_per_sample() {
+        let mut recorder = TimelineRecorder::new(100, 1, Duration::from_secs(1000));
+        recorder.record_at(Duration::from_secs(0), 0);
+        recorder.record_at(Duration::from_secs(1), 2 * 1024 * 1024);
+        recorder.record_at(Duration::from_secs(2), 1024 * 1024);
+        let svg = recorder.to_svg("Memory <usage>");
+        assert!(svg.contains("Memory &lt;usage&gt;"));
+        assert!(svg.contains(r#"points="90.0,440.0 630.0,50.0 1170.0,245.0""#));
+    }
+}
@@ -20,11 +20,7 @@ lazy_static! {
 lazy_static! {
     pub static ref DEBUG_MODE: bool = match std::env::var("FIL_DEBUG") {
         Ok(value) => {
-            if value == "1" {
-                true
-            } else {
-                false
-            }
+            value == "1"
         }
         _ => false,
     };
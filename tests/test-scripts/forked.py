@@ -0,0 +1,19 @@
+import multiprocessing
+
+import numpy
+
+
+def child():
+    data = numpy.ones((1024, 1024, 30), dtype=numpy.uint8)
+    return int(data.sum())
+
+
+def main():
+    data = numpy.ones((1024, 1024, 20), dtype=numpy.uint8)
+    process = multiprocessing.get_context("fork").Process(target=child)
+    process.start()
+    process.join()
+    assert process.exitcode == 0
+
+
+main()
@@ -248,6 +248,38 @@ print(subprocess.check_output(["env"]))
         # assert b"DYLD_INSERT_LIBRARIES" not in result
 
 
+def test_forked_children_get_their_own_report():
+    """
+    fork()ed subprocesses are tracked separately, with their own report in a
+    subdirectory of the parent's report.
+    """
+    script = TEST_SCRIPTS / "forked.py"
+    output_dir = profile(script)
+    [parent_dir] = glob(str(output_dir / "*"))
+    [child_prof] = glob(str(Path(parent_dir) / "fork-*" / "peak-memory.prof"))
+    parent_allocations = get_allocations(
+        Path(parent_dir) / "peak-memory.prof", direct=True
+    )
+    child_allocations = get_allocations(Path(child_prof), direct=True)
+
+    ones = (numpy._core.numeric.__file__, "ones", ANY)
+    script = str(script)
+    parent_path = ((script, "<module>", 19), (script, "main", 12), ones)
+    assert match(parent_allocations, {parent_path: big}, as_mb) == pytest.approx(
+        20, 0.1
+    )
+
+    # The child's callstacks start inside the parent's call to start(), and it
+    # doesn't include memory inherited from the parent:
+    child_mb = sum(
+        size_kb / 1024
+        for path, size_kb in child_allocations.items()
+        if (script, "child", 7) in path
+    )
+    assert child_mb == pytest.approx(30, 0.1)
+    assert not any((script, "main", 12) in path for path in child_allocations)
+
+
 def test_out_of_memory():
     """
     If an allocation is run that runs out of memory, current allocations are
@@ -263,6 +295,12 @@ def test_out_of_memory():
             "out-of-memory.svg",
             "out-of-memory-reversed.svg",
             "out-of-memory.prof",
+            "out-of-memory-source.prof",
+            "out-of-memory-allocations.svg",
+            "out-of-memory-allocations-reversed.svg",
+            "out-of-memory-allocations.prof",
+            "out-of-memory-allocations-source.prof",
+            "fil-overhead.json",
         ],
         "out-of-memory.prof",
     )
@@ -299,6 +337,12 @@ def test_out_of_memory_slow_leak():
             "out-of-memory.svg",
             "out-of-memory-reversed.svg",
             "out-of-memory.prof",
+            "out-of-memory-source.prof",
+            "out-of-memory-allocations.svg",
+            "out-of-memory-allocations-reversed.svg",
+            "out-of-memory-allocations.prof",
+            "out-of-memory-allocations-source.prof",
+            "fil-overhead.json",
         ],
         "out-of-memory.prof",
     )
@@ -384,6 +428,12 @@ def test_out_of_memory_slow_leak_cgroups():
             "out-of-memory.svg",
             "out-of-memory-reversed.svg",
             "out-of-memory.prof",
+            "out-of-memory-source.prof",
+            "out-of-memory-allocations.svg",
+            "out-of-memory-allocations-reversed.svg",
+            "out-of-memory-allocations.prof",
+            "out-of-memory-allocations-source.prof",
+            "fil-overhead.json",
         ],
         "out-of-memory.prof",
     )